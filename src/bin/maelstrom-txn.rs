@@ -1,41 +1,66 @@
 use maelstrom_node::message_handlers::*;
 use maelstrom_node::messages::*;
 use maelstrom_node::node::*;
+use maelstrom_node::txn_engine;
 
-use std::io::{self, BufRead};
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Instant;
 
 fn main() {
     let node = Node::new();
     let node = Rc::new(RefCell::new(node));
+    node.borrow_mut().recover();
+
+    let engine = txn_engine::new_engine(&node.borrow().config);
 
     let router: Vec<Box<dyn MessageHandler>> = vec![
         Box::new(InitHandler),
         Box::new(InitOkHandler),
-        Box::new(TxnHandler),
+        Box::new(TxnHandler::new(engine)),
         Box::new(TxnOkHandler),
+        Box::new(TxnForwardOkHandler),
     ];
 
+    let mut next_tick = Instant::now() + TICK;
     loop {
-        let line = {
-            // unlock as soon as possible
-            let mut stdin = io::stdin().lock();
-            let mut buffer = String::new();
-            match stdin.read_line(&mut buffer) {
-                Ok(0) => break, // EOF reached, exit the loop
-                Ok(_) => buffer,
-                Err(e) => panic!("Error reading second line: {e}"),
-            }
-        };
+        if Instant::now() >= next_tick {
+            on_tick(&node);
+            next_tick = Instant::now() + TICK;
+        }
+        // first handle the messages queued while waiting for lin-kv
+        let queued = node.borrow_mut().inbox.pop_front();
+        let msg = match queued {
+            Some(msg) => msg,
+            None => {
+                let timeout = next_tick.saturating_duration_since(Instant::now());
+                let line = node.borrow().read_line(timeout);
+                let line = match line {
+                    Ok(line) => line,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break, // EOF reached, exit the loop
+                };
 
-        match serde_json::from_str::<Message>(&line) {
-            Ok(msg) => {
-                if let Some(response) = Node::handle_message(node.clone(), &msg, &router) {
-                    node.borrow_mut().send(response);
+                match serde_json::from_str::<Message>(&line) {
+                    Ok(msg) => {
+                        node.borrow_mut().txn_leader.received(&msg, Instant::now());
+                        msg
+                    }
+                    Err(err) => {
+                        eprintln!("invalid json data for message: {}", err);
+                        let reply = node.borrow().malformed_reply(&line, &err);
+                        if let Some(reply) = reply {
+                            node.borrow().send(reply);
+                        }
+                        continue;
+                    }
                 }
             }
-            Err(err) => eprintln!("invalid json data for message: {}", err),
+        };
+
+        if let Some(response) = Node::handle_message(node.clone(), &msg, &router) {
+            node.borrow_mut().send(response);
         }
     }
 }
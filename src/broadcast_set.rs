@@ -0,0 +1,261 @@
+//! The set of broadcast values a node has seen.
+//!
+//! Unsigned integers, which is all maelstrom's broadcast workload sends,
+//! go into an [`IntervalSet`]. Any other JSON payload is kept in a hash set
+//! keyed by its canonical encoding. Both halves are cheap to snapshot.
+use crate::interval_set::IntervalSet;
+use crate::messages::BroadcastValue;
+use serde::de::{SeqAccess, Visitor};
+use serde::ser::SerializeSeq;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashSet;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// How often a node sends its digest to a neighbor, see `AntiEntropy`.
+pub const GOSSIP_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BroadcastSet {
+    ints: IntervalSet,
+    others: Rc<HashSet<BroadcastValue>>,
+}
+
+impl BroadcastSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns true if the value was not in the set before.
+    pub fn insert(&mut self, value: &BroadcastValue) -> bool {
+        match value.as_u64() {
+            Some(v) => self.ints.insert(v),
+            None => {
+                if self.others.contains(value) {
+                    false
+                } else {
+                    Rc::make_mut(&mut self.others).insert(value.clone())
+                }
+            }
+        }
+    }
+
+    pub fn contains(&self, value: &BroadcastValue) -> bool {
+        match value.as_u64() {
+            Some(v) => self.ints.contains(v),
+            None => self.others.contains(value),
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.ints.len() + self.others.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ints.is_empty() && self.others.is_empty()
+    }
+
+    /// The integer part of the set.
+    pub fn ints(&self) -> &IntervalSet {
+        &self.ints
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = BroadcastValue> + '_ {
+        self.ints
+            .iter()
+            .map(BroadcastValue::from)
+            .chain(self.others.iter().cloned())
+    }
+
+    /// Values in `self` but not in `other`.
+    pub fn difference(&self, other: &BroadcastSet) -> BroadcastSet {
+        BroadcastSet {
+            ints: self.ints.difference(&other.ints),
+            others: Rc::new(self.others.difference(&other.others).cloned().collect()),
+        }
+    }
+
+    /// Adds every value of `other` into `self`.
+    pub fn union_with(&mut self, other: &BroadcastSet) {
+        self.ints.union_with(&other.ints);
+        if !other.others.is_subset(&self.others) {
+            Rc::make_mut(&mut self.others).extend(other.others.iter().cloned());
+        }
+    }
+}
+
+/// A [`BroadcastSet`] on the wire with its integers as `[start, end]`
+/// ranges, so that gossiping it costs O(ranges) rather than O(values).
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SetDigest {
+    ranges: Vec<(u64, u64)>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    others: Vec<BroadcastValue>,
+}
+
+impl From<&BroadcastSet> for SetDigest {
+    fn from(set: &BroadcastSet) -> Self {
+        SetDigest {
+            ranges: set.ints.ranges().collect(),
+            others: set.others.iter().cloned().collect(),
+        }
+    }
+}
+
+impl From<&SetDigest> for BroadcastSet {
+    fn from(digest: &SetDigest) -> Self {
+        let mut set = BroadcastSet::new();
+        for &(start, end) in &digest.ranges {
+            if start <= end {
+                set.ints.insert_range(start, end);
+            }
+        }
+        for value in &digest.others {
+            set.insert(value);
+        }
+        set
+    }
+}
+
+/// Anti-entropy for the flood and plumtree modes. Every `GOSSIP_INTERVAL`
+/// the node sends the digest of `messages_seen` to its next neighbor, which
+/// takes what is new to it and answers with the difference the other way
+/// round. Lost broadcasts are repaired even once their sender gave up.
+#[derive(Debug, Default)]
+pub struct AntiEntropy {
+    next: Option<Instant>,
+    round: usize,
+}
+
+impl AntiEntropy {
+    /// The neighbor to gossip with, if a round is due at `now`.
+    pub fn peer(&mut self, now: Instant, neighbors: &[String]) -> Option<String> {
+        if neighbors.is_empty() || self.next.is_some_and(|next| now < next) {
+            return None;
+        }
+        self.next = Some(now + GOSSIP_INTERVAL);
+        self.round += 1;
+        Some(neighbors[self.round % neighbors.len()].clone())
+    }
+}
+
+impl FromIterator<BroadcastValue> for BroadcastSet {
+    fn from_iter<I: IntoIterator<Item = BroadcastValue>>(iter: I) -> Self {
+        let mut set = BroadcastSet::new();
+        for v in iter {
+            set.insert(&v);
+        }
+        set
+    }
+}
+
+// serialized as a plain list of values, integers first
+impl Serialize for BroadcastSet {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let len = usize::try_from(self.len()).ok();
+        let mut seq = serializer.serialize_seq(len)?;
+        for v in self.ints.iter() {
+            seq.serialize_element(&v)?;
+        }
+        for v in self.others.iter() {
+            seq.serialize_element(v)?;
+        }
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for BroadcastSet {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct BroadcastSetVisitor;
+
+        impl<'de> Visitor<'de> for BroadcastSetVisitor {
+            type Value = BroadcastSet;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a list of broadcast values")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let mut set = BroadcastSet::new();
+                while let Some(v) = seq.next_element::<BroadcastValue>()? {
+                    set.insert(&v);
+                }
+                Ok(set)
+            }
+        }
+
+        deserializer.deserialize_seq(BroadcastSetVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn set(values: &[serde_json::Value]) -> BroadcastSet {
+        values.iter().cloned().map(BroadcastValue::new).collect()
+    }
+
+    #[test]
+    fn difference_and_union_cover_both_halves() {
+        let a = set(&[json!(1), json!(2), json!(3), json!("x"), json!({"k": 1})]);
+        let b = set(&[json!(2), json!("x"), json!("y")]);
+        assert_eq!(
+            a.difference(&b),
+            set(&[json!(1), json!(3), json!({"k": 1})])
+        );
+        assert_eq!(b.difference(&a), set(&[json!("y")]));
+
+        let mut merged = b.clone();
+        merged.union_with(&a.difference(&b));
+        assert_eq!(merged.len(), 6);
+        assert!(a.difference(&merged).is_empty());
+        // the snapshot taken before is unchanged
+        assert_eq!(b.len(), 3);
+    }
+
+    #[test]
+    fn a_digest_sends_integers_as_ranges() {
+        let mut values: Vec<serde_json::Value> = (0..100).map(|v| json!(v)).collect();
+        values.push(json!(200));
+        values.push(json!("x"));
+        let set = set(&values);
+        let digest = SetDigest::from(&set);
+        let wire = serde_json::to_value(&digest).unwrap();
+        assert_eq!(
+            wire,
+            json!({"ranges": [[0, 99], [200, 200]], "others": ["x"]})
+        );
+        let back: SetDigest = serde_json::from_value(wire).unwrap();
+        assert_eq!(BroadcastSet::from(&back), set);
+        let empty = serde_json::to_value(SetDigest::from(&BroadcastSet::new())).unwrap();
+        assert_eq!(empty, json!({"ranges": []}));
+    }
+
+    #[test]
+    fn gossip_goes_to_the_neighbors_in_turn() {
+        let neighbors = vec!["n1".to_string(), "n2".to_string()];
+        let mut anti_entropy = AntiEntropy::default();
+        let now = Instant::now();
+        assert_eq!(anti_entropy.peer(now, &[]), None);
+        let first = anti_entropy.peer(now, &neighbors).unwrap();
+        assert_eq!(
+            anti_entropy.peer(now + GOSSIP_INTERVAL / 2, &neighbors),
+            None
+        );
+        let second = anti_entropy
+            .peer(now + GOSSIP_INTERVAL, &neighbors)
+            .unwrap();
+        assert_ne!(first, second);
+    }
+}
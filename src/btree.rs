@@ -0,0 +1,401 @@
+//! A persistent B-tree stored in lin-kv.
+//!
+//! Every tree node is an immutable lin-kv object. An update copies only the
+//! nodes on the path from the root to the changed key and gives them new ids
+//! (path copying), so changing one key of an n key tree writes O(log n)
+//! small nodes instead of a whole partition map. Old roots stay valid, a
+//! reader holding one sees a consistent snapshot.
+//!
+//! ```text
+//! "tree-n0-9": {"internal": {"keys": [20], "children": ["tree-n0-7", "tree-n1-4"]}}
+//! "tree-n0-7": {"leaf": [[1, "n0-3"], [7, "n1-5"]]}
+//! "tree-n1-4": {"leaf": [[20, "n0-6"], [31, "n0-8"]]}
+//! ```
+use crate::gc::is_tombstone;
+use crate::lin_kv;
+use crate::node::Node;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum TreeNode<K, V> {
+    /// entries sorted by key
+    Leaf(Vec<(K, V)>),
+    /// `children[i]` holds the keys in `[keys[i - 1], keys[i])`
+    Internal { keys: Vec<K>, children: Vec<String> },
+}
+
+impl<K, V> TreeNode<K, V> {
+    fn len(&self) -> usize {
+        match self {
+            TreeNode::Leaf(entries) => entries.len(),
+            TreeNode::Internal { children, .. } => children.len(),
+        }
+    }
+}
+
+pub struct PersistentTree<K, V> {
+    node: Rc<RefCell<Node>>,
+    /// max entries of a leaf and children of an internal node
+    order: usize,
+    root: Option<String>,
+    // tree nodes loaded or created so far
+    nodes: HashMap<String, TreeNode<K, V>>,
+    // nodes created by this tree and not written yet, they are updated in
+    // place instead of being copied again
+    fresh: HashSet<String>,
+    // stored nodes that were copied and are no longer referenced
+    replaced: Vec<String>,
+    // a node on some path was garbage collected, see gc.rs
+    stale: bool,
+}
+
+impl<K, V> PersistentTree<K, V>
+where
+    K: Ord + Clone + Serialize + DeserializeOwned,
+    V: Clone + Serialize + DeserializeOwned,
+{
+    pub fn new(node: &Rc<RefCell<Node>>, root: Option<String>, order: usize) -> Self {
+        assert!(order >= 3, "a b-tree needs an order of at least 3");
+        Self {
+            node: node.clone(),
+            order,
+            root,
+            nodes: HashMap::new(),
+            fresh: HashSet::new(),
+            replaced: Vec::new(),
+            stale: false,
+        }
+    }
+
+    pub fn root(&self) -> Option<&String> {
+        self.root.as_ref()
+    }
+
+    /// True if a node was garbage collected, the root is too old to read.
+    pub fn stale(&self) -> bool {
+        self.stale
+    }
+
+    /// The stored nodes replaced by copies since the last call.
+    pub fn take_replaced(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.replaced)
+    }
+
+    /// Loads the nodes on the paths to all `keys`, one round trip per level.
+    pub fn load_paths(&mut self, keys: &[K]) {
+        let Some(root) = self.root.clone() else {
+            return;
+        };
+        let mut level: HashMap<String, Vec<K>> = HashMap::from([(root, keys.to_vec())]);
+        while !level.is_empty() {
+            self.fetch(level.keys().cloned().collect());
+            let mut next: HashMap<String, Vec<K>> = HashMap::new();
+            for (id, keys) in level {
+                if let TreeNode::Internal {
+                    keys: seps,
+                    children,
+                } = &self.nodes[&id]
+                {
+                    for k in keys {
+                        let child = &children[child_index(seps, &k)];
+                        next.entry(child.clone()).or_default().push(k);
+                    }
+                }
+            }
+            level = next;
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        let mut id = self.root.clone()?;
+        loop {
+            self.fetch(vec![id.clone()]);
+            match &self.nodes[&id] {
+                TreeNode::Leaf(entries) => {
+                    return entries
+                        .binary_search_by(|(k, _)| k.cmp(key))
+                        .ok()
+                        .map(|i| entries[i].1.clone());
+                }
+                TreeNode::Internal { keys, children } => {
+                    id = children[child_index(keys, key)].clone();
+                }
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        let (id, split) = self.insert_at(self.root.clone(), key, value);
+        self.root = Some(match split {
+            Some((sep, right)) => self.create(TreeNode::Internal {
+                keys: vec![sep],
+                children: vec![id, right],
+            }),
+            None => id,
+        });
+    }
+
+    /// The nodes created since the last call, to be written before the new
+    /// root is published.
+    pub fn take_writes(&mut self) -> Vec<(String, serde_json::Value)> {
+        let mut node = self.node.borrow_mut();
+        self.fresh
+            .drain()
+            .map(|id| {
+                let value = serde_json::to_value(&self.nodes[&id]).unwrap();
+                node.tree_cache.insert(id.clone(), value.clone());
+                (id, value)
+            })
+            .collect()
+    }
+
+    /// Inserts into the subtree at `id`. Returns the subtree's new id and,
+    /// if it had to be split, the separator and the new right sibling.
+    fn insert_at(&mut self, id: Option<String>, key: K, value: V) -> (String, Option<(K, String)>) {
+        let Some(id) = id else {
+            return (self.create(TreeNode::Leaf(vec![(key, value)])), None);
+        };
+        self.fetch(vec![id.clone()]);
+        let mut tree_node = self.nodes[&id].clone();
+        match &mut tree_node {
+            TreeNode::Leaf(entries) => match entries.binary_search_by(|(k, _)| k.cmp(&key)) {
+                Ok(i) => entries[i].1 = value,
+                Err(i) => entries.insert(i, (key, value)),
+            },
+            TreeNode::Internal { keys, children } => {
+                let i = child_index(keys, &key);
+                let (child, split) = self.insert_at(Some(children[i].clone()), key, value);
+                children[i] = child;
+                if let Some((sep, right)) = split {
+                    keys.insert(i, sep);
+                    children.insert(i + 1, right);
+                }
+            }
+        }
+
+        let split = (tree_node.len() > self.order).then(|| {
+            let (sep, right) = split(&mut tree_node);
+            (sep, self.create(right))
+        });
+
+        // path copying: a stored node is never changed, only replaced
+        let id = if self.fresh.contains(&id) {
+            self.nodes.insert(id.clone(), tree_node);
+            id
+        } else {
+            self.replaced.push(id);
+            self.create(tree_node)
+        };
+        (id, split)
+    }
+
+    fn create(&mut self, tree_node: TreeNode<K, V>) -> String {
+        let id = self.next_id();
+        self.nodes.insert(id.clone(), tree_node);
+        self.fresh.insert(id.clone());
+        id
+    }
+
+    fn next_id(&self) -> String {
+        let node = self.node.borrow();
+        node.ids.next_id(&format!("tree-{}", node.id))
+    }
+
+    /// Loads tree nodes from the node's cache or else from lin-kv in a
+    /// single round trip.
+    fn fetch(&mut self, ids: Vec<String>) {
+        let mut missed = Vec::new();
+        {
+            let mut node = self.node.borrow_mut();
+            for id in ids {
+                if self.nodes.contains_key(&id) {
+                    continue;
+                }
+                match node.tree_cache.get(&id) {
+                    Some(value) => {
+                        let tree_node = serde_json::from_value(value.clone()).unwrap();
+                        self.nodes.insert(id, tree_node);
+                    }
+                    None => missed.push(id),
+                }
+            }
+        }
+        let values = lin_kv::read_many(&self.node, &missed);
+        let mut node = self.node.borrow_mut();
+        for (id, value) in missed.into_iter().zip(values) {
+            let value = value.unwrap_or_else(|| panic!("tree node {id} is missing"));
+            if is_tombstone(&value) {
+                self.stale = true;
+                self.nodes.insert(id, TreeNode::Leaf(Vec::new()));
+                continue;
+            }
+            node.tree_cache.insert(id.clone(), value.clone());
+            self.nodes
+                .insert(id, serde_json::from_value(value).unwrap());
+        }
+    }
+}
+
+/// The child of an internal node that may hold `key`.
+fn child_index<K: Ord>(keys: &[K], key: &K) -> usize {
+    keys.partition_point(|sep| sep <= key)
+}
+
+/// Splits a node in two halves, returning the separator and the right half.
+fn split<K: Clone, V>(tree_node: &mut TreeNode<K, V>) -> (K, TreeNode<K, V>) {
+    match tree_node {
+        TreeNode::Leaf(entries) => {
+            let right = entries.split_off(entries.len() / 2);
+            (right[0].0.clone(), TreeNode::Leaf(right))
+        }
+        TreeNode::Internal { keys, children } => {
+            let mid = keys.len() / 2;
+            let right_keys = keys.split_off(mid + 1);
+            let sep = keys.pop().unwrap();
+            let right_children = children.split_off(mid + 1);
+            (
+                sep,
+                TreeNode::Internal {
+                    keys: right_keys,
+                    children: right_children,
+                },
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lru_cache::LruCache;
+    use serde_json::json;
+
+    type Tree = PersistentTree<u64, String>;
+
+    // stored nodes come from the node's cache, so lin-kv is never asked
+    fn node() -> Rc<RefCell<Node>> {
+        let mut node = Node::new();
+        node.id = "n0".to_string();
+        node.tree_cache = LruCache::new(1024);
+        Rc::new(RefCell::new(node))
+    }
+
+    /// A tree of order 3 holding `keys`, written to the cache.
+    fn stored(node: &Rc<RefCell<Node>>, keys: impl IntoIterator<Item = u64>) -> String {
+        let mut tree = Tree::new(node, None, 3);
+        for k in keys {
+            tree.insert(k, format!("v{k}"));
+        }
+        tree.take_writes();
+        tree.root().unwrap().clone()
+    }
+
+    fn depth(tree: &mut Tree) -> usize {
+        let mut id = tree.root().unwrap().clone();
+        let mut depth = 1;
+        loop {
+            tree.fetch(vec![id.clone()]);
+            match &tree.nodes[&id] {
+                TreeNode::Leaf(_) => return depth,
+                TreeNode::Internal { children, .. } => id = children[0].clone(),
+            }
+            depth += 1;
+        }
+    }
+
+    #[test]
+    fn finds_every_inserted_key() {
+        let node = node();
+        let mut tree = Tree::new(&node, None, 3);
+        assert_eq!(tree.get(&1), None);
+        for k in (0..50).map(|i| i * 7 % 50) {
+            tree.insert(k, format!("v{k}"));
+        }
+        for k in 0..50 {
+            assert_eq!(tree.get(&k), Some(format!("v{k}")));
+        }
+        assert_eq!(tree.get(&50), None);
+        tree.insert(7, "seven".to_string());
+        assert_eq!(tree.get(&7), Some("seven".to_string()));
+    }
+
+    #[test]
+    fn a_full_leaf_splits_under_a_new_root() {
+        let node = node();
+        let mut tree = Tree::new(&node, None, 3);
+        for k in 1..=3 {
+            tree.insert(k, format!("v{k}"));
+        }
+        let root = tree.root().unwrap().clone();
+        assert!(matches!(&tree.nodes[&root], TreeNode::Leaf(entries) if entries.len() == 3));
+
+        tree.insert(4, "v4".to_string());
+        let root = tree.root().unwrap().clone();
+        let TreeNode::Internal { keys, children } = tree.nodes[&root].clone() else {
+            panic!("root did not split");
+        };
+        assert_eq!(keys, vec![3]);
+        assert_eq!(children.len(), 2);
+        let left = serde_json::to_value(&tree.nodes[&children[0]]).unwrap();
+        let right = serde_json::to_value(&tree.nodes[&children[1]]).unwrap();
+        assert_eq!(left, json!({"leaf": [[1, "v1"], [2, "v2"]]}));
+        assert_eq!(right, json!({"leaf": [[3, "v3"], [4, "v4"]]}));
+        // nothing was stored yet, so nothing was copied
+        assert!(tree.take_replaced().is_empty());
+        assert_eq!(tree.take_writes().len(), 3);
+    }
+
+    #[test]
+    fn internal_nodes_split_as_well() {
+        let node = node();
+        let root = stored(&node, 0..30);
+        let mut tree = Tree::new(&node, Some(root), 3);
+        assert!(depth(&mut tree) >= 3);
+        for k in 0..30 {
+            assert_eq!(tree.get(&k), Some(format!("v{k}")));
+        }
+    }
+
+    #[test]
+    fn an_update_copies_only_its_path() {
+        let node = node();
+        let old_root = stored(&node, 0..30);
+        let mut tree = Tree::new(&node, Some(old_root.clone()), 3);
+        let depth = depth(&mut tree);
+
+        tree.insert(5, "new".to_string());
+        let replaced = tree.take_replaced();
+        let writes = tree.take_writes();
+        assert_eq!(replaced.len(), depth);
+        assert_eq!(writes.len(), depth);
+        assert_eq!(replaced[depth - 1], old_root);
+        let new_root = tree.root().unwrap().clone();
+        assert_ne!(new_root, old_root);
+
+        // the old root is still a consistent snapshot
+        let mut old = Tree::new(&node, Some(old_root), 3);
+        assert_eq!(old.get(&5), Some("v5".to_string()));
+        let mut new = Tree::new(&node, Some(new_root), 3);
+        assert_eq!(new.get(&5), Some("new".to_string()));
+        assert_eq!(new.get(&29), Some("v29".to_string()));
+    }
+
+    #[test]
+    fn fresh_nodes_are_updated_in_place() {
+        let node = node();
+        let root = stored(&node, 0..30);
+        let mut tree = Tree::new(&node, Some(root), 3);
+        let depth = depth(&mut tree);
+        tree.insert(5, "a".to_string());
+        tree.insert(5, "b".to_string());
+        // the second insert goes down the copies made by the first
+        assert_eq!(tree.take_replaced().len(), depth);
+        assert_eq!(tree.take_writes().len(), depth);
+        assert_eq!(tree.get(&5), Some("b".to_string()));
+    }
+}
@@ -0,0 +1,212 @@
+//! Calvin-style deterministic txn sequencing.
+//!
+//! Instead of racing for a root or for key locks, every batch of txns gets
+//! a slot in one global log kept in lin-kv, `calvin-0`, `calvin-1`, ...
+//! A node claims the next free slot with a CAS that only creates it, and
+//! loses nothing when another node was faster: it applies that slot and
+//! tries the one after.
+//!
+//! ```text
+//! "calvin-7": [[["append", 1, 3], ["r", 2, null]], [["w", 4, 5]]]
+//! ```
+//!
+//! Every node keeps a replica of the database in memory and applies the
+//! log in slot order with the same `run_local` as `MemoryEngine`. Applying
+//! is deterministic, including the txns that fail with error 22, so all
+//! replicas agree and no txn is ever aborted. A node learns its own results
+//! by applying its slot. Read-only batches take no slot: the node catches
+//! up to the end of the log and reads its replica.
+//!
+//! A restarted node starts from an empty replica and replays the whole log.
+use crate::lin_kv::LinKv;
+use crate::messages::*;
+use crate::txn_engine::run_local;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Slots read per round trip while catching up.
+const WINDOW: usize = 16;
+
+fn slot_key(slot: u64) -> String {
+    format!("calvin-{slot}")
+}
+
+/// A node's copy of the database, as of the first `applied` slots.
+#[derive(Debug, Default)]
+pub struct Replica {
+    state: HashMap<TxnKey, Value>,
+    applied: u64,
+}
+
+impl Replica {
+    /// Sequences `txns` as one slot of the log and returns their results.
+    /// If lin-kv fails the CAS for another reason than a taken slot, the
+    /// txns may or may not have their slot and all fail with crash.
+    pub fn sequence<K: LinKv>(
+        &mut self,
+        kv: &K,
+        txns: &[Vec<MicroOp>],
+    ) -> Vec<Result<Vec<MicroOp>, ErrorExtra>> {
+        if !txns.iter().flatten().any(MicroOp::is_write) {
+            self.catch_up(kv);
+            return txns
+                .iter()
+                .map(|txn| run_local(&self.state, txn).map(|(results, _)| results))
+                .collect();
+        }
+        let entry = serde_json::to_value(txns).unwrap();
+        loop {
+            self.catch_up(kv);
+            let claim = (slot_key(self.applied), Value::Null, entry.clone());
+            match kv.cas_many(vec![claim]).pop().unwrap() {
+                Ok(()) => return self.apply(txns),
+                // another node claimed the slot first
+                Err(PRECONDITION_FAILED) => continue,
+                Err(code) => {
+                    eprintln!("calvin slot {} failed with {code}", self.applied);
+                    let err = ErrorExtra {
+                        code: CRASH,
+                        text: format!("lin-kv failed the log write with {code}"),
+                    };
+                    return vec![Err(err); txns.len()];
+                }
+            }
+        }
+    }
+
+    /// Applies the slots written by other nodes, up to the first free one.
+    fn catch_up<K: LinKv>(&mut self, kv: &K) {
+        loop {
+            let keys: Vec<String> = (self.applied..self.applied + WINDOW as u64)
+                .map(slot_key)
+                .collect();
+            for entry in kv.read_many(&keys) {
+                let Some(entry) = entry else {
+                    return;
+                };
+                let txns: Vec<Vec<MicroOp>> =
+                    serde_json::from_value(entry).expect("a calvin log entry");
+                self.apply(&txns);
+            }
+        }
+    }
+
+    /// Applies the slot `applied` holding `txns`.
+    fn apply(&mut self, txns: &[Vec<MicroOp>]) -> Vec<Result<Vec<MicroOp>, ErrorExtra>> {
+        self.applied += 1;
+        txns.iter()
+            .map(|txn| {
+                let (results, state) = run_local(&self.state, txn)?;
+                self.state.extend(state);
+                Ok(results)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lin_kv::mem::MemKv;
+    use serde_json::json;
+
+    fn txns(batch: Value) -> Vec<Vec<MicroOp>> {
+        serde_json::from_value(batch).unwrap()
+    }
+
+    fn log(kv: &MemKv, slot: u64, batch: Value) {
+        kv.data.borrow_mut().insert(slot_key(slot), batch);
+    }
+
+    #[test]
+    fn lost_slot_is_applied_before_retrying_the_next() {
+        let kv = MemKv::default();
+        let mut replica = Replica::default();
+        *kv.before_cas.borrow_mut() = Some(Box::new(|data| {
+            data.insert(slot_key(0), json!([[["append", 1, 1]]]));
+        }));
+        let res = replica.sequence(&kv, &txns(json!([[["append", 1, 2], ["r", 1, null]]])));
+        let expected: Vec<MicroOp> =
+            serde_json::from_value(json!([["append", 1, 2], ["r", 1, [1, 2]]])).unwrap();
+        assert_eq!(
+            res.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+            vec![expected]
+        );
+        assert_eq!(replica.applied, 2);
+        assert_eq!(
+            kv.data.borrow()[&slot_key(1)],
+            json!([[["append", 1, 2], ["r", 1, null]]])
+        );
+    }
+
+    #[test]
+    fn read_only_batch_catches_up_without_a_slot() {
+        let kv = MemKv::default();
+        // more slots than one window
+        for slot in 0..(WINDOW as u64 + 4) {
+            log(&kv, slot, json!([[["append", 1, slot]]]));
+        }
+        let mut replica = Replica::default();
+        let res = replica.sequence(&kv, &txns(json!([[["r", 1, null]]])));
+        let all: Vec<u64> = (0..WINDOW as u64 + 4).collect();
+        assert_eq!(
+            serde_json::to_value(res[0].as_ref().unwrap()).unwrap(),
+            json!([["r", 1, all]])
+        );
+        assert_eq!(replica.applied, WINDOW as u64 + 4);
+        assert_eq!(kv.data.borrow().len(), WINDOW + 4);
+    }
+
+    #[test]
+    fn failing_txns_fail_the_same_on_every_replica() {
+        let kv = MemKv::default();
+        let mut a = Replica::default();
+        let res = a.sequence(
+            &kv,
+            &txns(json!([
+                [["w", 1, 5]],
+                [["append", 1, 6]],
+                [["append", 2, 7]]
+            ])),
+        );
+        assert!(res[0].is_ok() && res[2].is_ok());
+        assert_eq!(res[1].as_ref().unwrap_err().code, PRECONDITION_FAILED);
+
+        let mut b = Replica::default();
+        b.sequence(&kv, &txns(json!([[["r", 1, null]]])));
+        assert_eq!(a.state, b.state);
+        assert_eq!(b.state[&TxnKey::new(json!(1))], json!(5));
+        assert_eq!(b.state[&TxnKey::new(json!(2))], json!([7]));
+    }
+
+    /// lin-kv that fails every CAS with `code`.
+    struct FailingCas {
+        kv: MemKv,
+        code: u64,
+    }
+
+    impl LinKv for FailingCas {
+        fn read_many(&self, keys: &[String]) -> Vec<Option<Value>> {
+            self.kv.read_many(keys)
+        }
+
+        fn cas_many(&self, changes: Vec<(String, Value, Value)>) -> Vec<Result<(), u64>> {
+            vec![Err(self.code); changes.len()]
+        }
+    }
+
+    #[test]
+    fn other_cas_errors_fail_the_batch() {
+        let kv = FailingCas {
+            kv: MemKv::default(),
+            code: 11,
+        };
+        let mut replica = Replica::default();
+        let res = replica.sequence(&kv, &txns(json!([[["w", 1, 5]], [["w", 2, 6]]])));
+        assert_eq!(res.len(), 2);
+        for res in res {
+            assert_eq!(res.unwrap_err().code, CRASH);
+        }
+        assert_eq!(replica.applied, 0);
+    }
+}
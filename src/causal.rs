@@ -0,0 +1,160 @@
+//! Causal broadcast.
+//!
+//! Every broadcast is stamped with the sender's vector clock and sent to all
+//! nodes. A receiver holds a message back until every message that causally
+//! precedes it has been delivered, so if a node saw `a` before broadcasting
+//! `b`, no node delivers `b` before `a`. Concurrent messages may be
+//! delivered in different orders on different nodes.
+use crate::messages::BroadcastValue;
+use crate::vector_clock::VectorClock;
+
+#[derive(Debug, Default)]
+pub struct CausalBroadcast {
+    // number of messages delivered from each node
+    delivered: VectorClock,
+    // (origin, clock, message) waiting for their causal past
+    holdback: Vec<(String, VectorClock, BroadcastValue)>,
+}
+
+impl CausalBroadcast {
+    /// Delivers a local broadcast and returns the clock to send it with.
+    pub fn stamp(&mut self, id: &str) -> VectorClock {
+        self.delivered.increment(id);
+        self.delivered.clone()
+    }
+
+    /// Accepts a message from `origin` and returns every message that became
+    /// deliverable, in causal order.
+    pub fn receive(
+        &mut self,
+        origin: &str,
+        clock: VectorClock,
+        message: BroadcastValue,
+    ) -> Vec<BroadcastValue> {
+        // delivered already, or waiting in the holdback
+        let counter = clock.get(origin);
+        let duplicate = clock == self.delivered
+            || clock.happened_before(&self.delivered)
+            || self
+                .holdback
+                .iter()
+                .any(|(o, c, _)| o == origin && c.get(origin) == counter);
+        if duplicate {
+            return Vec::new();
+        }
+        self.holdback.push((origin.to_string(), clock, message));
+
+        let mut ready = Vec::new();
+        while let Some(i) = self
+            .holdback
+            .iter()
+            .position(|(o, c, _)| self.delivered.can_deliver(c, o))
+        {
+            // the next one from its origin and nothing newer from the others
+            let (_, clock, message) = self.holdback.swap_remove(i);
+            self.delivered.merge(&clock);
+            ready.push(message);
+        }
+        if !self.holdback.is_empty() {
+            eprintln!("causal holdback: {}", self.holdback.len());
+        }
+        ready
+    }
+
+    pub fn clock(&self) -> &VectorClock {
+        &self.delivered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(counters: &[(&str, u64)]) -> VectorClock {
+        let mut clock = VectorClock::new();
+        for &(node, counter) in counters {
+            for _ in 0..counter {
+                clock.increment(node);
+            }
+        }
+        clock
+    }
+
+    fn values(ready: Vec<BroadcastValue>) -> Vec<u64> {
+        ready.iter().filter_map(BroadcastValue::as_u64).collect()
+    }
+
+    #[test]
+    fn in_order_messages_are_delivered_at_once() {
+        let mut causal = CausalBroadcast::default();
+        let ready = causal.receive("n0", clock(&[("n0", 1)]), 1.into());
+        assert_eq!(values(ready), vec![1]);
+        let ready = causal.receive("n0", clock(&[("n0", 2)]), 2.into());
+        assert_eq!(values(ready), vec![2]);
+        assert_eq!(causal.clock(), &clock(&[("n0", 2)]));
+    }
+
+    #[test]
+    fn a_message_waits_for_the_earlier_ones_from_its_origin() {
+        let mut causal = CausalBroadcast::default();
+        assert!(causal
+            .receive("n0", clock(&[("n0", 3)]), 3.into())
+            .is_empty());
+        assert!(causal
+            .receive("n0", clock(&[("n0", 2)]), 2.into())
+            .is_empty());
+        let ready = causal.receive("n0", clock(&[("n0", 1)]), 1.into());
+        assert_eq!(values(ready), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn a_message_waits_for_its_causal_past_from_other_nodes() {
+        let mut causal = CausalBroadcast::default();
+        // n1 saw n0's 1 and 2 before broadcasting 10, the 2 is delayed
+        let after = clock(&[("n0", 2), ("n1", 1)]);
+        assert!(causal.receive("n1", after, 10.into()).is_empty());
+        assert_eq!(
+            values(causal.receive("n0", clock(&[("n0", 1)]), 1.into())),
+            vec![1]
+        );
+        // a concurrent message from n2 does not wait
+        let ready = causal.receive("n2", clock(&[("n2", 1)]), 20.into());
+        assert_eq!(values(ready), vec![20]);
+        let ready = causal.receive("n0", clock(&[("n0", 2)]), 2.into());
+        assert_eq!(values(ready), vec![2, 10]);
+        assert_eq!(causal.clock(), &clock(&[("n0", 2), ("n1", 1), ("n2", 1)]));
+    }
+
+    #[test]
+    fn duplicates_are_dropped() {
+        let mut causal = CausalBroadcast::default();
+        assert!(causal
+            .receive("n0", clock(&[("n0", 2)]), 2.into())
+            .is_empty());
+        assert!(causal
+            .receive("n0", clock(&[("n0", 2)]), 2.into())
+            .is_empty());
+        assert_eq!(
+            values(causal.receive("n0", clock(&[("n0", 1)]), 1.into())),
+            vec![1, 2]
+        );
+        assert!(causal
+            .receive("n0", clock(&[("n0", 1)]), 1.into())
+            .is_empty());
+        assert!(causal
+            .receive("n0", clock(&[("n0", 2)]), 2.into())
+            .is_empty());
+    }
+
+    #[test]
+    fn own_broadcasts_are_delivered_when_stamped() {
+        let mut causal = CausalBroadcast::default();
+        assert_eq!(causal.stamp("n0"), clock(&[("n0", 1)]));
+        causal.receive("n1", clock(&[("n0", 1), ("n1", 1)]), 5.into());
+        assert_eq!(causal.stamp("n0"), clock(&[("n0", 2), ("n1", 1)]));
+        // our own message echoed back is a duplicate
+        assert!(causal
+            .receive("n0", clock(&[("n0", 1)]), 1.into())
+            .is_empty());
+    }
+}
@@ -0,0 +1,192 @@
+//! Lists stored as linked chunk segments.
+//!
+//! Every key's value lives in an immutable chunk. Copying a whole list into
+//! a new chunk on every append makes txns slower as lists grow, so an append
+//! only writes a segment with the new elements. A segment lists the ids of
+//! all chunks before it, oldest first, so a reader fetches them in a single
+//! round trip. Once a chain is `MAELSTROM_LIST_SEGMENTS` chunks long the
+//! next write compacts it into one value chunk.
+//!
+//! ```text
+//! "n0-…-3": {"value": [1, 2, 3]}
+//! "n0-…-8": {"segment": {"prev": ["n0-…-3"], "elements": [4]}}
+//! "n1-…-2": {"segment": {"prev": ["n0-…-3", "n0-…-8"], "elements": [5, 6]}}
+//! ```
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Chunk {
+    /// the whole value, a written value or a compacted list
+    Value(Value),
+    /// elements appended to the list made of the `prev` chunks
+    Segment {
+        prev: Vec<String>,
+        elements: Vec<Value>,
+    },
+}
+
+impl Chunk {
+    /// The chunks this one is appended to, they are needed to read it.
+    pub fn prev(&self) -> &[String] {
+        match self {
+            Chunk::Value(_) => &[],
+            Chunk::Segment { prev, .. } => prev,
+        }
+    }
+
+    /// The value of a chunk, given the chunks in `prev()` in the same order.
+    pub fn assemble(&self, prev: &[Chunk]) -> Value {
+        if let Chunk::Value(value) = self {
+            return value.clone();
+        }
+        let mut list = Vec::new();
+        for chunk in prev.iter().chain([self]) {
+            match chunk {
+                Chunk::Value(Value::Array(items)) => list = items.clone(),
+                Chunk::Value(other) => panic!("a list segment follows {other}"),
+                Chunk::Segment { elements, .. } => list.extend(elements.iter().cloned()),
+            }
+        }
+        Value::Array(list)
+    }
+
+    /// The chunk for `new`, the value replacing `old` that is stored in the
+    /// chunk `head`. A list that only grew gets a segment linked to `head`,
+    /// anything else, or a chain that reached `max_segments`, a value chunk.
+    pub fn next(
+        head: Option<(&String, &Chunk)>,
+        old: &Value,
+        new: &Value,
+        max_segments: usize,
+    ) -> Chunk {
+        if let (Some((id, chunk)), Value::Array(old), Value::Array(new)) = (head, old, new) {
+            if chunk.prev().len() + 1 < max_segments && new.starts_with(old) {
+                let mut prev = chunk.prev().to_vec();
+                prev.push(id.clone());
+                return Chunk::Segment {
+                    prev,
+                    elements: new[old.len()..].to_vec(),
+                };
+            }
+        }
+        Chunk::Value(new.clone())
+    }
+
+    /// The chunks no longer needed once `self` replaced `head` and its
+    /// chain: none for a segment linked to it, all of them otherwise.
+    pub fn replaced(&self, head: (&String, &Chunk)) -> Vec<String> {
+        if let Chunk::Segment { prev, .. } = self {
+            if prev.last() == Some(head.0) {
+                return Vec::new();
+            }
+        }
+        let mut chain = head.1.prev().to_vec();
+        chain.push(head.0.clone());
+        chain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Appends `elements` to the list stored in the chain ending at `head`.
+    fn append(chain: &[(String, Chunk)], elements: Value, max_segments: usize) -> Chunk {
+        let ((id, head), prev) = chain.split_last().unwrap();
+        let old = head.assemble(&chunks(prev));
+        let mut new = old.as_array().unwrap().clone();
+        new.extend(elements.as_array().unwrap().iter().cloned());
+        Chunk::next(Some((id, head)), &old, &Value::Array(new), max_segments)
+    }
+
+    fn chunks(chain: &[(String, Chunk)]) -> Vec<Chunk> {
+        chain.iter().map(|(_, c)| c.clone()).collect()
+    }
+
+    #[test]
+    fn an_append_writes_only_the_new_elements() {
+        let head = Chunk::Value(json!([1, 2]));
+        let id = "c0".to_string();
+        let next = Chunk::next(Some((&id, &head)), &json!([1, 2]), &json!([1, 2, 3, 4]), 8);
+        assert_eq!(
+            next,
+            Chunk::Segment {
+                prev: vec!["c0".to_string()],
+                elements: vec![json!(3), json!(4)]
+            }
+        );
+        assert_eq!(next.prev(), ["c0".to_string()]);
+        assert_eq!(
+            next.assemble(std::slice::from_ref(&head)),
+            json!([1, 2, 3, 4])
+        );
+        assert!(next.replaced((&id, &head)).is_empty());
+    }
+
+    #[test]
+    fn segments_chain_up_to_the_limit_then_compact() {
+        let mut chain = vec![("c0".to_string(), Chunk::Value(json!([0])))];
+        for i in 1..4 {
+            let next = append(&chain, json!([i]), 4);
+            assert_eq!(next.prev().len(), i as usize);
+            chain.push((format!("c{i}"), next));
+        }
+        let (last, prev) = chain.split_last().unwrap();
+        assert_eq!(last.1.assemble(&chunks(prev)), json!([0, 1, 2, 3]));
+
+        // the chain has 4 chunks, the next write compacts it
+        let compacted = append(&chain, json!([4]), 4);
+        assert_eq!(compacted, Chunk::Value(json!([0, 1, 2, 3, 4])));
+        let (id, head) = chain.last().unwrap();
+        assert_eq!(compacted.replaced((id, head)), ["c0", "c1", "c2", "c3"]);
+    }
+
+    #[test]
+    fn anything_but_growing_a_list_writes_a_value() {
+        let head = Chunk::Value(json!([1, 2]));
+        let id = "c0".to_string();
+        let next = |old: Value, new: Value| Chunk::next(Some((&id, &head)), &old, &new, 8);
+        assert_eq!(
+            next(json!([1, 2]), json!([2, 3])),
+            Chunk::Value(json!([2, 3]))
+        );
+        assert_eq!(next(json!([1, 2]), json!("x")), Chunk::Value(json!("x")));
+        assert_eq!(next(json!(5), json!([5])), Chunk::Value(json!([5])));
+        assert_eq!(
+            Chunk::next(None, &json!([]), &json!([1]), 8),
+            Chunk::Value(json!([1]))
+        );
+        // one segment per chunk means no segments at all
+        let unsegmented = Chunk::next(Some((&id, &head)), &json!([1, 2]), &json!([1, 2, 3]), 1);
+        assert_eq!(unsegmented, Chunk::Value(json!([1, 2, 3])));
+    }
+
+    #[test]
+    fn a_value_chunk_replaces_the_whole_chain() {
+        let segment = Chunk::Segment {
+            prev: vec!["c0".to_string(), "c1".to_string()],
+            elements: vec![json!(3)],
+        };
+        let id = "c2".to_string();
+        let written = Chunk::Value(json!("x"));
+        assert_eq!(written.replaced((&id, &segment)), ["c0", "c1", "c2"]);
+        assert_eq!(written.assemble(&[]), json!("x"));
+    }
+
+    #[test]
+    fn chunks_keep_their_stored_format() {
+        let segment = Chunk::Segment {
+            prev: vec!["c0".to_string()],
+            elements: vec![json!(4)],
+        };
+        let stored = json!({"segment": {"prev": ["c0"], "elements": [4]}});
+        assert_eq!(serde_json::to_value(&segment).unwrap(), stored);
+        assert_eq!(
+            serde_json::to_value(Chunk::Value(json!([1]))).unwrap(),
+            json!({"value": [1]})
+        );
+    }
+}
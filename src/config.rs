@@ -0,0 +1,216 @@
+//! Runtime settings.
+//!
+//! Maelstrom starts the node binary without arguments, so every knob is an
+//! environment variable read once when the node is created, e.g.
+//!
+//! ```shell
+//! MAELSTROM_BROADCAST=plumtree ./test.sh c3d
+//! ```
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BroadcastMode {
+    /// Forward every new value to all topology neighbors.
+    Flood,
+    /// Epidemic broadcast trees, see `plumtree.rs`.
+    Plumtree,
+    /// Same delivery order on every node, see `total_order.rs`.
+    TotalOrder,
+    /// Delivery respects happened-before, see `causal.rs`.
+    Causal,
+}
+
+/// See `txn_engine.rs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxnEngineKind {
+    Memory,
+    Thunk,
+    Partitioned,
+    Percolator,
+    Occ,
+    Calvin,
+}
+
+/// See `partitioning.rs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitioningKind {
+    Range,
+    Hash,
+    Adaptive,
+}
+
+/// How the partitioned engine maps keys to chunk ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexKind {
+    /// Partition maps listed in the root, see `partitioning.rs`.
+    Partitions,
+    /// A persistent b-tree, see `btree.rs`.
+    BTree,
+}
+
+/// How the thunk engine encodes its objects, see `thunk.rs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThunkCodec {
+    /// `thunk::Json`
+    Json,
+    /// `thunk::JsonString`
+    JsonString,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub broadcast_mode: BroadcastMode,
+    pub txn_engine: TxnEngineKind,
+    /// Entries in each of the node's chunk, partition and tree node caches.
+    pub cache_size: usize,
+    /// How many times a txn is re-run after losing the root CAS.
+    pub txn_retries: u32,
+    /// Base delay before a retry, doubled on every attempt.
+    pub txn_backoff: Duration,
+    /// Max txns committed together, 1 disables group commit.
+    pub txn_batch: usize,
+    /// How long a batch waits for more txns to come in, 0 takes only the
+    /// queued ones.
+    pub txn_batch_window: Duration,
+    /// Forward txns to a leader, see `txn_leader.rs`.
+    pub txn_leader: bool,
+    pub txn_lease: Duration,
+    /// How long a percolator or occ lock holds before other txns may
+    /// resolve it.
+    pub txn_lock_ttl: Duration,
+    pub partitioning: PartitioningKind,
+    /// Keys per range, or the split threshold of adaptive partitions, at
+    /// least 1.
+    pub partition_size: usize,
+    /// Buckets of hash partitioning, at least 1.
+    pub partition_count: usize,
+    pub index: IndexKind,
+    /// Max entries of a b-tree node, at least 3.
+    pub btree_order: usize,
+    /// Max chunks a list is split into before it is compacted, 1 stores
+    /// every list in one chunk.
+    pub list_segments: usize,
+    pub thunk_codec: ThunkCodec,
+    /// Root versions whose lin-kv objects are kept, 0 disables gc.
+    pub gc_window: u64,
+    /// Root versions `read_at` can go back to, the gc keeps at least as many.
+    pub history: u64,
+    /// Where the write-ahead logs go, see `wal.rs`. No log if unset.
+    pub wal_dir: Option<PathBuf>,
+    /// Records after which the log is compacted into a snapshot.
+    pub wal_snapshot: usize,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let broadcast_mode = match env("MAELSTROM_BROADCAST").as_deref() {
+            None | Some("flood") => BroadcastMode::Flood,
+            Some("plumtree") => BroadcastMode::Plumtree,
+            Some("total-order") => BroadcastMode::TotalOrder,
+            Some("causal") => BroadcastMode::Causal,
+            Some(other) => panic!("unknown MAELSTROM_BROADCAST: {other}"),
+        };
+
+        let txn_engine = match env("MAELSTROM_TXN_ENGINE").as_deref() {
+            None if cfg!(feature = "lin_kv") => TxnEngineKind::Partitioned,
+            None | Some("memory") => TxnEngineKind::Memory,
+            Some("thunk") => TxnEngineKind::Thunk,
+            Some("partitioned") => TxnEngineKind::Partitioned,
+            Some("percolator") => TxnEngineKind::Percolator,
+            Some("occ") => TxnEngineKind::Occ,
+            Some("calvin") => TxnEngineKind::Calvin,
+            Some(other) => panic!("unknown MAELSTROM_TXN_ENGINE: {other}"),
+        };
+
+        let cache_size = number("MAELSTROM_CACHE_SIZE").unwrap_or(4096);
+        let txn_retries = number("MAELSTROM_TXN_RETRIES").unwrap_or(5);
+        let txn_backoff = Duration::from_millis(number("MAELSTROM_TXN_BACKOFF_MS").unwrap_or(2));
+        let txn_batch = number("MAELSTROM_TXN_BATCH").unwrap_or(32).max(1);
+        let txn_batch_window = Duration::from_millis(number("MAELSTROM_TXN_BATCH_MS").unwrap_or(0));
+        let txn_leader = matches!(
+            env("MAELSTROM_TXN_LEADER").as_deref(),
+            Some("1" | "on" | "true")
+        );
+        let txn_lease = Duration::from_millis(number("MAELSTROM_TXN_LEASE_MS").unwrap_or(1000));
+        let txn_lock_ttl =
+            Duration::from_millis(number("MAELSTROM_TXN_LOCK_TTL_MS").unwrap_or(1000));
+
+        let partitioning = match env("MAELSTROM_PARTITIONING").as_deref() {
+            None | Some("range") => PartitioningKind::Range,
+            Some("hash") => PartitioningKind::Hash,
+            Some("adaptive") => PartitioningKind::Adaptive,
+            Some(other) => panic!("unknown MAELSTROM_PARTITIONING: {other}"),
+        };
+        let partition_size = number("MAELSTROM_PARTITION_SIZE").unwrap_or(20).max(1);
+        let partition_count = number("MAELSTROM_PARTITION_COUNT").unwrap_or(16).max(1);
+
+        let index = match env("MAELSTROM_TXN_INDEX").as_deref() {
+            None | Some("partitions") => IndexKind::Partitions,
+            Some("btree") => IndexKind::BTree,
+            Some(other) => panic!("unknown MAELSTROM_TXN_INDEX: {other}"),
+        };
+        let btree_order = number("MAELSTROM_BTREE_ORDER").unwrap_or(32).max(3);
+        let gc_window = number("MAELSTROM_GC_WINDOW").unwrap_or(16);
+        let history = number("MAELSTROM_HISTORY").unwrap_or(0);
+        let wal_dir = env("MAELSTROM_WAL_DIR").map(PathBuf::from);
+        // the log does not hold their delivery state, a restarted node would
+        // deliver everything again in a new order
+        if wal_dir.is_some()
+            && matches!(
+                broadcast_mode,
+                BroadcastMode::TotalOrder | BroadcastMode::Causal
+            )
+        {
+            panic!("MAELSTROM_WAL_DIR does not work with MAELSTROM_BROADCAST={broadcast_mode:?}");
+        }
+        let wal_snapshot = number("MAELSTROM_WAL_SNAPSHOT").unwrap_or(1000).max(1);
+        let list_segments = number("MAELSTROM_LIST_SEGMENTS").unwrap_or(8).max(1);
+        let thunk_codec = match env("MAELSTROM_THUNK_CODEC").as_deref() {
+            None | Some("json") => ThunkCodec::Json,
+            Some("json-string") => ThunkCodec::JsonString,
+            Some(other) => panic!("unknown MAELSTROM_THUNK_CODEC: {other}"),
+        };
+
+        Config {
+            broadcast_mode,
+            txn_engine,
+            cache_size,
+            txn_retries,
+            txn_backoff,
+            txn_batch,
+            txn_batch_window,
+            txn_leader,
+            txn_lease,
+            txn_lock_ttl,
+            partitioning,
+            partition_size,
+            partition_count,
+            index,
+            btree_order,
+            list_segments,
+            thunk_codec,
+            gc_window,
+            history,
+            wal_dir,
+            wal_snapshot,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::from_env()
+    }
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+fn number<T: std::str::FromStr>(name: &str) -> Option<T> {
+    env(name).map(|v| {
+        v.parse()
+            .unwrap_or_else(|_| panic!("{name} must be a number, got {v}"))
+    })
+}
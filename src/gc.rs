@@ -0,0 +1,135 @@
+//! Garbage collection of lin-kv objects.
+//!
+//! Chunks, partitions and tree nodes are immutable, every commit writes new
+//! ones and leaves the old ones behind. lin-kv can neither delete nor list
+//! keys, so each node remembers what its own commits made unreachable and
+//! later overwrites those keys with a small tombstone.
+//!
+//! An object superseded by root version `s` is still reachable from the
+//! roots before `s`. It is kept until the newest `window` roots no longer
+//! include any of those, so a txn working on a slightly old root keeps
+//! seeing its objects. A txn that is slower than that and reads a tombstone
+//! aborts with `TXN_CONFLICT` and is re-run on the current root.
+//!
+//! Objects written by a txn that lost the root CAS were never reachable and
+//! are collected right away. Garbage known only to a node that crashes is
+//! leaked.
+use crate::messages::PRECONDITION_FAILED;
+
+pub const TOMBSTONE: &str = "__gc__";
+
+pub fn is_tombstone(value: &serde_json::Value) -> bool {
+    value.as_str() == Some(TOMBSTONE)
+}
+
+#[derive(Debug, Default)]
+pub struct Gc {
+    /// Root versions whose objects are kept, 0 disables collection.
+    window: u64,
+    // (root version that superseded them, object ids)
+    retired: Vec<(u64, Vec<String>)>,
+}
+
+impl Gc {
+    pub fn new(window: u64) -> Self {
+        Self {
+            window,
+            retired: Vec::new(),
+        }
+    }
+
+    /// Records `ids` as unreachable from root `version` on. Use version 0
+    /// for objects that no root ever referenced.
+    pub fn retire(&mut self, version: u64, ids: Vec<String>) {
+        if self.window == 0 || ids.is_empty() {
+            return;
+        }
+        self.retired.push((version, ids));
+    }
+
+    /// Retires what a commit of root `version` superseded, or what it wrote
+    /// if it lost the root CAS, as nothing will ever reference that. After
+    /// any other error the CAS may have happened after all, so everything
+    /// is kept.
+    pub fn after_commit(
+        &mut self,
+        committed: Result<(), u64>,
+        version: u64,
+        superseded: Vec<String>,
+        written: Vec<String>,
+    ) {
+        match committed {
+            Ok(()) => self.retire(version, superseded),
+            Err(PRECONDITION_FAILED) => self.retire(0, written),
+            Err(code) => eprintln!("root cas failed with error {code}"),
+        }
+    }
+
+    /// Takes the objects unreachable from the last `window` roots, given
+    /// that `current` is the latest root version.
+    pub fn collect(&mut self, current: u64) -> Vec<String> {
+        let mut ids = Vec::new();
+        let window = self.window;
+        self.retired.retain_mut(|(version, batch)| {
+            if *version + window <= current + 1 {
+                ids.append(batch);
+                false
+            } else {
+                true
+            }
+        });
+        ids
+    }
+
+    pub fn pending(&self) -> usize {
+        self.retired.iter().map(|(_, ids)| ids.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn objects_are_collected_once_window_newer_roots_exist() {
+        let mut gc = Gc::new(3);
+        // superseded by root 5, so still reachable from root 4
+        gc.retire(5, ids(&["a"]));
+        gc.retire(6, ids(&["b"]));
+        assert!(gc.collect(5).is_empty());
+        assert!(gc.collect(6).is_empty());
+        // roots 5, 6 and 7 are the last 3, none of them has "a"
+        assert_eq!(gc.collect(7), ids(&["a"]));
+        assert_eq!(gc.pending(), 1);
+        assert_eq!(gc.collect(8), ids(&["b"]));
+        assert!(gc.collect(100).is_empty());
+    }
+
+    #[test]
+    fn lost_root_cas_only_collects_what_the_txn_wrote() {
+        let mut gc = Gc::new(3);
+        gc.after_commit(Err(PRECONDITION_FAILED), 5, ids(&["old"]), ids(&["new"]));
+        assert_eq!(gc.collect(4), ids(&["new"]));
+        assert!(gc.collect(100).is_empty());
+    }
+
+    #[test]
+    fn failed_root_cas_collects_nothing() {
+        let mut gc = Gc::new(3);
+        gc.after_commit(Err(11), 5, ids(&["old"]), ids(&["new"]));
+        assert_eq!(gc.pending(), 0);
+        assert!(gc.collect(100).is_empty());
+    }
+
+    #[test]
+    fn disabled_gc_keeps_everything() {
+        let mut gc = Gc::new(0);
+        gc.after_commit(Ok(()), 5, ids(&["old"]), ids(&["new"]));
+        gc.retire(0, ids(&["lost"]));
+        assert!(gc.collect(100).is_empty());
+    }
+}
@@ -1,18 +1,66 @@
-use crate::node::Node;
+use std::cell::Cell;
+use uuid::Uuid;
 
-static mut NEXT_ID: usize = 0;
+/// Ids for objects written to storage: chunks, partitions and tree nodes.
+///
+/// A counter alone starts over at 0 when the kill nemesis restarts a node,
+/// which would then overwrite its live objects. So every id also carries a
+/// random epoch drawn once per process, e.g. `n0-3fa85f6457174562-7`.
+#[derive(Debug)]
+pub struct IdGen {
+    epoch: String,
+    next: Cell<u64>,
+}
+
+impl IdGen {
+    pub fn new() -> Self {
+        IdGen {
+            epoch: format!("{:016x}", Uuid::new_v4().as_u64_pair().0),
+            next: Cell::new(0),
+        }
+    }
 
-pub struct IdGen<'a> {
-    node: &'a Node,
+    /// A new id starting with `prefix`, unique across restarts.
+    pub fn next_id(&self, prefix: &str) -> String {
+        let n = self.next.get() + 1;
+        self.next.set(n);
+        format!("{prefix}-{}-{n}", self.epoch)
+    }
 }
 
-impl<'a> IdGen<'a> {
-    pub fn new(node: &'a Node) -> IdGen<'a> {
-        IdGen { node }
+impl Default for IdGen {
+    fn default() -> Self {
+        Self::new()
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
 
-    pub fn next_id(&mut self) -> String {
-        unsafe { NEXT_ID += 1; }
-        format!("{}-{}", self.node.id, unsafe { NEXT_ID })
+    fn counter(id: &str) -> u64 {
+        id.rsplit('-').next().unwrap().parse().unwrap()
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn restarted_node_never_reuses_an_id() {
+        let before = IdGen::new();
+        let old: HashSet<String> = (0..100).map(|_| before.next_id("n0")).collect();
+        // the restarted process counts from 0 again, under a new epoch
+        let after = IdGen::new();
+        for _ in 0..100 {
+            let id = after.next_id("n0");
+            assert!(!old.contains(&id), "{id} reused");
+        }
+    }
+
+    #[test]
+    fn ids_of_one_process_count_up() {
+        let ids = IdGen::new();
+        let counters: Vec<u64> = (0..5).map(|_| counter(&ids.next_id("part"))).collect();
+        assert_eq!(counters, vec![1, 2, 3, 4, 5]);
+        let id = ids.next_id("part");
+        assert!(id.starts_with(&format!("part-{}-", ids.epoch)));
+    }
+}
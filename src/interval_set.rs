@@ -0,0 +1,324 @@
+//! A compact set of integers stored as disjoint, non-adjacent ranges.
+//!
+//! Broadcast values in maelstrom are mostly dense integers, so storing
+//! `[start, end]` ranges keeps the set tiny compared with a `HashSet<u64>`.
+//! The ranges live behind an `Rc`, which makes a snapshot (`clone`) O(1);
+//! the map is only copied when a shared set is mutated.
+use serde::de::{SeqAccess, Visitor};
+use serde::ser::SerializeSeq;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::ops::Bound::{Excluded, Included};
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntervalSet {
+    // start -> end, both inclusive
+    ranges: Rc<BTreeMap<u64, u64>>,
+}
+
+impl IntervalSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns true if the value was not in the set before.
+    pub fn insert(&mut self, value: u64) -> bool {
+        self.insert_range(value, value)
+    }
+
+    /// Inserts all values in `[start, end]`.
+    /// Returns true if at least one of them was not in the set before.
+    pub fn insert_range(&mut self, start: u64, end: u64) -> bool {
+        assert!(start <= end, "invalid range [{start}, {end}]");
+        if self.contains_range(start, end) {
+            return false;
+        }
+
+        let ranges = Rc::make_mut(&mut self.ranges);
+        let (mut start, mut end) = (start, end);
+
+        // merge with the range on the left if they overlap or touch
+        if let Some((&s, &e)) = ranges.range(..=start).next_back() {
+            if e.saturating_add(1) >= start {
+                start = s;
+                end = end.max(e);
+            }
+        }
+
+        // swallow every range starting inside [start, end + 1]
+        let upper = end.saturating_add(1);
+        let merged: Vec<(u64, u64)> = ranges.range(start..=upper).map(|(&s, &e)| (s, e)).collect();
+        for (s, e) in merged {
+            ranges.remove(&s);
+            end = end.max(e);
+        }
+
+        ranges.insert(start, end);
+        true
+    }
+
+    pub fn contains(&self, value: u64) -> bool {
+        self.contains_range(value, value)
+    }
+
+    pub fn contains_range(&self, start: u64, end: u64) -> bool {
+        matches!(self.ranges.range(..=start).next_back(), Some((_, &e)) if e >= end)
+    }
+
+    /// Number of values in the set.
+    pub fn len(&self) -> u64 {
+        self.ranges
+            .iter()
+            .fold(0u64, |acc, (s, e)| acc.saturating_add(e - s + 1))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Number of disjoint ranges, i.e. the real storage cost of the set.
+    pub fn range_count(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// Iterates over the `(start, end)` ranges in ascending order.
+    pub fn ranges(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.ranges.iter().map(|(&s, &e)| (s, e))
+    }
+
+    /// Iterates over every value in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        self.ranges().flat_map(|(s, e)| s..=e)
+    }
+
+    /// Values in `self` but not in `other`.
+    ///
+    /// This is the delta a node has to gossip to a peer whose set is `other`.
+    /// It costs O(ranges) rather than O(values).
+    pub fn difference(&self, other: &IntervalSet) -> IntervalSet {
+        let mut out = BTreeMap::new();
+        for (s, e) in self.ranges() {
+            let mut cur = s;
+            let mut covered = false;
+            // the range of other starting at or before s, if it reaches s
+            let first = other
+                .ranges
+                .range(..=s)
+                .next_back()
+                .filter(|(_, &oe)| oe >= s);
+            let rest = other.ranges.range((Excluded(s), Included(e)));
+            for (&os, &oe) in first.into_iter().chain(rest) {
+                if os > cur {
+                    out.insert(cur, os - 1);
+                }
+                if oe >= e {
+                    covered = true;
+                    break;
+                }
+                cur = cur.max(oe + 1);
+            }
+            if !covered {
+                out.insert(cur, e);
+            }
+        }
+        IntervalSet {
+            ranges: Rc::new(out),
+        }
+    }
+
+    /// Adds every value of `other` into `self`.
+    pub fn union_with(&mut self, other: &IntervalSet) {
+        for (s, e) in other.ranges() {
+            self.insert_range(s, e);
+        }
+    }
+}
+
+impl FromIterator<u64> for IntervalSet {
+    fn from_iter<I: IntoIterator<Item = u64>>(iter: I) -> Self {
+        let mut set = IntervalSet::new();
+        for v in iter {
+            set.insert(v);
+        }
+        set
+    }
+}
+
+// maelstrom expects a plain list of values, e.g. in `read_ok`
+impl Serialize for IntervalSet {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let len = usize::try_from(self.len()).ok();
+        let mut seq = serializer.serialize_seq(len)?;
+        for v in self.iter() {
+            seq.serialize_element(&v)?;
+        }
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for IntervalSet {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct IntervalSetVisitor;
+
+        impl<'de> Visitor<'de> for IntervalSetVisitor {
+            type Value = IntervalSet;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a list of unsigned integers")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let mut set = IntervalSet::new();
+                while let Some(v) = seq.next_element::<u64>()? {
+                    set.insert(v);
+                }
+                Ok(set)
+            }
+        }
+
+        deserializer.deserialize_seq(IntervalSetVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(set: &IntervalSet) -> Vec<(u64, u64)> {
+        set.ranges().collect()
+    }
+
+    #[test]
+    fn insert_reports_new_values() {
+        let mut set = IntervalSet::new();
+        assert!(set.is_empty());
+        assert!(set.insert(5));
+        assert!(!set.insert(5));
+        assert!(set.contains(5));
+        assert!(!set.contains(4));
+        assert_eq!(set.len(), 1);
+    }
+
+    #[test]
+    fn adjacent_values_merge_into_one_range() {
+        let mut set: IntervalSet = [1, 3].into_iter().collect();
+        assert_eq!(ranges(&set), vec![(1, 1), (3, 3)]);
+        set.insert(2);
+        assert_eq!(ranges(&set), vec![(1, 3)]);
+        set.insert(0);
+        set.insert(4);
+        assert_eq!(ranges(&set), vec![(0, 4)]);
+        assert_eq!(set.len(), 5);
+    }
+
+    #[test]
+    fn a_gap_keeps_ranges_apart() {
+        let mut set = IntervalSet::new();
+        set.insert_range(0, 9);
+        set.insert_range(11, 20);
+        assert_eq!(set.range_count(), 2);
+        assert!(!set.contains(10));
+        assert!(!set.contains_range(5, 15));
+        assert!(set.contains_range(12, 20));
+        set.insert(10);
+        assert_eq!(ranges(&set), vec![(0, 20)]);
+    }
+
+    #[test]
+    fn insert_range_swallows_the_ranges_it_covers() {
+        let mut set: IntervalSet = [2, 4, 6, 20].into_iter().collect();
+        assert!(set.insert_range(1, 7));
+        assert_eq!(ranges(&set), vec![(1, 7), (20, 20)]);
+        assert!(!set.insert_range(3, 5));
+        assert!(set.insert_range(7, 19));
+        assert_eq!(ranges(&set), vec![(1, 20)]);
+        set.insert(u64::MAX);
+        set.insert(u64::MAX - 1);
+        assert_eq!(ranges(&set), vec![(1, 20), (u64::MAX - 1, u64::MAX)]);
+    }
+
+    #[test]
+    fn difference_of_overlapping_ranges() {
+        let a: IntervalSet = (0..=20).collect();
+        let mut b = IntervalSet::new();
+        b.insert_range(5, 8);
+        b.insert_range(15, 30);
+        assert_eq!(ranges(&a.difference(&b)), vec![(0, 4), (9, 14)]);
+        assert_eq!(ranges(&b.difference(&a)), vec![(21, 30)]);
+        assert!(a.difference(&a).is_empty());
+    }
+
+    #[test]
+    fn difference_of_adjacent_ranges() {
+        let mut a = IntervalSet::new();
+        a.insert_range(0, 9);
+        let mut b = IntervalSet::new();
+        b.insert_range(10, 19);
+        assert_eq!(ranges(&a.difference(&b)), vec![(0, 9)]);
+        // a range of `other` ending right before one of `self`
+        let mut c = IntervalSet::new();
+        c.insert_range(0, 4);
+        assert_eq!(ranges(&a.difference(&c)), vec![(5, 9)]);
+        c.insert_range(9, 9);
+        assert_eq!(ranges(&a.difference(&c)), vec![(5, 8)]);
+    }
+
+    #[test]
+    fn difference_of_disjoint_ranges() {
+        let a: IntervalSet = [1, 2, 3, 10, 11].into_iter().collect();
+        let b: IntervalSet = [5, 6, 20].into_iter().collect();
+        assert_eq!(a.difference(&b), a);
+        assert_eq!(a.difference(&IntervalSet::new()), a);
+        assert!(IntervalSet::new().difference(&a).is_empty());
+        let mut edge = IntervalSet::new();
+        edge.insert_range(u64::MAX - 2, u64::MAX);
+        let top: IntervalSet = [u64::MAX].into_iter().collect();
+        assert_eq!(
+            ranges(&edge.difference(&top)),
+            vec![(u64::MAX - 2, u64::MAX - 1)]
+        );
+    }
+
+    #[test]
+    fn union_merges_overlapping_adjacent_and_disjoint_ranges() {
+        let mut a: IntervalSet = (0..5).collect();
+        let mut b = IntervalSet::new();
+        b.insert_range(3, 7);
+        b.insert_range(8, 9);
+        b.insert_range(20, 25);
+        a.union_with(&b);
+        assert_eq!(ranges(&a), vec![(0, 9), (20, 25)]);
+        // the delta and what the peer has make up everything
+        let mut peer: IntervalSet = (0..3).collect();
+        peer.union_with(&a.difference(&peer));
+        assert_eq!(peer, a);
+    }
+
+    #[test]
+    fn a_clone_is_a_snapshot() {
+        let mut set: IntervalSet = (0..3).collect();
+        let snapshot = set.clone();
+        set.insert(10);
+        assert_eq!(ranges(&snapshot), vec![(0, 2)]);
+        assert_eq!(ranges(&set), vec![(0, 2), (10, 10)]);
+    }
+
+    #[test]
+    fn serializes_as_a_plain_list() {
+        let set: IntervalSet = [3, 1, 2, 7].into_iter().collect();
+        let json = serde_json::to_string(&set).unwrap();
+        assert_eq!(json, "[1,2,3,7]");
+        let back: IntervalSet = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, set);
+    }
+}
@@ -1,9 +1,38 @@
+pub mod broadcast_set;
+#[cfg(feature = "lin_kv")]
+pub mod btree;
+#[cfg(feature = "lin_kv")]
+pub mod calvin;
+pub mod causal;
+#[cfg(feature = "lin_kv")]
+pub mod chunk_list;
+pub mod config;
+#[cfg(feature = "lin_kv")]
+pub mod gc;
 pub mod idgen;
+pub mod interval_set;
+#[cfg(feature = "lin_kv")]
+pub mod lin_kv;
+pub mod lru_cache;
 pub mod message_handlers;
 pub mod messages;
 pub mod node;
 #[cfg(feature = "lin_kv")]
+pub mod occ;
+#[cfg(feature = "lin_kv")]
+pub mod partitioning;
+#[cfg(feature = "lin_kv")]
+pub mod percolator;
+pub mod plumtree;
 pub mod thunk;
+pub mod total_order;
+pub mod txn_engine;
+#[cfg(feature = "lin_kv")]
+pub mod txn_leader;
+pub mod vector_clock;
+pub mod wal;
+#[cfg(feature = "lin_kv")]
+pub mod transactor;
 #[cfg(feature = "lin_kv")]
 pub mod transactor2;
 #[cfg(feature = "lin_kv")]
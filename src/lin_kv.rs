@@ -0,0 +1,212 @@
+//! Batched access to the lin-kv service.
+use crate::messages::*;
+use crate::node::Node;
+use crate::thunk::{Codec, Json, Store};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const SVC: &str = "lin-kv";
+
+/// Reads all keys in one round trip. A missing key reads as `None`.
+pub fn read_many(node: &Rc<RefCell<Node>>, keys: &[String]) -> Vec<Option<serde_json::Value>> {
+    if keys.is_empty() {
+        return Vec::new();
+    }
+    let reqs = keys
+        .iter()
+        .map(|key| {
+            MessageExtra::KvRead(KvReadExtra {
+                key: key.as_str().into(),
+            })
+        })
+        .collect();
+    node.borrow_mut()
+        .sync_rpc_many(SVC, reqs)
+        .into_iter()
+        .map(|res| match res {
+            MessageExtra::KvReadOk(v) => Some(v.value),
+            MessageExtra::Error(err) if err.code == KEY_DOES_NOT_EXIST => None,
+            _ => panic!("wrong response for lin-kv read"),
+        })
+        .collect()
+}
+
+/// Writes all keys in one round trip.
+pub fn write_many(node: &Rc<RefCell<Node>>, writes: Vec<(String, serde_json::Value)>) {
+    if writes.is_empty() {
+        return;
+    }
+    let reqs = writes
+        .into_iter()
+        .map(|(key, value)| {
+            MessageExtra::KvWrite(KvWriteExtra {
+                key: key.into(),
+                value,
+            })
+        })
+        .collect();
+    for res in node.borrow_mut().sync_rpc_many(SVC, reqs) {
+        if !matches!(res, MessageExtra::KvWriteOk) {
+            unreachable!("the write error is not expected");
+        }
+    }
+}
+
+/// Compare-and-set, creating `key` if it does not exist. The error is the
+/// lin-kv error code, `PRECONDITION_FAILED` if `key` did not hold `from`.
+pub fn cas(
+    node: &Rc<RefCell<Node>>,
+    key: &str,
+    from: serde_json::Value,
+    to: serde_json::Value,
+) -> Result<(), u64> {
+    cas_many(node, vec![(key.to_string(), from, to)])
+        .pop()
+        .unwrap()
+}
+
+/// Several `cas` in one round trip, each with its own outcome.
+pub fn cas_many(
+    node: &Rc<RefCell<Node>>,
+    changes: Vec<(String, serde_json::Value, serde_json::Value)>,
+) -> Vec<Result<(), u64>> {
+    if changes.is_empty() {
+        return Vec::new();
+    }
+    let reqs = changes
+        .into_iter()
+        .map(|(key, from, to)| {
+            MessageExtra::KvCas(KvCasData {
+                key: key.into(),
+                from,
+                to,
+                create_if_not_exists: true,
+            })
+        })
+        .collect();
+    node.borrow_mut()
+        .sync_rpc_many(SVC, reqs)
+        .into_iter()
+        .map(|res| match res {
+            MessageExtra::KvCasOk => Ok(()),
+            MessageExtra::Error(err) => Err(err.code),
+            _ => panic!("wrong response for lin-kv cas"),
+        })
+        .collect()
+}
+
+/// The lin-kv calls of an engine, so it can be tested on a map in memory.
+pub trait LinKv {
+    fn read_many(&self, keys: &[String]) -> Vec<Option<serde_json::Value>>;
+    fn cas_many(
+        &self,
+        changes: Vec<(String, serde_json::Value, serde_json::Value)>,
+    ) -> Vec<Result<(), u64>>;
+}
+
+impl LinKv for Rc<RefCell<Node>> {
+    fn read_many(&self, keys: &[String]) -> Vec<Option<serde_json::Value>> {
+        read_many(self, keys)
+    }
+
+    fn cas_many(
+        &self,
+        changes: Vec<(String, serde_json::Value, serde_json::Value)>,
+    ) -> Vec<Result<(), u64>> {
+        cas_many(self, changes)
+    }
+}
+
+/// lin-kv as a thunk store, see `thunk.rs`.
+#[derive(Debug, Clone)]
+pub struct LinKvStore<C = Json> {
+    node: Rc<RefCell<Node>>,
+    codec: C,
+}
+
+impl<C> LinKvStore<C> {
+    pub fn new(node: &Rc<RefCell<Node>>, codec: C) -> Self {
+        Self {
+            node: node.clone(),
+            codec,
+        }
+    }
+}
+
+impl<C: Codec<serde_json::Value>> Store for LinKvStore<C> {
+    type Raw = serde_json::Value;
+    type Codec = C;
+
+    fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    fn read(&self, id: &str) -> Option<serde_json::Value> {
+        read_many(&self.node, &[id.to_string()]).pop().unwrap()
+    }
+
+    fn read_many(&self, ids: &[String]) -> Vec<Option<serde_json::Value>> {
+        read_many(&self.node, ids)
+    }
+
+    fn write(&self, id: &str, raw: serde_json::Value) -> bool {
+        write_many(&self.node, vec![(id.to_string(), raw)]);
+        true
+    }
+
+    fn next_id(&self) -> String {
+        let node = self.node.borrow();
+        node.ids.next_id(&node.id)
+    }
+}
+
+/// lin-kv in memory, for tests.
+#[cfg(test)]
+pub mod mem {
+    use super::LinKv;
+    use crate::messages::PRECONDITION_FAILED;
+    use serde_json::Value;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    pub type Hook = Box<dyn FnOnce(&mut HashMap<String, Value>)>;
+
+    /// `before_cas` runs once, right before the next CAS, and `after_read`
+    /// right after the next read, to let another txn get in between.
+    #[derive(Default)]
+    pub struct MemKv {
+        pub data: RefCell<HashMap<String, Value>>,
+        pub before_cas: RefCell<Option<Hook>>,
+        pub after_read: RefCell<Option<Hook>>,
+    }
+
+    impl LinKv for MemKv {
+        fn read_many(&self, keys: &[String]) -> Vec<Option<Value>> {
+            let values = {
+                let data = self.data.borrow();
+                keys.iter().map(|key| data.get(key).cloned()).collect()
+            };
+            if let Some(hook) = self.after_read.take() {
+                hook(&mut self.data.borrow_mut());
+            }
+            values
+        }
+
+        fn cas_many(&self, changes: Vec<(String, Value, Value)>) -> Vec<Result<(), u64>> {
+            if let Some(hook) = self.before_cas.take() {
+                hook(&mut self.data.borrow_mut());
+            }
+            let mut data = self.data.borrow_mut();
+            changes
+                .into_iter()
+                .map(|(key, from, to)| match data.get(&key) {
+                    Some(current) if *current != from => Err(PRECONDITION_FAILED),
+                    _ => {
+                        data.insert(key, to);
+                        Ok(())
+                    }
+                })
+                .collect()
+        }
+    }
+}
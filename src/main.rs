@@ -1,23 +1,20 @@
-mod message_handlers;
-mod messages;
-mod node;
+use maelstrom_node::config::BroadcastMode;
+use maelstrom_node::message_handlers::*;
+use maelstrom_node::messages::*;
+use maelstrom_node::node::*;
 
-use message_handlers::*;
-use messages::*;
-use node::*;
-
-use std::io::{self, BufRead};
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Instant;
 
 fn main() {
-    let stdin = io::stdin().lock();
-
     let node = Node::new();
     let node = Rc::new(RefCell::new(node));
+    node.borrow_mut().recover();
     node.borrow_mut().start_broadcast_loop();
 
-    let router: Vec<Box<dyn MessageHandler>> = vec![
+    let mut router: Vec<Box<dyn MessageHandler>> = vec![
         Box::new(InitHandler),
         Box::new(InitOkHandler),
         Box::new(EchoHandler),
@@ -26,15 +23,53 @@ fn main() {
         Box::new(GenerateOkHandler),
         Box::new(TopologyHandler),
         Box::new(TopologyOkHandler),
-        Box::new(BroadcastHandler),
         Box::new(BroadcastOkHandler),
+        Box::new(GossipHandler),
+        Box::new(GossipOkHandler),
         #[cfg(not(feature = "lin_kv"))]
         Box::new(ReadHandler),
         #[cfg(not(feature = "lin_kv"))]
         Box::new(ReadOkHandler),
     ];
 
-    for line in stdin.lines() {
+    let broadcast_mode = node.borrow().config.broadcast_mode;
+    match broadcast_mode {
+        BroadcastMode::Flood => router.push(Box::new(BroadcastHandler)),
+        BroadcastMode::Plumtree => {
+            router.push(Box::new(PlumtreeBroadcastHandler));
+            router.push(Box::new(IHaveHandler));
+            router.push(Box::new(GraftHandler));
+            router.push(Box::new(PruneHandler));
+        }
+        BroadcastMode::TotalOrder => {
+            router.push(Box::new(TotalOrderBroadcastHandler));
+            router.push(Box::new(TobForwardHandler));
+            router.push(Box::new(TobCasReplyHandler));
+            router.push(Box::new(TobDeliverHandler));
+            router.push(Box::new(TobLeaderHandler));
+            router.push(Box::new(AckHandler));
+            router.push(Box::new(ReadLogHandler));
+        }
+        BroadcastMode::Causal => {
+            router.push(Box::new(CausalBroadcastHandler));
+            router.push(Box::new(CausalMessageHandler));
+            router.push(Box::new(AckHandler));
+            router.push(Box::new(ReadLogHandler));
+        }
+    }
+
+    let mut next_tick = Instant::now() + TICK;
+    loop {
+        // the init read by `recover`
+        let queued = node.borrow_mut().inbox.pop_front();
+        if let Some(msg) = queued {
+            if let Some(response) = Node::handle_message(node.clone(), &msg, &router) {
+                node.borrow_mut().send(response);
+            }
+            continue;
+        }
+        let timeout = next_tick.saturating_duration_since(Instant::now());
+        let line = node.borrow().read_line(timeout);
         match line {
             Ok(content) => {
                 if content.is_empty() {
@@ -46,10 +81,21 @@ fn main() {
                             node.borrow_mut().send(response);
                         }
                     }
-                    Err(err) => eprintln!("invalid json data for message: {}", err),
+                    Err(err) => {
+                        eprintln!("invalid json data for message: {}", err);
+                        let reply = node.borrow().malformed_reply(&content, &err);
+                        if let Some(reply) = reply {
+                            node.borrow().send(reply);
+                        }
+                    }
                 }
             }
-            Err(err) => eprintln!("Error reading line: {}", err),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if Instant::now() >= next_tick {
+            on_tick(&node);
+            next_tick = Instant::now() + TICK;
         }
     }
 }
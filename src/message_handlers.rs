@@ -1,11 +1,18 @@
+use crate::broadcast_set::{BroadcastSet, SetDigest};
+use crate::config::BroadcastMode;
 use crate::messages::*;
 use crate::node::*;
-#[cfg(feature = "lin_kv")]
-use crate::Transactor;
+use crate::plumtree;
+use crate::total_order::{ClaimOutcome, GapOutcome};
+use crate::txn_engine::TxnEngine;
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
+/// How often the main loop calls `on_tick`.
+pub const TICK: Duration = Duration::from_millis(50);
+
 pub trait MessageHandler {
     fn can_handle(&self, req: &Message) -> bool;
 
@@ -25,6 +32,8 @@ impl MessageHandler for InitHandler {
         if let MessageExtra::Init(init) = &req.body.extra {
             node.borrow_mut().id = init.node_id.clone();
             node.borrow_mut().node_ids = init.node_ids.clone();
+            // already done by `recover` unless init was not the first message
+            node.borrow_mut().open_wal();
 
             Some(MessageExtra::InitOk)
         } else {
@@ -114,7 +123,12 @@ impl MessageHandler for TopologyHandler {
 
     fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
         if let MessageExtra::Topology(payload) = &req.body.extra {
-            node.borrow_mut().topology = payload.topology.clone();
+            let mut node = node.borrow_mut();
+            node.topology = payload.topology.clone();
+            if node.config.broadcast_mode == BroadcastMode::Plumtree {
+                let (id, neighbors) = (node.id.clone(), node.neighbors());
+                node.plumtree.set_peers(&id, &neighbors);
+            }
             Some(MessageExtra::TopologyOk)
         } else {
             None
@@ -143,31 +157,14 @@ impl MessageHandler for BroadcastHandler {
 
     fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
         if let MessageExtra::Broadcast(payload) = &req.body.extra {
-            if node.borrow_mut().messages_seen.insert(payload.message) {
-                for neibor in node.borrow().topology.get(&node.borrow().id).unwrap() {
+            if node.borrow_mut().mark_seen(&payload.message) {
+                let node = node.borrow();
+                for neibor in node.topology.get(&node.id).unwrap() {
                     if neibor == &req.src {
                         continue;
                     }
-                    let my_req = Message {
-                        src: node.borrow().id.clone(),
-                        dest: neibor.clone(),
-                        body: MessageBody {
-                            msg_id: Some(node.borrow().next_msg_id()),
-                            in_reply_to: None,
-                            // broadcast message
-                            extra: req.body.extra.clone(),
-                        },
-                    };
-                    eprintln!("sent {}", serde_json::to_string(&my_req).unwrap());
-                    {
-                        let node = node.borrow();
-                        let mut unpacked = node.unacked.lock().unwrap();
-                        unpacked.insert(
-                            my_req.body.msg_id.unwrap(),
-                            serde_json::to_string(&my_req).unwrap(),
-                        );
-                    }
-                    node.borrow().send(my_req);
+                    // broadcast message
+                    node.send_reliable(neibor, req.body.extra.clone());
                 }
             }
 
@@ -188,27 +185,550 @@ impl MessageHandler for BroadcastOkHandler {
     fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
         if let Some(in_reply_to) = &req.body.in_reply_to {
             eprintln!("broadcast ok for message {}", in_reply_to);
-            let node = node.borrow();
-            node.unacked.lock().unwrap().remove(in_reply_to);
+            node.borrow().ack(*in_reply_to);
+        }
+        None
+    }
+}
+
+/// Broadcast handler for plumtree mode, replaces `BroadcastHandler`.
+pub struct PlumtreeBroadcastHandler;
+
+impl MessageHandler for PlumtreeBroadcastHandler {
+    fn can_handle(&self, req: &Message) -> bool {
+        matches!(req.body.extra, MessageExtra::Broadcast(_))
+    }
+
+    fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
+        if let MessageExtra::Broadcast(payload) = &req.body.extra {
+            let mut node = node.borrow_mut();
+            let is_new = node.mark_seen(&payload.message);
+            let from_peer = node.node_ids.contains(&req.src).then_some(req.src.as_str());
+            let id = plumtree::message_id(&payload.message);
+
+            if is_new {
+                let (eager, lazy) = node.plumtree.deliver(id, &payload.message, from_peer);
+                for peer in eager {
+                    node.send_reliable(&peer, req.body.extra.clone());
+                }
+                for peer in lazy {
+                    node.send_to(&peer, MessageExtra::IHave(IHaveExtra { ids: vec![id] }));
+                }
+            } else if let Some(peer) = from_peer {
+                if node.plumtree.duplicate(id, peer) {
+                    eprintln!("prune {peer}");
+                    node.send_to(peer, MessageExtra::Prune);
+                }
+            }
+
+            Some(MessageExtra::BroadcastOk)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct IHaveHandler;
+
+impl MessageHandler for IHaveHandler {
+    fn can_handle(&self, req: &Message) -> bool {
+        matches!(req.body.extra, MessageExtra::IHave(_))
+    }
+
+    fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
+        if let MessageExtra::IHave(payload) = &req.body.extra {
+            node.borrow_mut()
+                .plumtree
+                .announced(&payload.ids, &req.src, Instant::now());
+        }
+        None
+    }
+}
+
+pub struct GraftHandler;
+
+impl MessageHandler for GraftHandler {
+    fn can_handle(&self, req: &Message) -> bool {
+        matches!(req.body.extra, MessageExtra::Graft(_))
+    }
+
+    fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
+        if let MessageExtra::Graft(payload) = &req.body.extra {
+            let mut node = node.borrow_mut();
+            let values = node.plumtree.grafted(&payload.ids, &req.src);
+            for message in values {
+                node.send_reliable(
+                    &req.src,
+                    MessageExtra::Broadcast(BroadcastRequestExtra { message }),
+                );
+            }
+        }
+        None
+    }
+}
+
+pub struct PruneHandler;
+
+impl MessageHandler for PruneHandler {
+    fn can_handle(&self, req: &Message) -> bool {
+        matches!(req.body.extra, MessageExtra::Prune)
+    }
+
+    fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
+        node.borrow_mut().plumtree.add_lazy(&req.src);
+        None
+    }
+}
+
+/// Anti-entropy, see `broadcast_set::AntiEntropy`. Takes what the peer has
+/// seen and answers with what it is missing.
+pub struct GossipHandler;
+
+impl MessageHandler for GossipHandler {
+    fn can_handle(&self, req: &Message) -> bool {
+        matches!(req.body.extra, MessageExtra::Gossip(_))
+    }
+
+    fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
+        if let MessageExtra::Gossip(payload) = &req.body.extra {
+            let theirs = BroadcastSet::from(&payload.seen);
+            let mut node = node.borrow_mut();
+            let missing = node.messages_seen.difference(&theirs);
+            let new = node.merge_seen(&theirs);
+            if !new.is_empty() {
+                eprintln!("gossip from {}: {} new values", req.src, new.len());
+            }
+            Some(MessageExtra::GossipOk(GossipExtra {
+                seen: SetDigest::from(&missing),
+            }))
+        } else {
+            None
+        }
+    }
+}
+
+pub struct GossipOkHandler;
+
+impl MessageHandler for GossipOkHandler {
+    fn can_handle(&self, req: &Message) -> bool {
+        matches!(req.body.extra, MessageExtra::GossipOk(_))
+    }
+
+    fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
+        if let MessageExtra::GossipOk(payload) = &req.body.extra {
+            let new = node.borrow_mut().merge_seen(&BroadcastSet::from(&payload.seen));
+            if !new.is_empty() {
+                eprintln!("gossip_ok from {}: {} new values", req.src, new.len());
+            }
+        }
+        None
+    }
+}
+
+/// Timers of the broadcast modes, of txn retries and of txn forwarding,
+/// called by the main loop every `TICK`.
+pub fn on_tick(node: &Rc<RefCell<Node>>) {
+    {
+        // conflicted txns whose backoff passed run before anything new
+        let mut node = node.borrow_mut();
+        let due = node.txn_retries.due(Instant::now());
+        for req in due.into_iter().rev() {
+            node.inbox.push_front(req);
+        }
+    }
+    #[cfg(feature = "lin_kv")]
+    {
+        // unanswered forwards are run here, in the order they came in
+        let mut node = node.borrow_mut();
+        let expired = node.txn_leader.expired(Instant::now());
+        for req in expired.into_iter().rev() {
+            eprintln!("txn forward of {:?} timed out, run it here", req.body.msg_id);
+            node.inbox.push_front(req);
+        }
+    }
+    let now = Instant::now();
+    let mode = node.borrow().config.broadcast_mode;
+    if matches!(mode, BroadcastMode::Flood | BroadcastMode::Plumtree) {
+        let mut node = node.borrow_mut();
+        let neighbors = node.neighbors();
+        if let Some(peer) = node.anti_entropy.peer(now, &neighbors) {
+            let seen = SetDigest::from(&node.messages_seen);
+            node.send_to(&peer, MessageExtra::Gossip(GossipExtra { seen }));
+        }
+    }
+    let mut node = node.borrow_mut();
+    match mode {
+        BroadcastMode::Plumtree => {
+            let grafts = node.plumtree.expired(now);
+            for (peer, ids) in grafts {
+                eprintln!("graft {ids:?} from {peer}");
+                node.send_to(&peer, MessageExtra::Graft(GraftExtra { ids }));
+            }
+        }
+        BroadcastMode::TotalOrder => {
+            let msg_id = node.next_msg_id();
+            if let Some(cas) = node.total_order.elect(msg_id, now) {
+                eprintln!("elect {cas:?}");
+                send_kv(&node, msg_id, MessageExtra::KvCas(cas));
+            }
+            for (seq, value) in node.total_order.expired_claims(now) {
+                eprintln!("claim of slot {seq} timed out");
+                tob_claim(&mut node, &value, Some(seq));
+            }
+            let msg_id = node.next_msg_id();
+            if let Some(read) = node.total_order.read_gap(msg_id, now) {
+                send_kv(&node, msg_id, MessageExtra::kv_read(read));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Sends a request to lin-kv, its reply is handled by `TobCasReplyHandler`.
+fn send_kv(node: &Node, msg_id: u64, extra: MessageExtra) {
+    node.send(Message {
+        src: node.id.clone(),
+        dest: "lin-kv".to_string(),
+        body: MessageBody {
+            msg_id: Some(msg_id),
+            in_reply_to: None,
+            extra,
+        },
+    });
+}
+
+/// Asks lin-kv for a slot of the total-order log, `seq` picks a specific one.
+fn tob_claim(node: &mut Node, value: &BroadcastValue, seq: Option<u64>) {
+    let msg_id = node.next_msg_id();
+    let cas = match seq {
+        Some(seq) => node.total_order.claim_slot(msg_id, seq, value),
+        None => node.total_order.claim(msg_id, value),
+    };
+    send_kv(node, msg_id, MessageExtra::KvCas(cas));
+}
+
+/// Sequences `value` if this node is the sequencer, or forwards it.
+fn tob_submit(node: &mut Node, value: &BroadcastValue) {
+    if node.messages_seen.contains(value) {
+        return;
+    }
+    let tob = &mut node.total_order;
+    if tob.is_leader() {
+        if tob.should_claim(value) {
+            tob_claim(node, value, None);
+        }
+    } else {
+        node.send_reliable(
+            node.total_order.leader(),
+            MessageExtra::TobForward(BroadcastRequestExtra {
+                message: value.clone(),
+            }),
+        );
+    }
+}
+
+/// Appends values to `delivery_log` in the order given. A value delivered
+/// twice, e.g. one that won two total-order slots, only counts the first time.
+fn deliver_in_order(node: &Rc<RefCell<Node>>, ready: Vec<BroadcastValue>) {
+    let mut node = node.borrow_mut();
+    for value in ready {
+        if node.mark_seen(&value) {
+            node.delivery_log.push(value);
+        }
+    }
+}
+
+/// Broadcast handler for total-order mode, replaces `BroadcastHandler`.
+pub struct TotalOrderBroadcastHandler;
+
+impl MessageHandler for TotalOrderBroadcastHandler {
+    fn can_handle(&self, req: &Message) -> bool {
+        matches!(req.body.extra, MessageExtra::Broadcast(_))
+    }
+
+    fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
+        if let MessageExtra::Broadcast(payload) = &req.body.extra {
+            let mut node = node.borrow_mut();
+            let node = &mut *node;
+            node.total_order.init(&node.id, &node.node_ids);
+            if !node.messages_seen.contains(&payload.message) {
+                node.total_order.submit(&payload.message);
+                tob_submit(node, &payload.message);
+            }
+            Some(MessageExtra::BroadcastOk)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct TobForwardHandler;
+
+impl MessageHandler for TobForwardHandler {
+    fn can_handle(&self, req: &Message) -> bool {
+        matches!(req.body.extra, MessageExtra::TobForward(_))
+    }
+
+    fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
+        if let MessageExtra::TobForward(payload) = &req.body.extra {
+            let mut node = node.borrow_mut();
+            let node = &mut *node;
+            node.total_order.init(&node.id, &node.node_ids);
+            tob_submit(node, &payload.message);
+            Some(MessageExtra::TobForwardOk)
+        } else {
+            None
+        }
+    }
+}
+
+/// Handles lin-kv replies to slot claims, sequencer elections and reads of
+/// missing slots.
+pub struct TobCasReplyHandler;
+
+impl MessageHandler for TobCasReplyHandler {
+    fn can_handle(&self, req: &Message) -> bool {
+        matches!(
+            req.body.extra,
+            MessageExtra::KvCasOk | MessageExtra::Error(_)
+        ) || req.body.extra.kv_read_value().is_some()
+    }
+
+    fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
+        let in_reply_to = req.body.in_reply_to?;
+        let error = match &req.body.extra {
+            MessageExtra::Error(err) => Some(err.code),
+            _ => None,
+        };
+
+        let ready = {
+            let mut node = node.borrow_mut();
+            let tob = &mut node.total_order;
+            if let Some(values) = tob.election_result(in_reply_to, error.is_none()) {
+                if error.is_none() {
+                    let epoch = tob.epoch();
+                    for peer in tob.peers() {
+                        node.send_reliable(
+                            &peer,
+                            MessageExtra::TobLeader(TobLeaderExtra {
+                                epoch,
+                                leader: node.id.clone(),
+                            }),
+                        );
+                    }
+                    for value in values {
+                        tob_submit(&mut node, &value);
+                    }
+                }
+                return None;
+            }
+
+            match tob.gap_result(in_reply_to, &req.body.extra) {
+                GapOutcome::Decided(seq, message) => {
+                    eprintln!("missing slot {seq} holds {message:?}");
+                    tob.receive(seq, message)
+                }
+                GapOutcome::Empty(seq) => {
+                    let msg_id = node.next_msg_id();
+                    if let Some(cas) = node.total_order.fill_gap(msg_id) {
+                        eprintln!("fill missing slot {seq}");
+                        send_kv(&node, msg_id, MessageExtra::KvCas(cas));
+                    }
+                    Vec::new()
+                }
+                GapOutcome::Failed => Vec::new(),
+                GapOutcome::Unknown => match tob.claim_result(in_reply_to, error) {
+                ClaimOutcome::Committed(seq, message) => {
+                    eprintln!("slot {seq} decided: {message:?}");
+                    for peer in node.total_order.peers() {
+                        node.send_reliable(
+                            &peer,
+                            MessageExtra::TobDeliver(TobDeliverExtra {
+                                seq,
+                                message: message.clone(),
+                            }),
+                        );
+                    }
+                    node.total_order.receive(seq, message)
+                }
+                ClaimOutcome::Retry(message) => {
+                    tob_submit(&mut node, &message);
+                    Vec::new()
+                }
+                ClaimOutcome::Resend(seq, message) => {
+                    tob_claim(&mut node, &message, Some(seq));
+                    Vec::new()
+                }
+                ClaimOutcome::Forward(leader, message) => {
+                    node.send_reliable(
+                        &leader,
+                        MessageExtra::TobForward(BroadcastRequestExtra { message }),
+                    );
+                    Vec::new()
+                }
+                ClaimOutcome::Unknown => Vec::new(),
+                },
+            }
+        };
+        deliver_in_order(node, ready);
+        None
+    }
+}
+
+pub struct TobDeliverHandler;
+
+impl MessageHandler for TobDeliverHandler {
+    fn can_handle(&self, req: &Message) -> bool {
+        matches!(req.body.extra, MessageExtra::TobDeliver(_))
+    }
+
+    fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
+        if let MessageExtra::TobDeliver(payload) = &req.body.extra {
+            let ready = {
+                let mut node = node.borrow_mut();
+                let node = &mut *node;
+                node.total_order.init(&node.id, &node.node_ids);
+                node.total_order.receive(payload.seq, payload.message.clone())
+            };
+            deliver_in_order(node, ready);
+            Some(MessageExtra::TobDeliverOk)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct TobLeaderHandler;
+
+impl MessageHandler for TobLeaderHandler {
+    fn can_handle(&self, req: &Message) -> bool {
+        matches!(req.body.extra, MessageExtra::TobLeader(_))
+    }
+
+    fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
+        if let MessageExtra::TobLeader(payload) = &req.body.extra {
+            let mut node = node.borrow_mut();
+            let node = &mut *node;
+            node.total_order.init(&node.id, &node.node_ids);
+            if let Some(values) = node.total_order.new_leader(payload.epoch, &payload.leader) {
+                eprintln!("new sequencer {} at epoch {}", payload.leader, payload.epoch);
+                for value in values {
+                    tob_submit(node, &value);
+                }
+            }
+            Some(MessageExtra::TobLeaderOk)
+        } else {
+            None
+        }
+    }
+}
+
+/// Stops retrying a message sent with `send_reliable` once it is acked.
+pub struct AckHandler;
+
+impl MessageHandler for AckHandler {
+    fn can_handle(&self, req: &Message) -> bool {
+        matches!(
+            req.body.extra,
+            MessageExtra::TobForwardOk
+                | MessageExtra::TobDeliverOk
+                | MessageExtra::TobLeaderOk
+                | MessageExtra::CausalBroadcastOk
+        )
+    }
+
+    fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
+        if let Some(in_reply_to) = &req.body.in_reply_to {
+            node.borrow().ack(*in_reply_to);
         }
         None
     }
 }
 
+/// Returns the values in the order they were delivered.
+pub struct ReadLogHandler;
+
+impl MessageHandler for ReadLogHandler {
+    fn can_handle(&self, req: &Message) -> bool {
+        matches!(req.body.extra, MessageExtra::ReadLog)
+    }
+
+    fn handle(&self, node: &Rc<RefCell<Node>>, _req: &Message) -> Option<MessageExtra> {
+        Some(MessageExtra::ReadLogOk(ReadLogResponseExtra {
+            log: node.borrow().delivery_log.clone(),
+        }))
+    }
+}
+
+/// Broadcast handler for causal mode, replaces `BroadcastHandler`.
+pub struct CausalBroadcastHandler;
+
+impl MessageHandler for CausalBroadcastHandler {
+    fn can_handle(&self, req: &Message) -> bool {
+        matches!(req.body.extra, MessageExtra::Broadcast(_))
+    }
+
+    fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
+        if let MessageExtra::Broadcast(payload) = &req.body.extra {
+            let mut node = node.borrow_mut();
+            if node.mark_seen(&payload.message) {
+                let id = node.id.clone();
+                let clock = node.causal.stamp(&id);
+                node.delivery_log.push(payload.message.clone());
+                for peer in node.node_ids.iter().filter(|n| **n != id) {
+                    node.send_reliable(
+                        peer,
+                        MessageExtra::CausalBroadcast(CausalBroadcastExtra {
+                            origin: id.clone(),
+                            clock: clock.clone(),
+                            message: payload.message.clone(),
+                        }),
+                    );
+                }
+            }
+            Some(MessageExtra::BroadcastOk)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct CausalMessageHandler;
+
+impl MessageHandler for CausalMessageHandler {
+    fn can_handle(&self, req: &Message) -> bool {
+        matches!(req.body.extra, MessageExtra::CausalBroadcast(_))
+    }
+
+    fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
+        if let MessageExtra::CausalBroadcast(payload) = &req.body.extra {
+            let ready = node.borrow_mut().causal.receive(
+                &payload.origin,
+                payload.clock.clone(),
+                payload.message.clone(),
+            );
+            deliver_in_order(node, ready);
+            Some(MessageExtra::CausalBroadcastOk)
+        } else {
+            None
+        }
+    }
+}
+
 #[cfg(not(feature = "lin_kv"))]
 pub struct ReadHandler;
 
 #[cfg(not(feature = "lin_kv"))]
 impl MessageHandler for ReadHandler {
     fn can_handle(&self, req: &Message) -> bool {
-        matches!(req.body.extra, MessageExtra::Read)
+        matches!(req.body.extra, MessageExtra::Read(ReadRequestExtra { key: None }))
     }
 
     fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
-        if let MessageExtra::Read = &req.body.extra {
-            Some(MessageExtra::ReadOk(ReadResponseExtra {
+        if let MessageExtra::Read(_) = &req.body.extra {
+            Some(MessageExtra::ReadOk(ReadOkExtra::Messages(ReadResponseExtra {
                 messages: node.borrow().messages_seen.clone(),
-            }))
+            })))
         } else {
             None
         }
@@ -221,7 +741,7 @@ pub struct ReadOkHandler;
 #[cfg(not(feature = "lin_kv"))]
 impl MessageHandler for ReadOkHandler {
     fn can_handle(&self, req: &Message) -> bool {
-        matches!(req.body.extra, MessageExtra::ReadOk(_))
+        matches!(req.body.extra, MessageExtra::ReadOk(ReadOkExtra::Messages(_)))
     }
 
     fn handle(&self, _node: &Rc<RefCell<Node>>, _req: &Message) -> Option<MessageExtra> {
@@ -229,63 +749,240 @@ impl MessageHandler for ReadOkHandler {
     }
 }
 
-pub struct TxnHandler;
+pub struct TxnHandler {
+    engine: RefCell<Box<dyn TxnEngine>>,
+}
+
+impl TxnHandler {
+    pub fn new(engine: Box<dyn TxnEngine>) -> Self {
+        Self {
+            engine: RefCell::new(engine),
+        }
+    }
+}
 
 impl MessageHandler for TxnHandler {
     fn can_handle(&self, req: &Message) -> bool {
-        matches!(req.body.extra, MessageExtra::Txn(_))
+        matches!(
+            req.body.extra,
+            MessageExtra::Txn(_) | MessageExtra::TxnForward(_) | MessageExtra::ReadAt(_)
+        )
     }
 
-    #[cfg(feature = "lin_kv")]
+    /// Txns that queued up in the inbox while the previous commit was in
+    /// flight, or that come in within `txn_batch_window`, are run in the
+    /// same batch, see `run_batch`. They are answered here, `req` by the
+    /// caller.
     fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
-        if let MessageExtra::Txn(payload) = &req.body.extra {
-            let mut transactor = Transactor::new(node);
-            let results = transactor.transact(&payload.txn);
-
-            match results {
-                Ok(txn) => {
-                    let mytxn = TxnResponseExtra { txn };
-                    Some(MessageExtra::TxnOk(mytxn))
+        let payload = match &req.body.extra {
+            MessageExtra::Txn(payload) => {
+                #[cfg(feature = "lin_kv")]
+                if forward_to_leader(node, req, payload) {
+                    return None;
                 }
-                Err(e) => Some(MessageExtra::Error(e)),
+                payload
+            }
+            MessageExtra::TxnForward(payload) => {
+                if stale_forward(&mut node.borrow_mut(), req) {
+                    eprintln!("drop txn forward {:?}, its sender runs it", req.body.msg_id);
+                    return None;
+                }
+                // serving forwards keeps the lease
+                #[cfg(feature = "lin_kv")]
+                crate::txn_leader::current_leader(node);
+                payload
+            }
+            MessageExtra::ReadAt(payload) => {
+                let res = self
+                    .engine
+                    .borrow_mut()
+                    .read_at(node, payload.version, &payload.keys);
+                return Some(match res {
+                    Ok(values) => MessageExtra::ReadAtOk(ReadAtResponseExtra {
+                        version: payload.version,
+                        values,
+                    }),
+                    Err(e) => MessageExtra::Error(e),
+                });
+            }
+            _ => return None,
+        };
+
+        let mut replies = self.run_batch(node, req, payload).into_iter();
+        let (_, own) = replies.next()?;
+        for (msg, reply) in replies {
+            let node = node.borrow();
+            if let Some(reply) = reply {
+                node.send(node.reply_to(&msg, reply));
             }
-        } else {
-            None
         }
+        own
     }
+}
 
-    #[cfg(not(feature = "lin_kv"))]
-    fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
-        if let MessageExtra::Txn(payload) = &req.body.extra {
-            let mut results = Vec::new();
-            for op in &payload.txn {
-                if op.0 == "r" {
-                    let node = node.borrow();
-                    let value = node.kv_store.get(&op.1);
-                    results.push(Query(
-                        "r".to_string(),
-                        op.1,
-                        QueryValue::Read(value.cloned()),
-                    ));
-                }
+impl TxnHandler {
+    /// Runs `req` together with the txns taken by `take_queued_txns`, see
+    /// `TxnEngine::transact_batch`, and pairs every request with its reply,
+    /// `req` first. A txn that conflicted and may be retried gets no reply,
+    /// it is parked in `Node::txn_retries` instead.
+    fn run_batch(
+        &self,
+        node: &Rc<RefCell<Node>>,
+        req: &Message,
+        payload: &TxnRequestExtra,
+    ) -> Vec<(Message, Option<MessageExtra>)> {
+        let batched = take_queued_txns(&mut node.borrow_mut());
+        let mut txns = vec![payload.txn.clone()];
+        txns.extend(batched.iter().map(|msg| match &msg.body.extra {
+            MessageExtra::Txn(payload) | MessageExtra::TxnForward(payload) => payload.txn.clone(),
+            _ => unreachable!(),
+        }));
+        if !batched.is_empty() {
+            eprintln!("group commit of {} txns", txns.len());
+        }
 
-                if op.0 == "append" {
-                    node.borrow_mut()
-                        .kv_store
-                        .entry(op.1)
-                        .or_default()
-                        .push(match op.2 {
-                            QueryValue::Append(v) => v,
-                            QueryValue::Read(_) => panic!("wrong value for 'append' operation"),
-                        });
-                    results.push(Query("append".to_string(), op.1, op.2.clone()));
-                }
-            }
+        let results = self.engine.borrow_mut().transact_batch(node, &txns);
+        let engine = self.engine.borrow();
+        let mut node = node.borrow_mut();
+        let now = Instant::now();
+        std::iter::once(req.clone())
+            .chain(batched)
+            .zip(results)
+            .map(|(msg, res)| {
+                let attempt = node.txn_retries.attempt(&msg);
+                let res = match res {
+                    Err(err) if err.code == TXN_CONFLICT => match engine.retry_after(attempt) {
+                        Some(delay) => {
+                            eprintln!(
+                                "txn {:?} of {} conflicted, retry {} in {delay:?}",
+                                msg.body.msg_id,
+                                msg.src,
+                                attempt + 1
+                            );
+                            node.txn_retries.schedule(msg.clone(), now + delay);
+                            return (msg, None);
+                        }
+                        None if attempt > 0 => Err(ErrorExtra {
+                            code: TXN_CONFLICT,
+                            text: format!("{} after {} attempts", err.text, attempt + 1),
+                        }),
+                        None => Err(err),
+                    },
+                    res => res,
+                };
+                node.txn_retries.done(&msg);
+                done_forward(&mut node, &msg);
+                let reply = txn_reply(&msg, res);
+                (msg, Some(reply))
+            })
+            .collect()
+    }
+}
 
-            let mytxn = TxnResponseExtra { txn: results };
-            Some(MessageExtra::TxnOk(mytxn))
-        } else {
-            None
+/// `txn_ok` or an error for a client, `txn_forward_ok` for a forwarding node.
+fn txn_reply(req: &Message, res: Result<Vec<MicroOp>, ErrorExtra>) -> MessageExtra {
+    match (&req.body.extra, res) {
+        (MessageExtra::TxnForward(_), res) => {
+            let (txn, error) = match res {
+                Ok(txn) => (Some(txn), None),
+                Err(e) => (None, Some(e)),
+            };
+            MessageExtra::TxnForwardOk(TxnForwardOkExtra { txn, error })
+        }
+        (_, Ok(txn)) => MessageExtra::TxnOk(TxnResponseExtra { txn }),
+        (_, Err(e)) => MessageExtra::Error(e),
+    }
+}
+
+/// Forwards a client txn to the txn leader, true if it was forwarded. The
+/// reply is relayed by `TxnForwardOkHandler`.
+#[cfg(feature = "lin_kv")]
+fn forward_to_leader(node: &Rc<RefCell<Node>>, req: &Message, payload: &TxnRequestExtra) -> bool {
+    if !node.borrow().txn_leader.enabled() || node.borrow_mut().txn_leader.taken_back(req) {
+        return false;
+    }
+    let leader = crate::txn_leader::current_leader(node);
+    let mut node = node.borrow_mut();
+    if leader == node.id {
+        return false;
+    }
+    let msg_id = node.next_msg_id();
+    eprintln!("forward txn {:?} to {leader}", req.body.msg_id);
+    let ttl = node.txn_leader.forward(msg_id, req, Instant::now());
+    node.send(Message {
+        src: node.id.clone(),
+        dest: leader,
+        body: MessageBody {
+            msg_id: Some(msg_id),
+            in_reply_to: None,
+            extra: MessageExtra::TxnForward(TxnRequestExtra {
+                txn: payload.txn.clone(),
+                ttl: Some(ttl),
+            }),
+        },
+    });
+    true
+}
+
+/// Whether `req` is a forwarded txn past its ttl, which the forwarding
+/// node runs itself.
+#[cfg(feature = "lin_kv")]
+fn stale_forward(node: &mut Node, req: &Message) -> bool {
+    node.txn_leader.stale(req, Instant::now())
+}
+
+#[cfg(not(feature = "lin_kv"))]
+fn stale_forward(_node: &mut Node, _req: &Message) -> bool {
+    false
+}
+
+/// Forgets the ttl of a forwarded txn once it is answered.
+#[cfg(feature = "lin_kv")]
+fn done_forward(node: &mut Node, req: &Message) {
+    node.txn_leader.done(req)
+}
+
+#[cfg(not(feature = "lin_kv"))]
+fn done_forward(_node: &mut Node, _req: &Message) {}
+
+/// Client txns are run here unless they have to be forwarded.
+#[cfg(feature = "lin_kv")]
+fn runs_client_txns(node: &Node) -> bool {
+    !node.txn_leader.enabled() || node.txn_leader.is_leader(&node.id)
+}
+
+#[cfg(not(feature = "lin_kv"))]
+fn runs_client_txns(_node: &Node) -> bool {
+    true
+}
+
+/// Removes up to `txn_batch - 1` txn requests from the inbox, keeping the
+/// order of everything else. Reads stdin into the inbox for up to
+/// `txn_batch_window` while there is room.
+fn take_queued_txns(node: &mut Node) -> Vec<Message> {
+    let deadline = Instant::now() + node.config.txn_batch_window;
+    let mut room = node.config.txn_batch - 1;
+    let client_txns = runs_client_txns(node);
+    let mut taken = Vec::new();
+    loop {
+        for msg in std::mem::take(&mut node.inbox) {
+            let batchable = match msg.body.extra {
+                MessageExtra::Txn(_) => client_txns,
+                MessageExtra::TxnForward(_) => true,
+                _ => false,
+            };
+            if room == 0 || !batchable {
+                node.inbox.push_back(msg);
+            } else if stale_forward(node, &msg) {
+                eprintln!("drop txn forward {:?}, its sender runs it", msg.body.msg_id);
+            } else {
+                taken.push(msg);
+                room -= 1;
+            }
+        }
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if room == 0 || timeout.is_zero() || !node.read_into_inbox(timeout) {
+            return taken;
         }
     }
 }
@@ -301,3 +998,226 @@ impl MessageHandler for TxnOkHandler {
         None
     }
 }
+
+/// Relays the leader's answer to a forwarded txn back to the client.
+#[cfg(feature = "lin_kv")]
+pub struct TxnForwardOkHandler;
+
+#[cfg(feature = "lin_kv")]
+impl MessageHandler for TxnForwardOkHandler {
+    fn can_handle(&self, req: &Message) -> bool {
+        matches!(req.body.extra, MessageExtra::TxnForwardOk(_))
+    }
+
+    fn handle(&self, node: &Rc<RefCell<Node>>, req: &Message) -> Option<MessageExtra> {
+        if let MessageExtra::TxnForwardOk(payload) = &req.body.extra {
+            let mut node = node.borrow_mut();
+            let (client_req, _) = node.txn_leader.forwarded.remove(&req.body.in_reply_to?)?;
+            let extra = match (&payload.txn, &payload.error) {
+                (_, Some(err)) => MessageExtra::Error(err.clone()),
+                (Some(txn), None) => MessageExtra::TxnOk(TxnResponseExtra { txn: txn.clone() }),
+                (None, None) => {
+                    // the leader may or may not have committed it
+                    eprintln!("txn_forward_ok without txn or error: {req:?}");
+                    MessageExtra::Error(ErrorExtra {
+                        code: CRASH,
+                        text: "the leader sent no result".to_string(),
+                    })
+                }
+            };
+            node.send(node.reply_to(&client_req, extra));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::txn_engine::{RetryEngine, TxnEngine};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::sync::mpsc::{self, Sender};
+
+    /// Commits every batch at once, like one root CAS, and echoes each txn.
+    /// A txn on a key in `conflicts` aborts that many times.
+    #[derive(Default)]
+    struct Stub {
+        batches: Rc<RefCell<Vec<usize>>>,
+        conflicts: HashMap<u64, u32>,
+    }
+
+    impl TxnEngine for Stub {
+        fn transact(
+            &mut self,
+            _node: &Rc<RefCell<Node>>,
+            txn: &[MicroOp],
+        ) -> Result<Vec<MicroOp>, ErrorExtra> {
+            Ok(txn.to_vec())
+        }
+
+        fn transact_batch(
+            &mut self,
+            _node: &Rc<RefCell<Node>>,
+            txns: &[Vec<MicroOp>],
+        ) -> Vec<Result<Vec<MicroOp>, ErrorExtra>> {
+            self.batches.borrow_mut().push(txns.len());
+            txns.iter()
+                .map(|txn| {
+                    let key = serde_json::to_value(txn).unwrap()[0][1].as_u64().unwrap();
+                    match self.conflicts.get_mut(&key) {
+                        Some(left) if *left > 0 => {
+                            *left -= 1;
+                            Err(ErrorExtra {
+                                code: TXN_CONFLICT,
+                                text: format!("key {key} conflicted"),
+                            })
+                        }
+                        _ => Ok(txn.clone()),
+                    }
+                })
+                .collect()
+        }
+    }
+
+    fn node(window: Duration) -> (Rc<RefCell<Node>>, Sender<String>) {
+        let mut config = Config::from_env();
+        config.txn_batch = 8;
+        config.txn_batch_window = window;
+        let (lines, stdin) = mpsc::channel();
+        let mut node = Node::with_stdin(config, stdin);
+        node.id = "n1".to_string();
+        (Rc::new(RefCell::new(node)), lines)
+    }
+
+    fn txn(client: &str, key: u64) -> Message {
+        serde_json::from_value(json!({
+            "src": client, "dest": "n1",
+            "body": {"type": "txn", "msg_id": 1, "txn": [["append", key, 1]]}
+        }))
+        .unwrap()
+    }
+
+    fn run(
+        node: &Rc<RefCell<Node>>,
+        req: &Message,
+    ) -> (Vec<usize>, Vec<(Message, Option<MessageExtra>)>) {
+        let batches = Rc::new(RefCell::new(Vec::new()));
+        let handler = TxnHandler::new(Box::new(Stub {
+            batches: batches.clone(),
+            ..Default::default()
+        }));
+        let replies = run_with(&handler, node, req);
+        let batches = batches.borrow().clone();
+        (batches, replies)
+    }
+
+    fn run_with(
+        handler: &TxnHandler,
+        node: &Rc<RefCell<Node>>,
+        req: &Message,
+    ) -> Vec<(Message, Option<MessageExtra>)> {
+        let MessageExtra::Txn(payload) = &req.body.extra else {
+            unreachable!()
+        };
+        handler.run_batch(node, req, payload)
+    }
+
+    fn replied_keys(
+        replies: &[(Message, Option<MessageExtra>)],
+    ) -> Vec<(String, serde_json::Value)> {
+        replies
+            .iter()
+            .map(|(msg, reply)| match reply {
+                Some(MessageExtra::TxnOk(ok)) => {
+                    let key = serde_json::to_value(&ok.txn).unwrap()[0][1].clone();
+                    (msg.src.clone(), key)
+                }
+                other => panic!("unexpected reply {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn queued_txns_share_one_commit_and_get_their_own_replies() {
+        let (node, _lines) = node(Duration::ZERO);
+        node.borrow_mut().inbox.extend([txn("c2", 2), txn("c3", 3)]);
+        let (batches, replies) = run(&node, &txn("c1", 1));
+        assert_eq!(batches, vec![3]);
+        assert_eq!(
+            replied_keys(&replies),
+            vec![("c1".into(), json!(1)), ("c2".into(), json!(2)), ("c3".into(), json!(3))]
+        );
+    }
+
+    #[test]
+    fn batch_window_drains_stdin() {
+        let (node, lines) = node(Duration::from_millis(50));
+        lines.send(serde_json::to_string(&txn("c2", 2)).unwrap()).unwrap();
+        let echo = r#"{"src":"c3","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hi"}}"#;
+        lines.send(echo.to_string()).unwrap();
+        lines.send(serde_json::to_string(&txn("c4", 4)).unwrap()).unwrap();
+        let (batches, replies) = run(&node, &txn("c1", 1));
+        assert_eq!(batches, vec![3]);
+        assert_eq!(
+            replied_keys(&replies),
+            vec![("c1".into(), json!(1)), ("c2".into(), json!(2)), ("c4".into(), json!(4))]
+        );
+        // everything else stays queued
+        assert_eq!(node.borrow().inbox.len(), 1);
+    }
+
+    #[test]
+    fn batch_without_window_takes_only_queued_txns() {
+        let (node, lines) = node(Duration::ZERO);
+        lines.send(serde_json::to_string(&txn("c2", 2)).unwrap()).unwrap();
+        let (batches, _) = run(&node, &txn("c1", 1));
+        assert_eq!(batches, vec![1]);
+        assert!(node.borrow().read_line(Duration::ZERO).is_ok());
+    }
+
+    #[test]
+    fn only_conflicted_txns_are_retried_up_to_the_limit() {
+        let (node, _lines) = node(Duration::ZERO);
+        let config = Config {
+            txn_retries: 2,
+            txn_backoff: Duration::ZERO,
+            ..node.borrow().config.clone()
+        };
+        let batches = Rc::new(RefCell::new(Vec::new()));
+        let stub = Stub {
+            batches: batches.clone(),
+            conflicts: HashMap::from([(2, 10), (3, 1)]),
+        };
+        let handler = TxnHandler::new(Box::new(RetryEngine::new(Box::new(stub), &config)));
+
+        node.borrow_mut().inbox.extend([txn("c2", 2), txn("c3", 3)]);
+        let replies = run_with(&handler, &node, &txn("c1", 1));
+        assert!(matches!(replies[0].1, Some(MessageExtra::TxnOk(_))));
+        assert!(replies[1].1.is_none() && replies[2].1.is_none());
+        // nothing is re-run before the tick
+        assert!(node.borrow().inbox.is_empty());
+
+        on_tick(&node);
+        let retry = node.borrow_mut().inbox.pop_front().unwrap();
+        let replies = run_with(&handler, &node, &retry);
+        assert_eq!(replies[0].0.src, "c2");
+        assert!(replies[0].1.is_none());
+        assert_eq!(replied_keys(&replies[1..]), vec![("c3".into(), json!(3))]);
+
+        on_tick(&node);
+        let retry = node.borrow_mut().inbox.pop_front().unwrap();
+        let replies = run_with(&handler, &node, &retry);
+        match &replies[..] {
+            [(_, Some(MessageExtra::Error(err)))] => {
+                assert_eq!(err.code, TXN_CONFLICT);
+                assert_eq!(err.text, "key 2 conflicted after 3 attempts");
+            }
+            other => panic!("unexpected replies {other:?}"),
+        }
+        assert_eq!(*batches.borrow(), vec![3, 2, 1]);
+        on_tick(&node);
+        assert!(node.borrow().inbox.is_empty());
+    }
+}
@@ -1,6 +1,10 @@
+use crate::broadcast_set::{BroadcastSet, SetDigest};
+use crate::vector_clock::VectorClock;
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
-use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
 
 /// protocol specification from https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -41,12 +45,33 @@ pub enum MessageExtra {
     TopologyOk,
     Broadcast(BroadcastRequestExtra),
     BroadcastOk,
+    #[serde(rename = "ihave")]
+    IHave(IHaveExtra),
+    Graft(GraftExtra),
+    Prune,
+    Gossip(GossipExtra),
+    GossipOk(GossipExtra),
+    TobForward(BroadcastRequestExtra),
+    TobForwardOk,
+    TobDeliver(TobDeliverExtra),
+    TobDeliverOk,
+    TobLeader(TobLeaderExtra),
+    TobLeaderOk,
+    ReadLog,
+    ReadLogOk(ReadLogResponseExtra),
+    CausalBroadcast(CausalBroadcastExtra),
+    CausalBroadcastOk,
+    // without `lin_kv` these are also the lin-kv read, see `kv_read`
     #[cfg(not(feature = "lin_kv"))]
-    Read,
+    Read(ReadRequestExtra),
     #[cfg(not(feature = "lin_kv"))]
-    ReadOk(ReadResponseExtra),
+    ReadOk(ReadOkExtra),
     Txn(TxnRequestExtra),
     TxnOk(TxnResponseExtra),
+    TxnForward(TxnRequestExtra),
+    TxnForwardOk(TxnForwardOkExtra),
+    ReadAt(ReadAtRequestExtra),
+    ReadAtOk(ReadAtResponseExtra),
     #[cfg(feature = "lin_kv")]
     #[serde(rename = "read")]
     KvRead(KvReadExtra),
@@ -69,6 +94,30 @@ pub struct ErrorExtra {
     pub text: String,
 }
 
+/// The request is not supported, e.g. a `read_at` on an engine that keeps
+/// no history.
+pub const NOT_SUPPORTED: u64 = 10;
+
+/// The request is not a valid message, e.g. a txn with an unknown op.
+pub const MALFORMED_REQUEST: u64 = 12;
+
+/// The outcome of the request is unknown, maelstrom's indefinite `crash`.
+pub const CRASH: u64 = 13;
+
+/// The key does not exist: lin-kv's reply to a read of a missing key, and
+/// ours to a `read_at` of a version that is not retained or not committed
+/// yet.
+pub const KEY_DOES_NOT_EXIST: u64 = 20;
+
+/// A precondition does not hold: lin-kv's reply to a CAS whose `from` did
+/// not match, and ours to an op that does not fit the value of its key,
+/// e.g. an append to a written number.
+pub const PRECONDITION_FAILED: u64 = 22;
+
+/// The txn aborted and nothing of it is visible, maelstrom's definite
+/// `txn-conflict`, e.g. after losing the race for the root.
+pub const TXN_CONFLICT: u64 = 30;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct KvReadExtra {
     pub key: serde_json::Value,
@@ -123,34 +172,556 @@ pub struct TopologyRequestExtra {
     pub topology: HashMap<String, Vec<String>>,
 }
 
-pub type BroadcastValue = u64;
+/// JSON encoding with object keys sorted, equal for values that differ only
+/// in the order of object keys.
+pub fn canonical_json(value: &serde_json::Value) -> String {
+    fn write(v: &serde_json::Value, out: &mut String) {
+        match v {
+            serde_json::Value::Array(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    write(item, out);
+                }
+                out.push(']');
+            }
+            serde_json::Value::Object(map) => {
+                let mut keys: Vec<&String> = map.keys().collect();
+                keys.sort();
+                out.push('{');
+                for (i, k) in keys.into_iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    out.push_str(&serde_json::Value::from(k.as_str()).to_string());
+                    out.push(':');
+                    write(&map[k], out);
+                }
+                out.push('}');
+            }
+            scalar => out.push_str(&scalar.to_string()),
+        }
+    }
+
+    let mut out = String::new();
+    write(value, &mut out);
+    out
+}
+
+/// A JSON value that compares and hashes by its canonical encoding, which
+/// is computed once when the value is created. Unsigned integers compare
+/// numerically, sort before everything else and need no encoding.
+#[derive(Debug, Clone)]
+pub struct CanonicalJson {
+    value: serde_json::Value,
+    // `None` for unsigned integers
+    canonical: Option<Box<str>>,
+}
+
+impl CanonicalJson {
+    pub fn value(&self) -> &serde_json::Value {
+        &self.value
+    }
+
+    pub fn into_value(self) -> serde_json::Value {
+        self.value
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        self.value.as_u64()
+    }
+
+    /// JSON encoding with object keys sorted, see [`canonical_json`].
+    pub fn canonical(&self) -> String {
+        match &self.canonical {
+            Some(canonical) => canonical.to_string(),
+            None => self.value.to_string(),
+        }
+    }
+}
+
+impl From<serde_json::Value> for CanonicalJson {
+    fn from(value: serde_json::Value) -> Self {
+        let canonical = match value.as_u64() {
+            Some(_) => None,
+            None => Some(canonical_json(&value).into()),
+        };
+        CanonicalJson { value, canonical }
+    }
+}
+
+impl PartialEq for CanonicalJson {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for CanonicalJson {}
+
+impl PartialOrd for CanonicalJson {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CanonicalJson {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.as_u64(), other.as_u64()) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => self.canonical.cmp(&other.canonical),
+        }
+    }
+}
+
+impl Hash for CanonicalJson {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self.as_u64() {
+            Some(n) => n.hash(state),
+            None => self.canonical.hash(state),
+        }
+    }
+}
+
+impl Serialize for CanonicalJson {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.value.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CanonicalJson {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        serde_json::Value::deserialize(deserializer).map(CanonicalJson::from)
+    }
+}
+
+/// A broadcast payload, which may be any JSON value.
+///
+/// Two payloads are the same message iff their canonical encodings are
+/// equal, so that `{"a":1,"b":2}` and `{"b":2,"a":1}` are deduplicated as
+/// one message. Maelstrom's integer workload is just the special case of a
+/// JSON number.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct BroadcastValue(pub CanonicalJson);
+
+impl BroadcastValue {
+    pub fn new(value: serde_json::Value) -> Self {
+        BroadcastValue(value.into())
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        self.0.as_u64()
+    }
+}
+
+impl From<u64> for BroadcastValue {
+    fn from(v: u64) -> Self {
+        BroadcastValue::new(v.into())
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BroadcastRequestExtra {
     pub message: BroadcastValue,
 }
 
+/// Lazy announcement of messages in plumtree mode.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IHaveExtra {
+    pub ids: Vec<u64>,
+}
+
+/// Asks a peer to resend messages and to add us to its eager peers.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GraftExtra {
+    pub ids: Vec<u64>,
+}
+
+/// Anti-entropy, see `broadcast_set::AntiEntropy`. A `gossip` carries what
+/// the sender has seen, its `gossip_ok` what the sender is missing.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GossipExtra {
+    pub seen: SetDigest,
+}
+
+/// A slot of the total-order log decided by the sequencer.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TobDeliverExtra {
+    pub seq: u64,
+    pub message: BroadcastValue,
+}
+
+/// Announces a new sequencer for total-order broadcast.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TobLeaderExtra {
+    pub epoch: u64,
+    pub leader: String,
+}
+
+/// A broadcast stamped with the vector clock of the node it originated on.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CausalBroadcastExtra {
+    pub origin: String,
+    pub clock: VectorClock,
+    pub message: BroadcastValue,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReadLogResponseExtra {
+    pub log: Vec<BroadcastValue>,
+}
+
+/// A client's read has no key.
+#[cfg(not(feature = "lin_kv"))]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ReadRequestExtra {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<serde_json::Value>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ReadResponseExtra {
-    pub messages: HashSet<BroadcastValue>,
+    pub messages: BroadcastSet,
+}
+
+#[cfg(not(feature = "lin_kv"))]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ReadOkExtra {
+    Messages(ReadResponseExtra),
+    Kv(KvReadOkExtra),
+}
+
+impl MessageExtra {
+    /// A lin-kv read of `key`. The broadcast binary only reads lin-kv in
+    /// total-order mode, which runs without `lin_kv`, so it shares the
+    /// `read` type with the broadcast workload.
+    pub fn kv_read(key: serde_json::Value) -> MessageExtra {
+        #[cfg(feature = "lin_kv")]
+        return MessageExtra::KvRead(KvReadExtra { key });
+        #[cfg(not(feature = "lin_kv"))]
+        MessageExtra::Read(ReadRequestExtra { key: Some(key) })
+    }
+
+    /// The value of a lin-kv `read_ok`.
+    pub fn kv_read_value(&self) -> Option<&serde_json::Value> {
+        match self {
+            #[cfg(feature = "lin_kv")]
+            MessageExtra::KvReadOk(read) => Some(&read.value),
+            #[cfg(not(feature = "lin_kv"))]
+            MessageExtra::ReadOk(ReadOkExtra::Kv(read)) => Some(&read.value),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TxnRequestExtra {
-    pub txn: Vec<Query>,
+    pub txn: Vec<MicroOp>,
+    /// ms after it arrived that the leader must not start a forwarded txn
+    /// any more, see `TxnLeader::forward`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TxnResponseExtra {
-    pub txn: Vec<Query>,
+    pub txn: Vec<MicroOp>,
 }
 
+/// Reads `keys` as of the commit with sequence number `version`.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Query(pub String, pub usize, pub QueryValue);
+pub struct ReadAtRequestExtra {
+    pub version: u64,
+    pub keys: Vec<TxnKey>,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(untagged)]
-pub enum QueryValue {
-    Read(Option<Vec<usize>>),
-    Append(usize),
+pub struct ReadAtResponseExtra {
+    pub version: u64,
+    /// `[key, value]` pairs, null for a key that did not exist
+    pub values: KeyMap<serde_json::Value>,
+}
+
+/// The leader's answer to a forwarded txn, either the completed ops or
+/// the error for the client.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxnForwardOkExtra {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub txn: Option<Vec<MicroOp>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorExtra>,
+}
+
+/// One operation of a txn, in Maelstrom's `[op, key, value]` encoding:
+///
+/// ```text
+/// ["r", 1, null]       ["r", 1, [3, 4]]     read, the value is filled in the reply
+/// ["append", 1, 4]                          append to the list at key 1
+/// ["w", 1, 4]                               overwrite key 1
+/// ```
+///
+/// Keys, elements and written values may be any JSON value, but appending
+/// or writing `null` is rejected, as is any other op.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MicroOp {
+    Read {
+        key: TxnKey,
+        value: Option<serde_json::Value>,
+    },
+    Append {
+        key: TxnKey,
+        value: serde_json::Value,
+    },
+    Write {
+        key: TxnKey,
+        value: serde_json::Value,
+    },
+}
+
+impl MicroOp {
+    pub fn key(&self) -> &TxnKey {
+        match self {
+            MicroOp::Read { key, .. } | MicroOp::Append { key, .. } | MicroOp::Write { key, .. } => {
+                key
+            }
+        }
+    }
+
+    /// True if the op changes its key.
+    pub fn is_write(&self) -> bool {
+        !matches!(self, MicroOp::Read { .. })
+    }
+}
+
+impl Serialize for MicroOp {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let null = serde_json::Value::Null;
+        match self {
+            MicroOp::Read { key, value } => ("r", key, value.as_ref().unwrap_or(&null)),
+            MicroOp::Append { key, value } => ("append", key, value),
+            MicroOp::Write { key, value } => ("w", key, value),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for MicroOp {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let (op, key, value) = <(String, TxnKey, serde_json::Value)>::deserialize(deserializer)?;
+        match op.as_str() {
+            "r" => Ok(MicroOp::Read {
+                key,
+                value: Some(value).filter(|v| !v.is_null()),
+            }),
+            "append" | "w" if value.is_null() => {
+                Err(D::Error::custom(format!("'{op}' on key {key} needs a value")))
+            }
+            "append" => Ok(MicroOp::Append { key, value }),
+            "w" => Ok(MicroOp::Write { key, value }),
+            _ => Err(D::Error::custom(format!("unknown txn op '{op}'"))),
+        }
+    }
+}
+
+/// A txn key, which may be any JSON value. Like [`BroadcastValue`], keys
+/// that differ only in the order of object keys are the same key.
+///
+/// Integer keys sort numerically and before all other keys, which sort by
+/// their canonical encoding.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+pub struct TxnKey(pub CanonicalJson);
+
+impl TxnKey {
+    pub fn new(value: serde_json::Value) -> Self {
+        TxnKey(value.into())
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        self.0.as_u64()
+    }
+
+    /// Where the key falls in the partitioned key space, see
+    /// `partitioning.rs`. An integer key is its own position, any other
+    /// key is hashed.
+    pub fn position(&self) -> usize {
+        match &self.0.canonical {
+            None => self.0.value.as_u64().unwrap_or_default() as usize,
+            Some(canonical) => {
+                let mut hasher = DefaultHasher::new();
+                // hashes like the `String` it was built from
+                canonical.as_ref().hash(&mut hasher);
+                hasher.finish() as usize
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for TxnKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.value.fmt(f)
+    }
+}
+
+impl From<u64> for TxnKey {
+    fn from(v: u64) -> Self {
+        TxnKey::new(v.into())
+    }
+}
+
+/// A map with [`TxnKey`] keys. JSON object keys can only be strings, so it
+/// is stored as a list of `[key, value]` pairs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyMap<V>(pub HashMap<TxnKey, V>);
+
+impl<V> KeyMap<V> {
+    pub fn new() -> Self {
+        KeyMap(HashMap::new())
+    }
+}
+
+impl<V> Default for KeyMap<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> std::ops::Deref for KeyMap<V> {
+    type Target = HashMap<TxnKey, V>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<V> std::ops::DerefMut for KeyMap<V> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<V> FromIterator<(TxnKey, V)> for KeyMap<V> {
+    fn from_iter<I: IntoIterator<Item = (TxnKey, V)>>(iter: I) -> Self {
+        KeyMap(iter.into_iter().collect())
+    }
+}
+
+impl<V> Extend<(TxnKey, V)> for KeyMap<V> {
+    fn extend<I: IntoIterator<Item = (TxnKey, V)>>(&mut self, iter: I) {
+        self.0.extend(iter)
+    }
+}
+
+impl<V> IntoIterator for KeyMap<V> {
+    type Item = (TxnKey, V);
+    type IntoIter = std::collections::hash_map::IntoIter<TxnKey, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<V: Serialize> Serialize for KeyMap<V> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter())
+    }
+}
+
+impl<'de, V: Deserialize<'de>> Deserialize<'de> for KeyMap<V> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Vec::<(TxnKey, V)>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashSet;
+
+    #[test]
+    fn values_equal_up_to_key_order() {
+        let a = BroadcastValue::new(json!({"a": 1, "b": [2, {"c": 3, "d": 4}]}));
+        let b: BroadcastValue = serde_json::from_str(r#"{"b":[2,{"d":4,"c":3}],"a":1}"#).unwrap();
+        assert_eq!(a, b);
+        let set: HashSet<BroadcastValue> = [a, b].into_iter().collect();
+        assert_eq!(set.len(), 1);
+        assert_ne!(BroadcastValue::new(json!([1, 2])), BroadcastValue::new(json!([2, 1])));
+        assert_eq!(BroadcastValue::from(7), BroadcastValue::new(json!(7)));
+    }
+
+    #[test]
+    fn integer_keys_sort_first_and_numerically() {
+        let mut keys = [
+            TxnKey::new(json!("b")),
+            TxnKey::from(10),
+            TxnKey::new(json!({"user": "ann"})),
+            TxnKey::from(9),
+            TxnKey::new(json!("a")),
+        ];
+        keys.sort();
+        let sorted: Vec<String> = keys.iter().map(TxnKey::to_string).collect();
+        assert_eq!(sorted, ["9", "10", "\"a\"", "\"b\"", r#"{"user":"ann"}"#]);
+        assert_eq!(TxnKey::from(3).position(), 3);
+        let user = TxnKey::new(json!({"user": "ann", "id": 1}));
+        let same = TxnKey::new(json!({"id": 1, "user": "ann"}));
+        assert_eq!(user.position(), same.position());
+    }
+
+    #[test]
+    fn serializes_the_original_value() {
+        let key = TxnKey::new(json!({"b": 1, "a": 2}));
+        assert_eq!(serde_json::to_value(&key).unwrap(), json!({"b": 1, "a": 2}));
+        assert_eq!(key.0.canonical(), r#"{"a":2,"b":1}"#);
+    }
+
+    fn op(json: serde_json::Value) -> Result<MicroOp, serde_json::Error> {
+        serde_json::from_value(json)
+    }
+
+    #[test]
+    fn micro_ops_round_trip() {
+        let ops = [
+            json!(["r", 1, null]),
+            json!(["r", "k", [1, {"a": 2}]]),
+            json!(["append", 1, 3]),
+            json!(["append", {"user": "ann"}, {"at": 3}]),
+            json!(["w", 2, "x"]),
+            json!(["w", 2, false]),
+        ];
+        for json in ops {
+            let parsed = op(json.clone()).unwrap();
+            assert_eq!(serde_json::to_value(&parsed).unwrap(), json);
+        }
+        let read = op(json!(["r", 1, null])).unwrap();
+        assert_eq!(read, MicroOp::Read { key: 1.into(), value: None });
+        assert!(!read.is_write());
+        assert!(op(json!(["w", 1, 0])).unwrap().is_write());
+    }
+
+    #[test]
+    fn append_and_write_need_a_value() {
+        let err = op(json!(["append", 1, null])).unwrap_err();
+        assert!(err.to_string().contains("'append' on key 1 needs a value"));
+        let err = op(json!(["w", "k", null])).unwrap_err();
+        assert!(err.to_string().contains("'w' on key \"k\" needs a value"));
+    }
+
+    #[test]
+    fn unknown_ops_and_wrong_arity_are_rejected() {
+        let err = op(json!(["cas", 1, 2])).unwrap_err();
+        assert!(err.to_string().contains("unknown txn op 'cas'"));
+        assert!(op(json!(["r", 1])).is_err());
+        assert!(op(json!(["append", 1, 2, 3])).is_err());
+        assert!(op(json!({"op": "r", "key": 1})).is_err());
+    }
 }
@@ -1,38 +1,140 @@
+use crate::broadcast_set::{AntiEntropy, BroadcastSet};
+use crate::causal::CausalBroadcast;
+#[cfg(feature = "lin_kv")]
+use crate::chunk_list::Chunk;
+use crate::config::Config;
+use crate::idgen::IdGen;
+#[cfg(feature = "lin_kv")]
+use crate::gc::Gc;
+#[cfg(feature = "lin_kv")]
+use crate::lru_cache::LruCache;
 use crate::message_handlers::*;
 use crate::messages::*;
+use crate::plumtree::Plumtree;
+use crate::total_order::TotalOrder;
+use crate::txn_engine::Retries;
+#[cfg(feature = "lin_kv")]
+use crate::txn_leader::TxnLeader;
+use crate::wal::{Record, State, Wal};
 use std::collections::HashMap;
-use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::sync::atomic::AtomicU64;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub struct Node {
+    pub config: Config,
     pub id: String,
     pub node_ids: Vec<String>,
     pub topology: HashMap<String, Vec<String>>,
-    pub messages_seen: HashSet<BroadcastValue>,
+    pub messages_seen: BroadcastSet,
+    // delivery order of messages_seen, in total-order and causal mode
+    pub delivery_log: Vec<BroadcastValue>,
     msg_id: AtomicU64,
     // msg_id -> Message serialized string
     pub unacked: Arc<Mutex<HashMap<u64, String>>>,
-    // for txn-list-append challenge
-    #[cfg(not(feature = "lin_kv"))]
-    pub kv_store: HashMap<usize, Vec<usize>>,
+    // grafts are sent from `on_tick`
+    pub plumtree: Plumtree,
+    // elections, claim timeouts and gaps are checked in `on_tick`
+    pub total_order: TotalOrder,
+    pub causal: CausalBroadcast,
+    // gossip rounds are started from `on_tick`
+    pub anti_entropy: AntiEntropy,
+    // messages that arrived while `sync_rpc` was waiting for a reply
+    pub inbox: VecDeque<Message>,
+    // lines of stdin, see `read_line`
+    stdin: Receiver<String>,
+    // ids of the objects this node writes to storage
+    pub ids: IdGen,
+    // for txn-list-append challenge, see `txn_engine::MemoryEngine`
+    pub kv_store: HashMap<TxnKey, serde_json::Value>,
+    // lin-kv objects are never rewritten under the same id, so they can be
+    // cached across transactions. chunk_id -> chunk, see `chunk_list.rs`
+    #[cfg(feature = "lin_kv")]
+    pub chunk_cache: LruCache<String, Chunk>,
+    // partition_id -> (key, chunk_id)
+    #[cfg(feature = "lin_kv")]
+    pub partition_cache: LruCache<String, KeyMap<String>>,
+    // b-tree node id -> serialized node, see `btree.rs`
+    #[cfg(feature = "lin_kv")]
+    pub tree_cache: LruCache<String, serde_json::Value>,
+    // lin-kv objects waiting to be collected
+    #[cfg(feature = "lin_kv")]
+    pub gc: Gc,
+    #[cfg(feature = "lin_kv")]
+    pub txn_leader: TxnLeader,
+    // conflicted txn requests, queued again by `on_tick`
+    pub txn_retries: Retries,
+    // opened by `init`, see `open_wal`
+    wal: Option<Wal>,
 }
 
 impl Node {
     pub fn new() -> Self {
+        let config = Config::from_env();
+        // stdin is read by a thread of its own, so that the main loop can
+        // wait for a line with a timeout and run its timers meanwhile
+        let (lines, stdin) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lines() {
+                match line {
+                    Ok(line) => {
+                        if lines.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => eprintln!("Error reading line: {}", err),
+                }
+            }
+        });
+        Self::with_stdin(config, stdin)
+    }
+
+    /// A node that reads its input lines from `stdin`.
+    pub fn with_stdin(config: Config, stdin: Receiver<String>) -> Self {
+        #[cfg(feature = "lin_kv")]
+        let cache_size = config.cache_size;
+        #[cfg(feature = "lin_kv")]
+        let gc_window = match config.gc_window {
+            0 => 0,
+            window => window.max(config.history),
+        };
+        #[cfg(feature = "lin_kv")]
+        let txn_leader = TxnLeader::new(&config);
         Node {
+            config,
             id: String::from(""),
             node_ids: Vec::new(),
             topology: HashMap::new(),
-            messages_seen: HashSet::new(),
+            messages_seen: BroadcastSet::new(),
+            delivery_log: Vec::new(),
             msg_id: AtomicU64::new(0),
             unacked: Arc::new(Mutex::new(HashMap::new())),
-            #[cfg(not(feature = "lin_kv"))]
+            plumtree: Plumtree::default(),
+            total_order: TotalOrder::default(),
+            causal: CausalBroadcast::default(),
+            anti_entropy: AntiEntropy::default(),
+            inbox: VecDeque::new(),
+            stdin,
+            ids: IdGen::new(),
             kv_store: HashMap::new(),
+            #[cfg(feature = "lin_kv")]
+            chunk_cache: LruCache::new(cache_size),
+            #[cfg(feature = "lin_kv")]
+            partition_cache: LruCache::new(cache_size),
+            #[cfg(feature = "lin_kv")]
+            tree_cache: LruCache::new(cache_size),
+            #[cfg(feature = "lin_kv")]
+            gc: Gc::new(gc_window),
+            #[cfg(feature = "lin_kv")]
+            txn_leader,
+            txn_retries: Retries::default(),
+            wal: None,
         }
     }
 
@@ -54,11 +156,164 @@ impl Node {
         drop(jh);
     }
 
+    /// The next line of stdin, waiting at most `timeout` for it.
+    pub fn read_line(&self, timeout: Duration) -> Result<String, RecvTimeoutError> {
+        self.stdin.recv_timeout(timeout)
+    }
+
+    /// Waits at most `timeout` for the next message and queues it in the
+    /// inbox. False if none came.
+    pub fn read_into_inbox(&mut self, timeout: Duration) -> bool {
+        let Ok(line) = self.read_line(timeout) else {
+            return false;
+        };
+        match serde_json::from_str::<Message>(&line) {
+            Ok(msg) => {
+                #[cfg(feature = "lin_kv")]
+                self.txn_leader.received(&msg, std::time::Instant::now());
+                self.inbox.push_back(msg);
+            }
+            Err(err) => {
+                eprintln!("invalid json data for message: {}", err);
+                if let Some(reply) = self.malformed_reply(&line, &err) {
+                    self.send(reply);
+                }
+            }
+        }
+        true
+    }
+
     pub fn next_msg_id(&self) -> u64 {
         self.msg_id
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
     }
 
+    /// Replays the write-ahead log, if the node keeps one, before any message
+    /// is handled. Maelstrom only names the node in `init`, its first
+    /// message, so that is read here and left in `inbox` for the main loop.
+    pub fn recover(&mut self) {
+        if self.config.wal_dir.is_none() {
+            return;
+        }
+        let Ok(line) = self.stdin.recv() else {
+            return;
+        };
+        match serde_json::from_str::<Message>(&line) {
+            Ok(msg) => {
+                if let MessageExtra::Init(init) = &msg.body.extra {
+                    self.id = init.node_id.clone();
+                    self.open_wal();
+                }
+                self.inbox.push_back(msg);
+            }
+            Err(err) => eprintln!("wal: first message is not init, {err}: {line}"),
+        }
+    }
+
+    /// Replays this node's write-ahead log, if it keeps one and it is not
+    /// open yet, and logs every change from now on.
+    pub fn open_wal(&mut self) {
+        let Some(dir) = self.config.wal_dir.clone() else {
+            return;
+        };
+        if self.wal.is_some() {
+            return;
+        }
+        let (wal, state) = Wal::open(&dir, &self.id, self.config.wal_snapshot)
+            .unwrap_or_else(|e| panic!("can not open the wal in {dir:?}: {e}"));
+        eprintln!(
+            "wal: replayed {} values, {} keys, {} unacked",
+            state.messages_seen.len(),
+            state.kv_store.len(),
+            state.unacked.len()
+        );
+        // new messages must not reuse the msg_id of a replayed one
+        if let Some(max) = state.unacked.keys().max() {
+            self.msg_id
+                .fetch_max(max + 1, std::sync::atomic::Ordering::Relaxed);
+        }
+        self.messages_seen = state.messages_seen;
+        self.kv_store = state.kv_store.0;
+        *self.unacked.lock().unwrap() = state.unacked;
+        self.wal = Some(wal);
+        self.snapshot_wal();
+    }
+
+    /// Logs a change already made in memory, so that a snapshot it
+    /// triggers includes it.
+    fn log(&self, record: Record) {
+        if let Some(wal) = &self.wal {
+            if wal.append(&record) {
+                self.snapshot_wal();
+            }
+        }
+    }
+
+    fn snapshot_wal(&self) {
+        let Some(wal) = &self.wal else {
+            return;
+        };
+        let state = State {
+            messages_seen: self.messages_seen.clone(),
+            kv_store: self
+                .kv_store
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            unacked: self.unacked.lock().unwrap().clone(),
+        };
+        wal.snapshot(&state)
+            .unwrap_or_else(|e| panic!("wal snapshot failed: {e}"));
+    }
+
+    /// Adds `value` to `messages_seen`, true if it was not there before.
+    pub fn mark_seen(&mut self, value: &BroadcastValue) -> bool {
+        let new = self.messages_seen.insert(value);
+        if new {
+            self.log(Record::Seen {
+                value: value.clone(),
+            });
+        }
+        new
+    }
+
+    /// Adds the values of `values` that are not in `messages_seen` yet and
+    /// returns them.
+    pub fn merge_seen(&mut self, values: &BroadcastSet) -> BroadcastSet {
+        let new = values.difference(&self.messages_seen);
+        if !new.is_empty() {
+            self.messages_seen.union_with(&new);
+            self.log(Record::SeenAll { values: new.clone() });
+        }
+        new
+    }
+
+    /// The nodes this node talks to directly, by default all others.
+    pub fn neighbors(&self) -> Vec<String> {
+        match self.topology.get(&self.id) {
+            Some(neighbors) => neighbors.clone(),
+            None => self.node_ids.iter().filter(|n| **n != self.id).cloned().collect(),
+        }
+    }
+
+    /// Stores the values written by a txn in `kv_store`.
+    pub fn kv_write(&mut self, entries: HashMap<TxnKey, serde_json::Value>) {
+        let record = self.wal.is_some().then(|| Record::KvWrite {
+            entries: entries.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+        });
+        self.kv_store.extend(entries);
+        if let Some(record) = record {
+            self.log(record);
+        }
+    }
+
+    /// Stops resending the message `msg_id`, see `send_reliable`.
+    pub fn ack(&self, msg_id: u64) {
+        if self.unacked.lock().unwrap().remove(&msg_id).is_some() {
+            self.log(Record::Acked { msg_id });
+        }
+    }
+
     pub fn handle_message(
         node: Rc<RefCell<Node>>,
         req: &Message,
@@ -68,50 +323,141 @@ impl Node {
 
         match handler {
             Some(handler) => {
-                let res_extra = handler.handle(&node, req);
-                if res_extra.is_none() {
-                    None
-                } else {
-                    let mut res = Message {
-                        src: req.dest.clone(),
-                        dest: req.src.clone(),
-                        body: MessageBody {
-                            msg_id: None,
-                            in_reply_to: None,
-                            extra: res_extra.unwrap(),
-                        },
-                    };
-                    res.body.in_reply_to = req.body.msg_id;
-                    res.body.msg_id = Some(node.borrow().next_msg_id());
-                    Some(res)
-                }
+                let res_extra = handler.handle(&node, req)?;
+                let res = node.borrow().reply_to(req, res_extra);
+                Some(res)
             }
             None => None,
         }
     }
 
+    pub fn reply_to(&self, req: &Message, extra: MessageExtra) -> Message {
+        Message {
+            src: req.dest.clone(),
+            dest: req.src.clone(),
+            body: MessageBody {
+                msg_id: Some(self.next_msg_id()),
+                in_reply_to: req.body.msg_id,
+                extra,
+            },
+        }
+    }
+
+    /// The `malformed-request` error for a line that is not a valid message,
+    /// e.g. a txn with an unknown op. `None` if it can not be replied to.
+    pub fn malformed_reply(&self, line: &str, err: &serde_json::Error) -> Option<Message> {
+        let json: serde_json::Value = serde_json::from_str(line).ok()?;
+        Some(Message {
+            src: json["dest"].as_str()?.to_string(),
+            dest: json["src"].as_str()?.to_string(),
+            body: MessageBody {
+                msg_id: Some(self.next_msg_id()),
+                in_reply_to: Some(json["body"]["msg_id"].as_u64()?),
+                extra: MessageExtra::Error(ErrorExtra {
+                    code: MALFORMED_REQUEST,
+                    text: format!("malformed request: {err}"),
+                }),
+            },
+        })
+    }
+
     pub fn send(&self, res: Message) {
         println!("{}", serde_json::to_string(&res).unwrap());
     }
 
-    #[cfg(feature = "lin_kv")]
-    pub fn sync_rpc(&mut self, dest: &str, payload: MessageExtra) -> MessageExtra {
-        use std::io::BufRead;
+    /// Sends a fire-and-forget message to another node.
+    pub fn send_to(&self, dest: &str, extra: MessageExtra) {
+        self.send(Message {
+            src: self.id.clone(),
+            dest: dest.to_string(),
+            body: MessageBody {
+                msg_id: None,
+                in_reply_to: None,
+                extra,
+            },
+        });
+    }
+
+    /// Sends a message to another node and keeps resending it from the
+    /// broadcast loop until a reply with the same `in_reply_to` removes it
+    /// from `unacked`.
+    pub fn send_reliable(&self, dest: &str, extra: MessageExtra) {
         let req = Message {
             src: self.id.clone(),
             dest: dest.to_string(),
             body: MessageBody {
                 msg_id: Some(self.next_msg_id()),
                 in_reply_to: None,
-                extra: payload,
+                extra,
             },
         };
-        println!("{}", serde_json::to_string(&req).unwrap());
-        eprintln!("sent to lin-kv: {}", serde_json::to_string(&req).unwrap());
-        let mut line = String::new();
-        std::io::stdin().lock().read_line(&mut line).unwrap();
-        eprintln!("received from lin-kv: {}", line);
-        serde_json::from_str::<Message>(&line).unwrap().body.extra
+        let serialized = serde_json::to_string(&req).unwrap();
+        eprintln!("sent {}", serialized);
+        let msg_id = req.body.msg_id.unwrap();
+        self.unacked.lock().unwrap().insert(msg_id, serialized.clone());
+        self.log(Record::Sent {
+            msg_id,
+            message: serialized,
+        });
+        self.send(req);
+    }
+
+    #[cfg(feature = "lin_kv")]
+    pub fn sync_rpc(&mut self, dest: &str, payload: MessageExtra) -> MessageExtra {
+        self.sync_rpc_many(dest, vec![payload]).pop().unwrap()
+    }
+
+    /// Sends all requests at once and blocks until every one is answered,
+    /// so n requests cost one round trip instead of n. Replies are returned
+    /// in request order. Other messages read from stdin meanwhile are queued
+    /// in `inbox` for the main loop.
+    #[cfg(feature = "lin_kv")]
+    pub fn sync_rpc_many(&mut self, dest: &str, payloads: Vec<MessageExtra>) -> Vec<MessageExtra> {
+        let mut waiting: HashMap<u64, usize> = HashMap::new();
+        for (i, payload) in payloads.into_iter().enumerate() {
+            let req = Message {
+                src: self.id.clone(),
+                dest: dest.to_string(),
+                body: MessageBody {
+                    msg_id: Some(self.next_msg_id()),
+                    in_reply_to: None,
+                    extra: payload,
+                },
+            };
+            waiting.insert(req.body.msg_id.unwrap(), i);
+            let serialized = serde_json::to_string(&req).unwrap();
+            println!("{}", serialized);
+            eprintln!("sent to {dest}: {serialized}");
+        }
+
+        let mut replies: Vec<Option<MessageExtra>> = vec![None; waiting.len()];
+        while !waiting.is_empty() {
+            let Ok(line) = self.stdin.recv() else {
+                panic!("stdin closed while waiting for {dest}");
+            };
+            let msg = match serde_json::from_str::<Message>(&line) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    eprintln!("invalid json data for message: {}", err);
+                    if let Some(reply) = self.malformed_reply(&line, &err) {
+                        self.send(reply);
+                    }
+                    continue;
+                }
+            };
+            match msg.body.in_reply_to.and_then(|id| waiting.remove(&id)) {
+                Some(i) => {
+                    eprintln!("received from {dest}: {}", line.trim_end());
+                    replies[i] = Some(msg.body.extra);
+                }
+                None => {
+                    // forwards queued here are already waiting
+                    self.txn_leader.received(&msg, std::time::Instant::now());
+                    self.inbox.push_back(msg)
+                }
+            }
+        }
+        replies.into_iter().map(Option::unwrap).collect()
     }
 }
 
@@ -0,0 +1,576 @@
+//! Optimistic concurrency control on per-key version counters.
+//!
+//! Like `percolator.rs` every key has a lin-kv record of its own, but a txn
+//! takes no timestamps and holds no locks while it runs. It reads the
+//! records of its keys, remembers their versions and runs the ops in
+//! memory. At commit it validates that none of them changed:
+//!
+//! 1. lock the written keys with a CAS from the record it read, the first
+//!    one as the primary, which lists the others as secondaries;
+//! 2. read the keys it only read again and compare their versions;
+//! 3. mark the primary lock committed, the commit point;
+//! 4. install the new values with the next version, which unlocks them,
+//!    the secondaries first and the primary last.
+//!
+//! A read-only txn only does step 2. Conflicts abort only txns that share
+//! keys, and the error lists the keys that changed.
+//!
+//! ```text
+//! "occ-1": {"version": 3, "value": [1, 2]}
+//! "occ-2": {"version": 1, "value": 5, "lock": {"txn": "n1-…-4", "expires": 1700000000000, "value": 6, "primary": 2, "secondaries": [3], "committed": true}}
+//! "occ-3": {"version": 0, "lock": {"txn": "n1-…-4", "expires": 1700000000000, "value": [7], "primary": 2}}
+//! ```
+//!
+//! A lock left by a crashed txn is resolved by the next txn to find it once
+//! it expired (`MAELSTROM_TXN_LOCK_TTL_MS`), as its primary says: the txn
+//! is installed if the primary is committed, and aborted by unlocking the
+//! primary otherwise, so the owner can no longer commit. As the primary is
+//! unlocked last, a secondary whose primary is no longer locked by its txn
+//! belongs to an aborted txn. So nothing about a txn outlives its locks.
+use crate::lin_kv::LinKv;
+use crate::messages::*;
+use crate::node::Node;
+use crate::txn_engine::apply;
+use crate::txn_leader::now_ms;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Record {
+    /// 0 for a key that was never written
+    version: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lock: Option<Lock>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Lock {
+    txn: String,
+    /// unix ms, the lock may be resolved by others after that
+    expires: u64,
+    /// the value the key gets on commit
+    value: Value,
+    /// the first key the txn writes, whose lock decides the txn
+    primary: TxnKey,
+    /// on the primary, the other keys the txn writes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    secondaries: Vec<TxnKey>,
+    /// set on the primary at the commit point
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    committed: bool,
+}
+
+impl Record {
+    fn unlocked(&self) -> Record {
+        Record {
+            lock: None,
+            ..self.clone()
+        }
+    }
+
+    /// The primary record at the commit point.
+    fn committed(&self) -> Record {
+        let mut record = self.clone();
+        if let Some(lock) = &mut record.lock {
+            lock.committed = true;
+        }
+        record
+    }
+
+    /// The record once the lock is installed.
+    fn installed(&self) -> Record {
+        Record {
+            version: self.version + 1,
+            value: self.lock.as_ref().map(|lock| lock.value.clone()),
+            lock: None,
+        }
+    }
+}
+
+/// A record as read from lin-kv, `raw` is what a CAS compares.
+struct Stored {
+    key: TxnKey,
+    raw: Value,
+    record: Record,
+}
+
+impl Stored {
+    fn new(key: &TxnKey, record: Record) -> Self {
+        Stored {
+            key: key.clone(),
+            raw: serde_json::to_value(&record).unwrap(),
+            record,
+        }
+    }
+}
+
+fn record_key(key: &TxnKey) -> String {
+    format!("occ-{}", key.0.canonical())
+}
+
+fn conflict<'a>(keys: impl IntoIterator<Item = &'a TxnKey>) -> ErrorExtra {
+    let keys: Vec<String> = keys.into_iter().map(TxnKey::to_string).collect();
+    ErrorExtra {
+        code: TXN_CONFLICT,
+        text: format!("conflict on keys {}", keys.join(", ")),
+    }
+}
+
+pub struct Occ<K = Rc<RefCell<Node>>> {
+    kv: K,
+    lock_ttl: u64,
+    /// id of the txn, in its locks
+    txn: String,
+}
+
+impl Occ {
+    pub fn new(node: &Rc<RefCell<Node>>) -> Self {
+        let (lock_ttl, txn) = {
+            let node = node.borrow();
+            (
+                node.config.txn_lock_ttl.as_millis() as u64,
+                node.ids.next_id(&node.id),
+            )
+        };
+        Self {
+            kv: node.clone(),
+            lock_ttl,
+            txn,
+        }
+    }
+}
+
+impl<K: LinKv> Occ<K> {
+    /// Runs `txn`, an `Occ` runs a single txn.
+    pub fn transact(&self, txn: &[MicroOp]) -> Result<Vec<MicroOp>, ErrorExtra> {
+        let mut keys: Vec<TxnKey> = Vec::new();
+        for op in txn {
+            if !keys.contains(op.key()) {
+                keys.push(op.key().clone());
+            }
+        }
+        let stored = self.read_resolved(&keys)?;
+        let mut state: HashMap<TxnKey, Value> = stored
+            .iter()
+            .filter_map(|s| Some((s.key.clone(), s.record.value.clone()?)))
+            .collect();
+        let results = apply(&mut state, txn)?;
+
+        let written: HashSet<&TxnKey> = txn
+            .iter()
+            .filter(|op| op.is_write())
+            .map(MicroOp::key)
+            .collect();
+        let (writes, reads): (Vec<Stored>, Vec<Stored>) =
+            stored.into_iter().partition(|s| written.contains(&s.key));
+        if writes.is_empty() {
+            let changed = self.changed(&reads);
+            return if changed.is_empty() {
+                Ok(results)
+            } else {
+                Err(conflict(changed))
+            };
+        }
+
+        let expires = now_ms() + self.lock_ttl;
+        let primary = &writes[0].key;
+        let locked: Vec<Stored> = writes
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                let secondaries = match i {
+                    0 => writes[1..].iter().map(|s| s.key.clone()).collect(),
+                    _ => Vec::new(),
+                };
+                let lock = Lock {
+                    txn: self.txn.clone(),
+                    expires,
+                    value: state[&s.key].clone(),
+                    primary: primary.clone(),
+                    secondaries,
+                    committed: false,
+                };
+                Stored::new(
+                    &s.key,
+                    Record {
+                        lock: Some(lock),
+                        ..s.record.clone()
+                    },
+                )
+            })
+            .collect();
+        let ok = self.cas_many(writes.iter().zip(&locked).map(|(s, l)| (s, &l.record)));
+        let mut changed: Vec<&TxnKey> = writes
+            .iter()
+            .zip(&ok)
+            .filter(|(_, &ok)| !ok)
+            .map(|(s, _)| &s.key)
+            .collect();
+        if changed.is_empty() {
+            changed = self.changed(&reads);
+        }
+        let committed = Stored::new(primary, locked[0].record.committed());
+        if changed.is_empty() && !self.cas_many([(&locked[0], &committed.record)])[0] {
+            // held too long, another txn aborted it
+            changed = writes.iter().map(|s| &s.key).collect();
+        }
+        if !changed.is_empty() {
+            let held = locked.iter().zip(&ok).filter(|(_, &ok)| ok).map(|(l, _)| l);
+            let undo: Vec<Record> = held.clone().map(|l| l.record.unlocked()).collect();
+            self.cas_many(held.zip(&undo));
+            return Err(conflict(changed));
+        }
+        // a failed CAS means another txn installed it already
+        let done: Vec<Record> = locked[1..].iter().map(|l| l.record.installed()).collect();
+        self.cas_many(locked[1..].iter().zip(&done));
+        self.cas_many([(&committed, &committed.record.installed())]);
+        Ok(results)
+    }
+
+    /// The keys of `stored` whose records changed since they were read.
+    fn changed<'a>(&self, stored: &'a [Stored]) -> Vec<&'a TxnKey> {
+        let keys: Vec<TxnKey> = stored.iter().map(|s| s.key.clone()).collect();
+        stored
+            .iter()
+            .zip(self.read(&keys))
+            .filter(|(old, new)| old.raw != new.raw)
+            .map(|(old, _)| &old.key)
+            .collect()
+    }
+
+    /// Reads the records of `keys` and resolves the expired locks among
+    /// them. A lock that has not expired yet is a conflict.
+    fn read_resolved(&self, keys: &[TxnKey]) -> Result<Vec<Stored>, ErrorExtra> {
+        loop {
+            let stored = self.read(keys);
+            let locked: Vec<&Stored> = stored.iter().filter(|s| s.record.lock.is_some()).collect();
+            if locked.is_empty() {
+                return Ok(stored);
+            }
+            let now = now_ms();
+            let busy: Vec<&TxnKey> = locked
+                .iter()
+                .filter(|s| s.record.lock.as_ref().unwrap().expires > now)
+                .map(|s| &s.key)
+                .collect();
+            if !busy.is_empty() {
+                return Err(conflict(busy));
+            }
+            for s in locked {
+                self.resolve(s);
+            }
+        }
+    }
+
+    /// Moves the txn of the expired lock on `s` on, as its primary says.
+    /// A secondary of an undecided txn stays locked until the next round.
+    fn resolve(&self, s: &Stored) {
+        let lock = s.record.lock.as_ref().unwrap();
+        if lock.primary == s.key {
+            eprintln!(
+                "resolve primary lock of txn {} on key {}, committed {}",
+                lock.txn, s.key, lock.committed
+            );
+            if lock.committed {
+                let secondaries = self.read(&lock.secondaries);
+                let held: Vec<&Stored> = secondaries
+                    .iter()
+                    .filter(|o| o.record.lock.as_ref().is_some_and(|l| l.txn == lock.txn))
+                    .collect();
+                let done: Vec<Record> = held.iter().map(|o| o.record.installed()).collect();
+                self.cas_many(held.into_iter().zip(&done));
+                self.cas_many([(s, &s.record.installed())]);
+            } else {
+                self.cas_many([(s, &s.record.unlocked())]);
+            }
+            return;
+        }
+        let primary = self
+            .read(std::slice::from_ref(&lock.primary))
+            .pop()
+            .unwrap();
+        match &primary.record.lock {
+            Some(p) if p.txn == lock.txn && p.committed => {
+                self.cas_many([(s, &s.record.installed())]);
+            }
+            Some(p) if p.txn == lock.txn => {
+                eprintln!("abort txn {} at its primary {}", lock.txn, primary.key);
+                self.cas_many([(&primary, &primary.record.unlocked())]);
+            }
+            // the primary is unlocked last, so the txn was aborted
+            _ => {
+                self.cas_many([(s, &s.record.unlocked())]);
+            }
+        }
+    }
+
+    fn read(&self, keys: &[TxnKey]) -> Vec<Stored> {
+        let ids: Vec<String> = keys.iter().map(record_key).collect();
+        keys.iter()
+            .zip(self.kv.read_many(&ids))
+            .map(|(key, raw)| match raw {
+                Some(raw) => Stored {
+                    key: key.clone(),
+                    record: serde_json::from_value(raw.clone()).expect("an occ key record"),
+                    raw,
+                },
+                None => Stored {
+                    key: key.clone(),
+                    raw: Value::Null,
+                    record: Record::default(),
+                },
+            })
+            .collect()
+    }
+
+    /// Replaces each record with its new one, false where it changed since.
+    fn cas_many<'a>(
+        &self,
+        changes: impl IntoIterator<Item = (&'a Stored, &'a Record)>,
+    ) -> Vec<bool> {
+        let changes = changes
+            .into_iter()
+            .map(|(from, to)| {
+                (
+                    record_key(&from.key),
+                    from.raw.clone(),
+                    serde_json::to_value(to).unwrap(),
+                )
+            })
+            .collect();
+        self.kv
+            .cas_many(changes)
+            .iter()
+            .map(Result::is_ok)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lin_kv::mem::MemKv;
+    use serde_json::json;
+    use std::cell::Cell;
+
+    fn occ(kv: MemKv, txn: &str) -> Occ<MemKv> {
+        Occ {
+            kv,
+            lock_ttl: 60_000,
+            txn: txn.to_string(),
+        }
+    }
+
+    fn key(k: u64) -> TxnKey {
+        TxnKey::new(json!(k))
+    }
+
+    fn txn(ops: Value) -> Vec<MicroOp> {
+        serde_json::from_value(ops).unwrap()
+    }
+
+    fn record(o: &Occ<MemKv>, k: u64) -> Record {
+        o.read(&[key(k)]).pop().unwrap().record
+    }
+
+    fn store(o: &Occ<MemKv>, k: u64, record: &Record) {
+        let raw = serde_json::to_value(record).unwrap();
+        o.kv.data.borrow_mut().insert(record_key(&key(k)), raw);
+    }
+
+    /// Txn `dead` locked key 1, its primary, and key 2 to write 9 over 1,
+    /// and its locks expired.
+    fn abandoned_txn(o: &Occ<MemKv>, committed: bool) {
+        let lock = |secondaries, committed| Lock {
+            txn: "dead".to_string(),
+            expires: 0,
+            value: json!(9),
+            primary: key(1),
+            secondaries,
+            committed,
+        };
+        let record = |lock| Record {
+            version: 1,
+            value: Some(json!(1)),
+            lock: Some(lock),
+        };
+        store(o, 1, &record(lock(vec![key(2)], committed)));
+        store(o, 2, &record(lock(Vec::new(), false)));
+    }
+
+    #[test]
+    fn commit_installs_new_versions_and_leaves_nothing_else() {
+        let o = occ(MemKv::default(), "t1");
+        let res = o
+            .transact(&txn(json!([["append", 1, 3], ["w", 2, 4]])))
+            .unwrap();
+        assert_eq!(res, txn(json!([["append", 1, 3], ["w", 2, 4]])));
+        for (k, value) in [(1, json!([3])), (2, json!(4))] {
+            let record = record(&o, k);
+            assert_eq!((record.version, record.value), (1, Some(value)));
+            assert!(record.lock.is_none());
+        }
+        let mut keys: Vec<String> = o.kv.data.borrow().keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, ["occ-1", "occ-2"]);
+
+        let o = occ(o.kv, "t2");
+        let res = o
+            .transact(&txn(json!([["r", 1, null], ["r", 2, null]])))
+            .unwrap();
+        assert_eq!(res, txn(json!([["r", 1, [3]], ["r", 2, 4]])));
+    }
+
+    #[test]
+    fn conflict_names_the_changed_keys_and_unlocks() {
+        let o = occ(MemKv::default(), "t1");
+        o.transact(&txn(json!([["w", 1, 1], ["w", 3, 1]]))).unwrap();
+        let o = occ(o.kv, "t2");
+        // another txn writes key 1 after t2 read it
+        let changed = serde_json::to_value(Record {
+            version: 2,
+            value: Some(json!(7)),
+            lock: None,
+        })
+        .unwrap();
+        *o.kv.before_cas.borrow_mut() = Some(Box::new(move |data| {
+            data.insert(record_key(&key(1)), changed);
+        }));
+        let err = o
+            .transact(&txn(json!([["r", 1, null], ["r", 3, null], ["w", 2, 9]])))
+            .unwrap_err();
+        assert_eq!(err.code, TXN_CONFLICT);
+        assert_eq!(err.text, "conflict on keys 1");
+        let record = record(&o, 2);
+        assert!(record.lock.is_none());
+        assert_eq!(record.value, None);
+    }
+
+    #[test]
+    fn read_only_txn_validates_its_reads() {
+        let o = occ(MemKv::default(), "t1");
+        o.transact(&txn(json!([["w", 1, 1], ["w", 2, 2]]))).unwrap();
+        let o = occ(o.kv, "t2");
+        let res = o
+            .transact(&txn(json!([["r", 1, null], ["r", 2, null]])))
+            .unwrap();
+        assert_eq!(res, txn(json!([["r", 1, 1], ["r", 2, 2]])));
+
+        let changed = serde_json::to_value(Record {
+            version: 2,
+            value: Some(json!(5)),
+            lock: None,
+        })
+        .unwrap();
+        *o.kv.after_read.borrow_mut() = Some(Box::new(move |data| {
+            data.insert(record_key(&key(2)), changed);
+        }));
+        let err = o
+            .transact(&txn(json!([["r", 1, null], ["r", 2, null]])))
+            .unwrap_err();
+        assert_eq!(
+            (err.code, err.text.as_str()),
+            (TXN_CONFLICT, "conflict on keys 2")
+        );
+    }
+
+    #[test]
+    fn lock_that_has_not_expired_is_a_conflict() {
+        let o = occ(MemKv::default(), "t1");
+        abandoned_txn(&o, false);
+        let mut record = record(&o, 2);
+        record.lock.as_mut().unwrap().expires = u64::MAX;
+        store(&o, 2, &record);
+        let err = o.transact(&txn(json!([["r", 2, null]]))).unwrap_err();
+        assert_eq!(
+            (err.code, err.text.as_str()),
+            (TXN_CONFLICT, "conflict on keys 2")
+        );
+    }
+
+    #[test]
+    fn expired_undecided_txn_is_aborted_at_its_primary() {
+        let o = occ(MemKv::default(), "t1");
+        abandoned_txn(&o, false);
+        let res = o.transact(&txn(json!([["r", 2, null]]))).unwrap();
+        assert_eq!(res, txn(json!([["r", 2, 1]])));
+        for k in [1, 2] {
+            let record = record(&o, k);
+            assert_eq!((record.version, record.value), (1, Some(json!(1))));
+            assert!(record.lock.is_none());
+        }
+    }
+
+    #[test]
+    fn expired_committed_txn_is_installed() {
+        let o = occ(MemKv::default(), "t1");
+        abandoned_txn(&o, true);
+        let res = o.transact(&txn(json!([["r", 2, null]]))).unwrap();
+        assert_eq!(res, txn(json!([["r", 2, 9]])));
+        // the primary stays locked until a txn finds it
+        assert!(record(&o, 1).lock.is_some());
+
+        abandoned_txn(&o, true);
+        let res = o.transact(&txn(json!([["r", 1, null]]))).unwrap();
+        assert_eq!(res, txn(json!([["r", 1, 9]])));
+        for k in [1, 2] {
+            let record = record(&o, k);
+            assert_eq!((record.version, record.value), (2, Some(json!(9))));
+            assert!(record.lock.is_none());
+        }
+    }
+
+    /// Lets a resolver abort the txn at its primary, key 1, right before
+    /// the second CAS, the commit point of a txn that only writes.
+    struct AbortBeforeCommit {
+        kv: MemKv,
+        cas: Cell<u32>,
+    }
+
+    impl LinKv for AbortBeforeCommit {
+        fn read_many(&self, keys: &[String]) -> Vec<Option<Value>> {
+            self.kv.read_many(keys)
+        }
+
+        fn cas_many(&self, changes: Vec<(String, Value, Value)>) -> Vec<Result<(), u64>> {
+            self.cas.set(self.cas.get() + 1);
+            if self.cas.get() == 2 {
+                let mut data = self.kv.data.borrow_mut();
+                let primary = record_key(&key(1));
+                let mut record: Record = serde_json::from_value(data[&primary].clone()).unwrap();
+                record.lock = None;
+                data.insert(primary, serde_json::to_value(record).unwrap());
+            }
+            self.kv.cas_many(changes)
+        }
+    }
+
+    #[test]
+    fn owner_cannot_commit_after_its_primary_was_aborted() {
+        let o = Occ {
+            kv: AbortBeforeCommit {
+                kv: MemKv::default(),
+                cas: Cell::new(0),
+            },
+            lock_ttl: 60_000,
+            txn: "t1".to_string(),
+        };
+        let err = o
+            .transact(&txn(json!([["w", 1, 5], ["w", 2, 6]])))
+            .unwrap_err();
+        assert_eq!(
+            (err.code, err.text.as_str()),
+            (TXN_CONFLICT, "conflict on keys 1, 2")
+        );
+        for k in [1, 2] {
+            let record = o.read(&[key(k)]).pop().unwrap().record;
+            assert!(record.lock.is_none());
+            assert_eq!(record.value, None);
+        }
+    }
+}
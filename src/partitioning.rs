@@ -0,0 +1,310 @@
+//! How the partitioned transactor groups keys into partitions.
+//!
+//! The root maps a partition key to the partition's metadata. Strategies
+//! work on a key's position, see `TxnKey::position`: an integer key is its
+//! own position, any other key lands on a hash. What a partition key means
+//! depends on the strategy:
+//!
+//! * `range`: `position / size`, the original fixed ranges. Hashed keys
+//!   would get a range each, so they go to hash buckets at the top of the
+//!   key space instead.
+//! * `hash`: a hash of the key modulo a fixed number of buckets, so the root
+//!   never grows and hot ranges are spread out.
+//! * `adaptive`: the lower bound of a key range. A partition that grows past
+//!   the size threshold is split at its median key, and a partition that is
+//!   rewritten next to a small, cold neighbor absorbs it.
+//!
+//! All nodes must use the same strategy, it is part of the database format.
+use crate::config::{Config, PartitioningKind};
+use crate::messages::{KeyMap, TxnKey};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+/// Metadata of one partition, stored in the root next to its id.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PartInfo {
+    pub id: String,
+    /// number of keys in the partition
+    pub size: usize,
+    /// root version that last rewrote the partition
+    pub last_write: u64,
+}
+
+/// partition_key -> partition metadata
+pub type Parts = BTreeMap<usize, PartInfo>;
+
+/// key -> chunk_id
+pub type Partition = KeyMap<String>;
+
+pub trait Partitioner {
+    /// The partition key of `key` in a root with `parts`.
+    fn part_key(&self, parts: &Parts, key: &TxnKey) -> usize;
+
+    /// A neighbor that the partition at `part_key` should absorb when it is
+    /// rewritten at root `version`.
+    fn merge_with(&self, _parts: &Parts, _part_key: usize, _version: u64) -> Option<usize> {
+        None
+    }
+
+    /// The partition key at which a partition should be split in two.
+    fn split_at(&self, _part_key: usize, _part: &Partition) -> Option<usize> {
+        None
+    }
+}
+
+/// Creates the strategy selected by the config.
+pub fn new_partitioner(config: &Config) -> Box<dyn Partitioner> {
+    match config.partitioning {
+        PartitioningKind::Range => Box::new(FixedRange {
+            size: config.partition_size,
+            hashed: HashModulo {
+                buckets: config.partition_count,
+            },
+        }),
+        PartitioningKind::Hash => Box::new(HashModulo {
+            buckets: config.partition_count,
+        }),
+        PartitioningKind::Adaptive => Box::new(Adaptive {
+            max_size: config.partition_size,
+            cold_after: 100,
+        }),
+    }
+}
+
+pub struct FixedRange {
+    pub size: usize,
+    /// buckets for the keys that are not integers
+    pub hashed: HashModulo,
+}
+
+impl Partitioner for FixedRange {
+    fn part_key(&self, parts: &Parts, key: &TxnKey) -> usize {
+        if key.as_u64().is_none() {
+            return usize::MAX - self.hashed.part_key(parts, key);
+        }
+        key.position() / self.size
+    }
+}
+
+pub struct HashModulo {
+    pub buckets: usize,
+}
+
+impl Partitioner for HashModulo {
+    fn part_key(&self, _parts: &Parts, key: &TxnKey) -> usize {
+        // fibonacci hashing, the same on every node
+        let hash = (key.position() as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32;
+        (hash % self.buckets as u64) as usize
+    }
+}
+
+pub struct Adaptive {
+    /// split a partition with more keys than this
+    pub max_size: usize,
+    /// a partition not rewritten for this many root versions is cold
+    pub cold_after: u64,
+}
+
+impl Partitioner for Adaptive {
+    fn part_key(&self, parts: &Parts, key: &TxnKey) -> usize {
+        parts
+            .range(..=key.position())
+            .next_back()
+            .map(|(k, _)| *k)
+            .unwrap_or(0)
+    }
+
+    fn merge_with(&self, parts: &Parts, part_key: usize, version: u64) -> Option<usize> {
+        let size = parts.get(&part_key)?.size;
+        let right = parts.range(part_key + 1..).next();
+        let left = parts.range(..part_key).next_back();
+        right
+            .into_iter()
+            .chain(left)
+            .find(|(_, info)| {
+                info.last_write + self.cold_after <= version && info.size + size <= self.max_size
+            })
+            .map(|(k, _)| *k)
+    }
+
+    fn split_at(&self, _part_key: usize, part: &Partition) -> Option<usize> {
+        if part.len() <= self.max_size {
+            return None;
+        }
+        let mut keys: Vec<usize> = part.keys().map(TxnKey::position).collect();
+        keys.sort_unstable();
+        Some(keys[keys.len() / 2])
+    }
+}
+
+/// The merges `(touched, neighbor)` for a commit that rewrites the
+/// partitions `touched` of the root with `parts`. All are picked before any
+/// is done, so a neighbor is a real one, and each partition takes part in
+/// one merge at most.
+pub fn plan_merges(
+    partitioner: &dyn Partitioner,
+    parts: &Parts,
+    touched: &[usize],
+    version: u64,
+) -> Vec<(usize, usize)> {
+    let mut taken: HashSet<usize> = touched.iter().copied().collect();
+    touched
+        .iter()
+        .filter_map(|&pk| {
+            let other = partitioner.merge_with(parts, pk, version)?;
+            (adjacent(parts, pk, other) && taken.insert(other)).then_some((pk, other))
+        })
+        .collect()
+}
+
+/// Whether `b` is a partition and none lies between `a` and `b`. A range
+/// partition holds the keys up to the next one, so only adjacent ones can
+/// be merged.
+fn adjacent(parts: &Parts, a: usize, b: usize) -> bool {
+    let (low, high) = (a.min(b), a.max(b));
+    low != high && parts.contains_key(&b) && parts.range(low + 1..high).next().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn key(k: serde_json::Value) -> TxnKey {
+        TxnKey::new(k)
+    }
+
+    fn info(size: usize, last_write: u64) -> PartInfo {
+        PartInfo {
+            id: format!("part-{size}-{last_write}"),
+            size,
+            last_write,
+        }
+    }
+
+    fn partition(keys: impl IntoIterator<Item = u64>) -> Partition {
+        keys.into_iter()
+            .map(|k| (key(json!(k)), format!("chunk-{k}")))
+            .collect()
+    }
+
+    fn adaptive() -> Adaptive {
+        Adaptive {
+            max_size: 4,
+            cold_after: 10,
+        }
+    }
+
+    #[test]
+    fn fixed_range_routes_integers_by_range() {
+        let range = FixedRange {
+            size: 10,
+            hashed: HashModulo { buckets: 4 },
+        };
+        let parts = Parts::new();
+        assert_eq!(range.part_key(&parts, &key(json!(0))), 0);
+        assert_eq!(range.part_key(&parts, &key(json!(9))), 0);
+        assert_eq!(range.part_key(&parts, &key(json!(10))), 1);
+        assert_eq!(range.split_at(0, &partition(0..100)), None);
+        assert_eq!(range.merge_with(&parts, 0, 1000), None);
+    }
+
+    #[test]
+    fn fixed_range_puts_other_keys_in_a_few_buckets() {
+        let range = FixedRange {
+            size: 10,
+            hashed: HashModulo { buckets: 4 },
+        };
+        let parts = Parts::new();
+        let buckets: HashSet<usize> = (0..200)
+            .map(|i| range.part_key(&parts, &key(json!(format!("user-{i}")))))
+            .collect();
+        assert!(buckets.len() <= 4);
+        assert!(buckets.iter().all(|&pk| pk > usize::MAX - 4));
+        let user = key(json!({"user": "ann"}));
+        assert_eq!(range.part_key(&parts, &user), range.part_key(&parts, &user));
+    }
+
+    #[test]
+    fn hash_modulo_stays_within_its_buckets() {
+        let hash = HashModulo { buckets: 8 };
+        let parts = Parts::new();
+        let buckets: HashSet<usize> = (0..1000)
+            .map(|k| hash.part_key(&parts, &key(json!(k))))
+            .collect();
+        assert_eq!(buckets.len(), 8);
+        assert!(buckets.iter().all(|&pk| pk < 8));
+        assert_eq!(hash.split_at(0, &partition(0..100)), None);
+        assert_eq!(hash.merge_with(&parts, 0, 1000), None);
+    }
+
+    #[test]
+    fn adaptive_routes_to_the_range_below() {
+        let parts = Parts::from([(0, info(1, 0)), (10, info(1, 0)), (50, info(1, 0))]);
+        let adaptive = adaptive();
+        assert_eq!(adaptive.part_key(&parts, &key(json!(9))), 0);
+        assert_eq!(adaptive.part_key(&parts, &key(json!(10))), 10);
+        assert_eq!(adaptive.part_key(&parts, &key(json!(49))), 10);
+        assert_eq!(adaptive.part_key(&parts, &key(json!(1000))), 50);
+        assert_eq!(adaptive.part_key(&Parts::new(), &key(json!(7))), 0);
+    }
+
+    #[test]
+    fn adaptive_splits_a_large_partition_at_its_median() {
+        let adaptive = adaptive();
+        assert_eq!(adaptive.split_at(0, &partition([1, 2, 3, 4])), None);
+        assert_eq!(adaptive.split_at(0, &partition([1, 5, 2, 9, 7])), Some(5));
+    }
+
+    #[test]
+    fn adaptive_merges_with_a_small_cold_neighbor() {
+        let adaptive = adaptive();
+        // 10 is hot, 50 is cold but too large together with 20
+        let parts = Parts::from([
+            (0, info(1, 0)),
+            (10, info(1, 95)),
+            (20, info(2, 0)),
+            (50, info(3, 0)),
+        ]);
+        assert_eq!(adaptive.merge_with(&parts, 20, 100), None);
+        assert_eq!(adaptive.merge_with(&parts, 0, 100), None);
+        assert_eq!(adaptive.merge_with(&parts, 50, 100), None);
+        let parts = Parts::from([(0, info(1, 0)), (10, info(1, 95)), (20, info(2, 0))]);
+        assert_eq!(adaptive.merge_with(&parts, 10, 100), Some(20));
+    }
+
+    #[test]
+    fn merges_are_planned_on_the_loaded_root() {
+        let adaptive = adaptive();
+        let parts = Parts::from([
+            (0, info(1, 0)),
+            (10, info(1, 0)),
+            (20, info(1, 0)),
+            (30, info(1, 0)),
+        ]);
+        // 20 and 30 pick each other, but both are rewritten by this commit
+        let merges = plan_merges(&adaptive, &parts, &[0, 20, 30], 100);
+        assert_eq!(merges, vec![(0, 10)]);
+        // 10 is taken by the first merge
+        let parts = Parts::from([(0, info(1, 0)), (10, info(1, 0)), (20, info(3, 0))]);
+        let merges = plan_merges(&adaptive, &parts, &[0, 20], 100);
+        assert_eq!(merges, vec![(0, 10)]);
+    }
+
+    #[test]
+    fn only_adjacent_partitions_are_merged() {
+        /// merges with the partition two to the right
+        struct Skipping;
+        impl Partitioner for Skipping {
+            fn part_key(&self, _parts: &Parts, key: &TxnKey) -> usize {
+                key.position()
+            }
+
+            fn merge_with(&self, parts: &Parts, part_key: usize, _version: u64) -> Option<usize> {
+                parts.range(part_key + 1..).nth(1).map(|(k, _)| *k)
+            }
+        }
+        let parts = Parts::from([(0, info(1, 0)), (10, info(1, 0)), (20, info(1, 0))]);
+        assert!(plan_merges(&Skipping, &parts, &[0], 100).is_empty());
+    }
+}
@@ -0,0 +1,591 @@
+//! Percolator-style txns on per-key records.
+//!
+//! The other lin-kv engines publish every commit with a CAS of one root, so
+//! all writers race for the same key even when their txns share no keys.
+//! This engine keeps one record per txn key instead, with the versions of
+//! the key and the lock of the txn writing it, and commits with two-phase
+//! commit over the keys of the txn, like Google's Percolator:
+//!
+//! ```text
+//! "txn-ts": 42                       the timestamp oracle
+//! "key-1": {"versions": [{"start_ts": 7, "commit_ts": 9, "value": [1, 2]}],
+//!           "lock": {"start_ts": 41, "primary": 3, "expires": 1700000000000,
+//!                    "write": true, "value": [1, 2, 5]}}
+//! ```
+//!
+//! A txn that writes takes a start timestamp, reads the records of its keys
+//! and locks every one with a CAS from the record it read, the primary first.
+//! The primary is its first written key. Then it takes a commit timestamp
+//! and replaces the primary lock with a version, this CAS is the commit
+//! point, and last does the same for the secondaries. Read keys are locked
+//! too: a lock keeps the value of its key until the commit, so the values
+//! read are still current at the commit timestamp and txns are serializable
+//! in commit timestamp order, not only snapshot isolated. Timestamps come
+//! from one counter in lin-kv, so a txn that starts after another one
+//! finished gets a larger one.
+//!
+//! A read-only txn takes no locks, it reads the last version committed
+//! before its start timestamp. A write lock from an older start timestamp
+//! may still commit below it, so the txn conflicts and is retried.
+//!
+//! A lock is abandoned when its txn crashed or stalled. Once it expired
+//! (`MAELSTROM_TXN_LOCK_TTL_MS`), the next txn to find it resolves it by
+//! its primary: if the primary committed the lock is rolled forward,
+//! otherwise the primary lock and this one are removed. A txn whose primary
+//! lock was removed fails to commit it and aborts.
+//!
+//! A record keeps the last `VERSIONS` versions. The version of a primary
+//! lists its secondaries, they are rolled forward before it is dropped, so
+//! the evidence of a commit outlives its locks.
+use crate::lin_kv::LinKv;
+use crate::messages::*;
+use crate::node::Node;
+use crate::txn_engine::apply;
+use crate::txn_leader::now_ms;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+const TS_KEY: &str = "txn-ts";
+/// Versions kept per key, for read-only txns that started before the last
+/// commits.
+const VERSIONS: usize = 4;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Record {
+    /// oldest first, commit timestamps grow
+    #[serde(default)]
+    versions: Vec<Version>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lock: Option<Lock>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Version {
+    start_ts: u64,
+    commit_ts: u64,
+    value: Value,
+    /// the other keys of the txn, if this key was its primary
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    secondaries: Vec<TxnKey>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Lock {
+    start_ts: u64,
+    primary: TxnKey,
+    /// unix ms, the lock may be resolved by others after that
+    expires: u64,
+    /// false if the txn only reads the key
+    write: bool,
+    /// the value the key gets on commit
+    value: Value,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    secondaries: Vec<TxnKey>,
+}
+
+impl Record {
+    fn latest(&self) -> Option<&Value> {
+        self.versions.last().map(|v| &v.value)
+    }
+
+    /// The value last committed before `ts`, `Err` if that version was
+    /// already dropped.
+    fn at(&self, ts: u64) -> Result<Option<&Value>, ()> {
+        match self.versions.iter().rev().find(|v| v.commit_ts < ts) {
+            Some(v) => Ok(Some(&v.value)),
+            // fewer versions than kept, none was dropped yet
+            None if self.versions.len() < VERSIONS => Ok(None),
+            None => Err(()),
+        }
+    }
+
+    fn unlocked(&self) -> Record {
+        Record {
+            lock: None,
+            ..self.clone()
+        }
+    }
+}
+
+/// A record as read from lin-kv, `raw` is what a CAS compares.
+struct Stored {
+    key: TxnKey,
+    raw: Value,
+    record: Record,
+}
+
+impl Stored {
+    fn new(key: &TxnKey, record: Record) -> Self {
+        Stored {
+            key: key.clone(),
+            raw: serde_json::to_value(&record).unwrap(),
+            record,
+        }
+    }
+}
+
+fn record_key(key: &TxnKey) -> String {
+    format!("key-{}", key.0.canonical())
+}
+
+fn conflict(text: String) -> ErrorExtra {
+    ErrorExtra {
+        code: TXN_CONFLICT,
+        text,
+    }
+}
+
+pub struct Percolator<K = Rc<RefCell<Node>>> {
+    kv: K,
+    lock_ttl: u64,
+}
+
+impl Percolator {
+    pub fn new(node: &Rc<RefCell<Node>>) -> Self {
+        let lock_ttl = node.borrow().config.txn_lock_ttl.as_millis() as u64;
+        Self {
+            kv: node.clone(),
+            lock_ttl,
+        }
+    }
+}
+
+impl<K: LinKv> Percolator<K> {
+    pub fn transact(&self, txn: &[MicroOp]) -> Result<Vec<MicroOp>, ErrorExtra> {
+        let mut keys: Vec<TxnKey> = Vec::new();
+        for op in txn {
+            if !keys.contains(op.key()) {
+                keys.push(op.key().clone());
+            }
+        }
+        let start_ts = self.timestamp();
+        let Some(primary) = txn.iter().find(|op| op.is_write()).map(MicroOp::key) else {
+            return self.read_only(start_ts, &keys, txn);
+        };
+
+        let stored = self.read_resolved(&keys, None)?;
+        let mut state: HashMap<TxnKey, Value> = stored
+            .iter()
+            .filter_map(|s| Some((s.key.clone(), s.record.latest()?.clone())))
+            .collect();
+        let results = apply(&mut state, txn)?;
+
+        // prewrite, the primary first
+        let written: HashSet<&TxnKey> = txn
+            .iter()
+            .filter(|op| op.is_write())
+            .map(MicroOp::key)
+            .collect();
+        let expires = now_ms() + self.lock_ttl;
+        let (primary, secondaries): (Vec<Stored>, Vec<Stored>) =
+            stored.into_iter().partition(|s| &s.key == primary);
+        let primary = primary.into_iter().next().unwrap();
+        let lock = |s: &Stored| {
+            let write = written.contains(&s.key);
+            let mut record = s.record.clone();
+            record.lock = Some(Lock {
+                start_ts,
+                primary: primary.key.clone(),
+                expires,
+                write,
+                value: if write {
+                    state[&s.key].clone()
+                } else {
+                    Value::Null
+                },
+                secondaries: if s.key == primary.key {
+                    secondaries.iter().map(|s| s.key.clone()).collect()
+                } else {
+                    Vec::new()
+                },
+            });
+            Stored::new(&s.key, record)
+        };
+        let locked_primary = lock(&primary);
+        if !self.cas(&primary, &locked_primary.record) {
+            return Err(conflict(format!(
+                "primary key {} changed before the prewrite",
+                primary.key
+            )));
+        }
+        let locked: Vec<Stored> = secondaries.iter().map(lock).collect();
+        let prewritten =
+            self.cas_many(secondaries.iter().zip(&locked).map(|(s, l)| (s, &l.record)));
+        if prewritten.contains(&false) {
+            let undo = secondaries
+                .iter()
+                .zip(&locked)
+                .zip(&prewritten)
+                .filter(|(_, &ok)| ok)
+                .map(|((s, l), _)| (l, &s.record))
+                .chain([(&locked_primary, &primary.record)]);
+            self.cas_many(undo);
+            return Err(conflict(format!(
+                "txn {start_ts} lost a key before the prewrite"
+            )));
+        }
+
+        let commit_ts = self.timestamp();
+        if !self.cas(&locked_primary, &self.committed(&locked_primary, commit_ts)) {
+            // an expired lock, resolved by another txn
+            let undo: Vec<Record> = locked.iter().map(|l| l.record.unlocked()).collect();
+            self.cas_many(locked.iter().zip(&undo));
+            return Err(conflict(format!(
+                "txn {start_ts} was rolled back before its commit"
+            )));
+        }
+        let done: Vec<Record> = locked
+            .iter()
+            .map(|l| self.committed(l, commit_ts))
+            .collect();
+        // a failed CAS means another txn rolled it forward already
+        self.cas_many(locked.iter().zip(&done));
+        Ok(results)
+    }
+
+    fn read_only(
+        &self,
+        start_ts: u64,
+        keys: &[TxnKey],
+        txn: &[MicroOp],
+    ) -> Result<Vec<MicroOp>, ErrorExtra> {
+        let mut state = HashMap::new();
+        for s in self.read_resolved(keys, Some(start_ts))? {
+            let value = s.record.at(start_ts).map_err(|_| {
+                conflict(format!(
+                    "key {} has no version before {start_ts} any more",
+                    s.key
+                ))
+            })?;
+            if let Some(value) = value {
+                state.insert(s.key.clone(), value.clone());
+            }
+        }
+        apply(&mut state, txn)
+    }
+
+    /// Reads the records of `keys` and resolves the expired locks in the
+    /// way. A lock that has not expired yet is a conflict. A read-only txn
+    /// passes its `start_ts`, only older write locks are in its way.
+    fn read_resolved(
+        &self,
+        keys: &[TxnKey],
+        start_ts: Option<u64>,
+    ) -> Result<Vec<Stored>, ErrorExtra> {
+        loop {
+            let stored = self.read(keys);
+            let in_the_way = |s: &&Stored| {
+                s.record.lock.as_ref().is_some_and(|lock| match start_ts {
+                    Some(ts) => lock.write && lock.start_ts < ts,
+                    None => true,
+                })
+            };
+            let locked: Vec<&Stored> = stored.iter().filter(in_the_way).collect();
+            if locked.is_empty() {
+                return Ok(stored);
+            }
+            let now = now_ms();
+            for s in &locked {
+                let lock = s.record.lock.as_ref().unwrap();
+                if lock.expires > now {
+                    return Err(conflict(format!(
+                        "key {} is locked by txn {}",
+                        s.key, lock.start_ts
+                    )));
+                }
+            }
+            for s in locked {
+                self.resolve(s);
+            }
+        }
+    }
+
+    /// Rolls the expired lock of `s` forward or back, depending on its
+    /// primary.
+    fn resolve(&self, s: &Stored) {
+        let lock = s.record.lock.as_ref().unwrap();
+        let commit_ts = if lock.primary == s.key {
+            // still locked, so it never committed
+            None
+        } else {
+            self.primary_commit_ts(lock)
+        };
+        eprintln!(
+            "resolve lock of txn {} on key {}: {}",
+            lock.start_ts,
+            s.key,
+            if commit_ts.is_some() {
+                "roll forward"
+            } else {
+                "roll back"
+            }
+        );
+        match commit_ts {
+            Some(commit_ts) => self.cas(s, &self.committed(s, commit_ts)),
+            None => self.cas(s, &s.record.unlocked()),
+        };
+    }
+
+    /// The commit timestamp of the txn holding the secondary `lock`, or
+    /// None once its primary is rolled back. A primary still locked is
+    /// rolled back first, unless its owner commits it meanwhile.
+    fn primary_commit_ts(&self, lock: &Lock) -> Option<u64> {
+        loop {
+            let primary = self
+                .read(std::slice::from_ref(&lock.primary))
+                .pop()
+                .unwrap();
+            match &primary.record.lock {
+                // all locks of a txn expire together
+                Some(l) if l.start_ts == lock.start_ts => {
+                    if self.cas(&primary, &primary.record.unlocked()) {
+                        return None;
+                    }
+                    // the owner committed or another txn resolved it first
+                }
+                _ => {
+                    return primary
+                        .record
+                        .versions
+                        .iter()
+                        .find(|v| v.start_ts == lock.start_ts)
+                        .map(|v| v.commit_ts)
+                }
+            }
+        }
+    }
+
+    /// The record of `s` with its lock committed at `commit_ts`. Before a
+    /// primary version is dropped, its secondaries are rolled forward.
+    fn committed(&self, s: &Stored, commit_ts: u64) -> Record {
+        let mut record = s.record.clone();
+        let lock = record.lock.take().unwrap();
+        if lock.write {
+            record.versions.push(Version {
+                start_ts: lock.start_ts,
+                commit_ts,
+                value: lock.value,
+                secondaries: lock.secondaries,
+            });
+        }
+        let dropped = record.versions.len().saturating_sub(VERSIONS);
+        for version in record.versions.drain(..dropped) {
+            if version.secondaries.is_empty() {
+                continue;
+            }
+            let pending: Vec<Stored> = self
+                .read(&version.secondaries)
+                .into_iter()
+                .filter(|s| {
+                    s.record
+                        .lock
+                        .as_ref()
+                        .is_some_and(|l| l.start_ts == version.start_ts)
+                })
+                .collect();
+            let done: Vec<Record> = pending
+                .iter()
+                .map(|s| self.committed(s, version.commit_ts))
+                .collect();
+            self.cas_many(pending.iter().zip(&done));
+        }
+        record
+    }
+
+    /// Next value of the timestamp oracle.
+    fn timestamp(&self) -> u64 {
+        loop {
+            let current = self.kv.read_many(&[TS_KEY.to_string()]).pop().unwrap();
+            let next = current.as_ref().and_then(Value::as_u64).unwrap_or(0) + 1;
+            let cas = (
+                TS_KEY.to_string(),
+                current.unwrap_or(Value::Null),
+                next.into(),
+            );
+            if self.kv.cas_many(vec![cas]).pop().unwrap().is_ok() {
+                return next;
+            }
+        }
+    }
+
+    fn read(&self, keys: &[TxnKey]) -> Vec<Stored> {
+        let ids: Vec<String> = keys.iter().map(record_key).collect();
+        keys.iter()
+            .zip(self.kv.read_many(&ids))
+            .map(|(key, raw)| match raw {
+                Some(raw) => Stored {
+                    key: key.clone(),
+                    record: serde_json::from_value(raw.clone()).expect("a txn key record"),
+                    raw,
+                },
+                None => Stored {
+                    key: key.clone(),
+                    raw: Value::Null,
+                    record: Record::default(),
+                },
+            })
+            .collect()
+    }
+
+    /// Replaces the record of `from` with `to`, false if it changed since.
+    fn cas(&self, from: &Stored, to: &Record) -> bool {
+        self.cas_many([(from, to)]).pop().unwrap()
+    }
+
+    fn cas_many<'a>(
+        &self,
+        changes: impl IntoIterator<Item = (&'a Stored, &'a Record)>,
+    ) -> Vec<bool> {
+        let changes = changes
+            .into_iter()
+            .map(|(from, to)| {
+                (
+                    record_key(&from.key),
+                    from.raw.clone(),
+                    serde_json::to_value(to).unwrap(),
+                )
+            })
+            .collect();
+        self.kv
+            .cas_many(changes)
+            .iter()
+            .map(Result::is_ok)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lin_kv::mem::MemKv;
+    use serde_json::json;
+
+    fn percolator() -> Percolator<MemKv> {
+        Percolator {
+            kv: MemKv::default(),
+            lock_ttl: 60_000,
+        }
+    }
+
+    fn key(k: u64) -> TxnKey {
+        TxnKey::new(json!(k))
+    }
+
+    fn txn(ops: Value) -> Vec<MicroOp> {
+        serde_json::from_value(ops).unwrap()
+    }
+
+    fn record(p: &Percolator<MemKv>, k: u64) -> Record {
+        p.read(&[key(k)]).pop().unwrap().record
+    }
+
+    fn store(p: &Percolator<MemKv>, k: u64, record: &Record) {
+        let raw = serde_json::to_value(record).unwrap();
+        p.kv.data.borrow_mut().insert(record_key(&key(k)), raw);
+    }
+
+    /// Txn 5 locked key 1, its primary, and key 2 to write 9, and its locks
+    /// expired.
+    fn abandoned_txn(p: &Percolator<MemKv>) -> Record {
+        let lock = |secondaries| Lock {
+            start_ts: 5,
+            primary: key(1),
+            expires: 0,
+            write: true,
+            value: json!(9),
+            secondaries,
+        };
+        let primary = Record {
+            versions: Vec::new(),
+            lock: Some(lock(vec![key(2)])),
+        };
+        store(p, 1, &primary);
+        store(
+            p,
+            2,
+            &Record {
+                versions: Vec::new(),
+                lock: Some(lock(Vec::new())),
+            },
+        );
+        primary
+    }
+
+    #[test]
+    fn commit_writes_a_version_of_every_key() {
+        let p = percolator();
+        let res = p
+            .transact(&txn(json!([["append", 1, 3], ["w", 2, 4]])))
+            .unwrap();
+        assert_eq!(res.len(), 2);
+
+        let primary = record(&p, 1);
+        assert!(primary.lock.is_none());
+        assert_eq!(primary.latest(), Some(&json!([3])));
+        assert_eq!(primary.versions[0].secondaries, vec![key(2)]);
+        let secondary = record(&p, 2);
+        assert!(secondary.lock.is_none());
+        assert_eq!(secondary.latest(), Some(&json!(4)));
+
+        let res = p
+            .transact(&txn(json!([["r", 1, null], ["r", 2, null]])))
+            .unwrap();
+        assert_eq!(res, txn(json!([["r", 1, [3]], ["r", 2, 4]])));
+    }
+
+    #[test]
+    fn an_abandoned_txn_is_rolled_back_and_can_not_commit() {
+        let p = percolator();
+        let primary = abandoned_txn(&p);
+        p.transact(&txn(json!([["append", 2, 7]]))).unwrap();
+
+        let rolled_back = record(&p, 1);
+        assert!(rolled_back.lock.is_none() && rolled_back.versions.is_empty());
+        assert_eq!(record(&p, 2).latest(), Some(&json!([7])));
+
+        // the owner's commit point fails, so it aborts
+        let locked = Stored::new(&key(1), primary);
+        let committed = p.committed(&locked, 6);
+        assert!(!p.cas(&locked, &committed));
+    }
+
+    #[test]
+    fn a_resolver_losing_to_the_owners_commit_rolls_forward() {
+        let p = percolator();
+        let primary = abandoned_txn(&p);
+        let committed = p.committed(&Stored::new(&key(1), primary), 6);
+        let raw = serde_json::to_value(&committed).unwrap();
+        // the owner commits between the resolver's read of the primary and
+        // its rollback
+        *p.kv.before_cas.borrow_mut() = Some(Box::new(move |data| {
+            data.insert(record_key(&key(1)), raw);
+        }));
+
+        let stored = p.read_resolved(&[key(2)], None).unwrap();
+        assert_eq!(stored[0].record.latest(), Some(&json!(9)));
+        assert_eq!(stored[0].record.versions[0].commit_ts, 6);
+        assert_eq!(record(&p, 1).latest(), Some(&json!(9)));
+    }
+
+    #[test]
+    fn a_read_only_txn_ignores_newer_locks() {
+        let p = percolator();
+        p.transact(&txn(json!([["w", 1, 1]]))).unwrap();
+        let mut locked = record(&p, 1);
+        locked.lock = Some(Lock {
+            start_ts: 100,
+            primary: key(1),
+            expires: u64::MAX,
+            write: true,
+            value: json!(2),
+            secondaries: Vec::new(),
+        });
+        store(&p, 1, &locked);
+        // started before the lock, reads the committed value
+        let res = p.transact(&txn(json!([["r", 1, null]]))).unwrap();
+        assert_eq!(res, txn(json!([["r", 1, 1]])));
+    }
+}
@@ -0,0 +1,270 @@
+//! Epidemic broadcast trees (Plumtree).
+//!
+//! Every node splits its peers into an *eager* set, which receives the full
+//! payload, and a *lazy* set, which only receives an `ihave` announcement
+//! with the message id. Receiving a payload twice means there is a cycle in
+//! the eager graph, so the second sender is demoted with `prune`. If an
+//! announced message does not show up before a timeout, the tree is broken
+//! somewhere and the announcer is promoted back with `graft`. The eager links
+//! converge to a spanning tree, which keeps the message overhead close to one
+//! message per node, while the lazy links repair the tree after a partition.
+//!
+//! reference:
+//!   * https://asc.di.fct.unl.pt/~jleitao/pdf/srds07-leitao.pdf
+use crate::lru_cache::LruCache;
+use crate::messages::*;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+/// How long to wait for an announced message before grafting.
+const GRAFT_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Payloads kept to answer grafts and to tell duplicates apart. A late
+/// duplicate of a forgotten one prunes its sender, a late announcement of
+/// one costs a graft, and either only happens long after the tree settled.
+const KEEP: usize = 10_000;
+
+pub type MessageId = u64;
+
+/// All nodes run the same binary, so the default hasher gives every node the
+/// same id for the same payload.
+pub fn message_id(value: &BroadcastValue) -> MessageId {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[derive(Debug)]
+pub struct Plumtree {
+    eager: HashSet<String>,
+    lazy: HashSet<String>,
+    // message id -> (payload, peer it was first received from)
+    received: LruCache<MessageId, (BroadcastValue, Option<String>)>,
+    // message id -> (peers that announced it, when to graft the next one)
+    missing: HashMap<MessageId, (VecDeque<String>, Instant)>,
+}
+
+impl Default for Plumtree {
+    fn default() -> Self {
+        Self::keeping(KEEP)
+    }
+}
+
+impl Plumtree {
+    fn keeping(keep: usize) -> Self {
+        Self {
+            eager: HashSet::new(),
+            lazy: HashSet::new(),
+            received: LruCache::new(keep),
+            missing: HashMap::new(),
+        }
+    }
+
+    /// Starts over with every topology neighbor as an eager peer.
+    pub fn set_peers(&mut self, id: &str, neighbors: &[String]) {
+        self.eager = neighbors.iter().filter(|n| *n != id).cloned().collect();
+        self.lazy.clear();
+        eprintln!("plumtree eager peers: {:?}", self.eager);
+    }
+
+    pub fn add_eager(&mut self, peer: &str) {
+        self.lazy.remove(peer);
+        self.eager.insert(peer.to_string());
+    }
+
+    pub fn add_lazy(&mut self, peer: &str) {
+        self.eager.remove(peer);
+        self.lazy.insert(peer.to_string());
+    }
+
+    /// Records the first delivery of a message and returns the
+    /// `(eager, lazy)` peers it should be pushed to.
+    pub fn deliver(
+        &mut self,
+        id: MessageId,
+        value: &BroadcastValue,
+        from: Option<&str>,
+    ) -> (Vec<String>, Vec<String>) {
+        self.missing.remove(&id);
+        self.received
+            .insert(id, (value.clone(), from.map(str::to_string)));
+        if let Some(peer) = from {
+            self.add_eager(peer);
+        }
+        let others = |peers: &HashSet<String>| {
+            peers
+                .iter()
+                .filter(|p| Some(p.as_str()) != from)
+                .cloned()
+                .collect()
+        };
+        (others(&self.eager), others(&self.lazy))
+    }
+
+    /// Handles a payload we already have. Returns true if `peer` should be
+    /// pruned: it is a second eager path to us. A resend from the peer we
+    /// first got the message from is just a retry and keeps the link.
+    pub fn duplicate(&mut self, id: MessageId, peer: &str) -> bool {
+        match self.received.get(&id) {
+            Some((_, Some(first))) if first == peer => false,
+            _ => {
+                self.add_lazy(peer);
+                true
+            }
+        }
+    }
+
+    /// Remembers announced messages we have not received yet.
+    pub fn announced(&mut self, ids: &[MessageId], peer: &str, now: Instant) {
+        for id in ids {
+            if self.received.get(id).is_some() {
+                continue;
+            }
+            self.missing
+                .entry(*id)
+                .or_insert_with(|| (VecDeque::new(), now + GRAFT_TIMEOUT))
+                .0
+                .push_back(peer.to_string());
+        }
+    }
+
+    /// Payloads a peer asked for with `graft`.
+    pub fn grafted(&mut self, ids: &[MessageId], peer: &str) -> Vec<BroadcastValue> {
+        self.add_eager(peer);
+        ids.iter()
+            .filter_map(|id| self.received.get(id).map(|(v, _)| v.clone()))
+            .collect()
+    }
+
+    /// Pops the announcers whose timer expired: `(peer, message ids)`.
+    /// Checked on every tick of the main loop.
+    pub fn expired(&mut self, now: Instant) -> HashMap<String, Vec<MessageId>> {
+        let mut grafts: HashMap<String, Vec<MessageId>> = HashMap::new();
+        self.missing.retain(|id, (announcers, deadline)| {
+            if *deadline > now {
+                return true;
+            }
+            match announcers.pop_front() {
+                Some(peer) => {
+                    grafts.entry(peer).or_default().push(*id);
+                    // give the next announcer a chance if this one fails too
+                    *deadline = now + GRAFT_TIMEOUT;
+                    true
+                }
+                None => false,
+            }
+        });
+        for peer in grafts.keys() {
+            let peer = peer.clone();
+            self.add_eager(&peer);
+        }
+        grafts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn peers(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    fn sorted(mut peers: Vec<String>) -> Vec<String> {
+        peers.sort();
+        peers
+    }
+
+    fn tree() -> Plumtree {
+        let mut tree = Plumtree::default();
+        tree.set_peers("n0", &peers(&["n0", "n1", "n2", "n3"]));
+        tree
+    }
+
+    #[test]
+    fn deliver_pushes_to_eager_peers_and_announces_to_lazy_ones() {
+        let mut tree = tree();
+        tree.add_lazy("n3");
+        let value = BroadcastValue::new(json!(1));
+        let (eager, lazy) = tree.deliver(message_id(&value), &value, Some("n1"));
+        assert_eq!(sorted(eager), peers(&["n2"]));
+        assert_eq!(lazy, peers(&["n3"]));
+        // a client broadcast goes to every eager peer
+        let value = BroadcastValue::new(json!(2));
+        let (eager, _) = tree.deliver(message_id(&value), &value, None);
+        assert_eq!(sorted(eager), peers(&["n1", "n2"]));
+    }
+
+    #[test]
+    fn duplicate_from_a_second_peer_prunes_it() {
+        let mut tree = tree();
+        let value = BroadcastValue::new(json!(1));
+        let id = message_id(&value);
+        tree.deliver(id, &value, Some("n1"));
+        // a retry of the first sender keeps the link
+        assert!(!tree.duplicate(id, "n1"));
+        assert!(tree.duplicate(id, "n2"));
+        let next = BroadcastValue::new(json!(2));
+        let (eager, lazy) = tree.deliver(message_id(&next), &next, None);
+        assert_eq!(sorted(eager), peers(&["n1", "n3"]));
+        assert_eq!(lazy, peers(&["n2"]));
+    }
+
+    #[test]
+    fn ihave_grafts_announcers_in_turn_after_the_timeout() {
+        let mut tree = tree();
+        tree.add_lazy("n2");
+        tree.add_lazy("n3");
+        let start = Instant::now();
+        tree.announced(&[7], "n2", start);
+        tree.announced(&[7], "n3", start);
+        assert!(tree.expired(start).is_empty());
+        let grafts = tree.expired(start + GRAFT_TIMEOUT);
+        assert_eq!(grafts, HashMap::from([("n2".to_string(), vec![7])]));
+        let grafts = tree.expired(start + 2 * GRAFT_TIMEOUT);
+        assert_eq!(grafts, HashMap::from([("n3".to_string(), vec![7])]));
+        assert!(tree.expired(start + 3 * GRAFT_TIMEOUT).is_empty());
+        // grafting made them eager again
+        assert!(tree.lazy.is_empty());
+    }
+
+    #[test]
+    fn ihave_for_a_received_message_is_ignored() {
+        let mut tree = tree();
+        let value = BroadcastValue::new(json!(1));
+        let id = message_id(&value);
+        let start = Instant::now();
+        tree.deliver(id, &value, Some("n1"));
+        tree.announced(&[id], "n2", start);
+        assert!(tree.expired(start + GRAFT_TIMEOUT).is_empty());
+        // and one that arrives before the timeout cancels the graft
+        tree.announced(&[8], "n2", start);
+        tree.deliver(8, &value, Some("n1"));
+        assert!(tree.expired(start + GRAFT_TIMEOUT).is_empty());
+    }
+
+    #[test]
+    fn graft_makes_the_peer_eager_and_resends() {
+        let mut tree = tree();
+        tree.add_lazy("n2");
+        let value = BroadcastValue::new(json!(1));
+        let id = message_id(&value);
+        tree.deliver(id, &value, None);
+        assert_eq!(tree.grafted(&[id, 99], "n2"), vec![value]);
+        assert!(tree.eager.contains("n2") && !tree.lazy.contains("n2"));
+    }
+
+    #[test]
+    fn received_keeps_only_recent_payloads() {
+        let mut tree = Plumtree::keeping(2);
+        let values: Vec<BroadcastValue> = (0..3).map(|v| BroadcastValue::new(json!(v))).collect();
+        for value in &values {
+            tree.deliver(message_id(value), value, None);
+        }
+        assert_eq!(tree.received.len(), 2);
+        assert!(tree.grafted(&[message_id(&values[0])], "n1").is_empty());
+    }
+}
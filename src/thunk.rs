@@ -1,9 +1,6 @@
-use crate::idgen::IdGen;
-use crate::messages::*;
-use crate::node::Node;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::marker::PhantomData;
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 #[serde(untagged)]
@@ -41,139 +38,229 @@ impl<T> LazyValue<T> {
     }
 }
 
+/// Turns values into what a `Store` holds and back.
+pub trait Codec<Raw> {
+    fn encode<T: Serialize>(&self, value: &T) -> Raw;
+    /// `None` if `raw` is not a `T`.
+    fn decode<T: DeserializeOwned>(&self, raw: Raw) -> Option<T>;
+}
+
+/// Where thunks are loaded from and saved to, e.g. `lin_kv::LinKvStore`.
+pub trait Store {
+    type Raw;
+    type Codec: Codec<Self::Raw>;
+
+    fn codec(&self) -> &Self::Codec;
+    /// `None` if there is nothing stored under `id`.
+    fn read(&self, id: &str) -> Option<Self::Raw>;
+    /// Reads all `ids`, a store may do it in one round trip.
+    fn read_many(&self, ids: &[String]) -> Vec<Option<Self::Raw>> {
+        ids.iter().map(|id| self.read(id)).collect()
+    }
+    fn write(&self, id: &str, raw: Self::Raw) -> bool;
+    /// A fresh id, never handed out before.
+    fn next_id(&self) -> String;
+}
+
+/// Stores values as JSON values.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec<serde_json::Value> for Json {
+    fn encode<T: Serialize>(&self, value: &T) -> serde_json::Value {
+        serde_json::to_value(value).unwrap()
+    }
+
+    fn decode<T: DeserializeOwned>(&self, raw: serde_json::Value) -> Option<T> {
+        serde_json::from_value(raw).ok()
+    }
+}
+
+/// Stores values as JSON text inside a JSON string, opaque to the store.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonString;
+
+impl Codec<serde_json::Value> for JsonString {
+    fn encode<T: Serialize>(&self, value: &T) -> serde_json::Value {
+        serde_json::Value::String(serde_json::to_string(value).unwrap())
+    }
+
+    fn decode<T: DeserializeOwned>(&self, raw: serde_json::Value) -> Option<T> {
+        serde_json::from_str(raw.as_str()?).ok()
+    }
+}
+
 /// A Thunk is essentially a lazy loading mechanism for storing
-/// and retrieving values.
-#[derive(Debug, Clone, Default)]
-pub struct Thunk<T> {
-    // shared references to Node
-    pub node: Rc<RefCell<Node>>,
-    pub id: RefCell<LazyValue<String>>,
-    pub value: RefCell<LazyValue<T>>,
-    pub dirty: RefCell<bool>,
-}
-
-impl<T: Serialize> Serialize for Thunk<T> {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+/// and retrieving values. It only knows its id, the store it lives in is
+/// passed to `value` and `save`, so a thunk deserialized from its id is
+/// complete and can sit in any collection.
+pub struct Thunk<T, S: Store> {
+    /// `None` until the thunk gets a value
+    pub id: Option<String>,
+    pub value: LazyValue<T>,
+    pub dirty: bool,
+    store: PhantomData<fn() -> S>,
+}
+
+impl<T: std::fmt::Debug, S: Store> std::fmt::Debug for Thunk<T, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Thunk")
+            .field("id", &self.id)
+            .field("value", &self.value)
+            .field("dirty", &self.dirty)
+            .finish()
+    }
+}
+
+impl<T: Clone, S: Store> Clone for Thunk<T, S> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id.clone(),
+            value: self.value.clone(),
+            dirty: self.dirty,
+            store: PhantomData,
+        }
+    }
+}
+
+impl<T, S: Store> Default for Thunk<T, S> {
+    fn default() -> Self {
+        Self {
+            id: None,
+            value: LazyValue::UnLoaded,
+            dirty: false,
+            store: PhantomData,
+        }
+    }
+}
+
+impl<T, S: Store> Serialize for Thunk<T, S> {
+    fn serialize<Ser>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error>
     where
-        S: serde::Serializer,
+        Ser: serde::Serializer,
     {
-        self.id.borrow().serialize(serializer)
+        self.id.serialize(serializer)
     }
 }
 
-impl<'de, T: Deserialize<'de>> Deserialize<'de> for Thunk<T> {
+impl<'de, T, S: Store> Deserialize<'de> for Thunk<T, S> {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        Ok(Thunk {
-            node: Default::default(), // should be set by the caller
-            id: RefCell::new(LazyValue::Loaded(String::deserialize(deserializer)?)),
-            value: RefCell::new(LazyValue::UnLoaded),
-            dirty: RefCell::new(false),
-        })
+        Ok(Thunk::with_id(String::deserialize(deserializer)?))
     }
 }
 
-impl<T: Clone + Default + Serialize + for<'de> Deserialize<'de>> Thunk<T> {
-    const SVC: &'static str = "lin-kv";
-
-    pub fn new(node: Rc<RefCell<Node>>) -> Self {
+impl<T, S: Store> Thunk<T, S> {
+    /// A stored thunk, loaded on first use.
+    pub fn with_id(id: String) -> Self {
         Self {
-            node,
-            id: RefCell::new(LazyValue::UnLoaded),
-            value: RefCell::new(LazyValue::UnLoaded),
-            dirty: RefCell::new(false),
+            id: Some(id),
+            ..Default::default()
         }
     }
 
-    pub fn id(&self) -> Option<String> {
-        let mut id_ref = self.id.borrow_mut();
-        if matches!(*id_ref, LazyValue::UnLoaded) {
-            *id_ref = LazyValue::Loaded(IdGen::new(&self.node.borrow()).next_id());
+    pub fn dirty(&self) -> bool {
+        self.dirty
+    }
+}
+
+impl<T: Serialize + DeserializeOwned, S: Store> Thunk<T, S> {
+    pub fn value(&mut self, store: &S) -> Option<&T> {
+        let id = self.id.as_deref()?;
+        self.value
+            .get_or_insert_with(|| decode(store, id, store.read(id)))
+    }
+
+    /// Loads all `thunks` that are not loaded yet with one `read_many`.
+    pub fn load_many<'a>(thunks: impl IntoIterator<Item = &'a mut Self>, store: &S)
+    where
+        T: 'a,
+        S: 'a,
+    {
+        let pending: Vec<&mut Self> = thunks
+            .into_iter()
+            .filter(|t| t.id.is_some() && matches!(t.value, LazyValue::UnLoaded))
+            .collect();
+        let ids: Vec<String> = pending.iter().filter_map(|t| t.id.clone()).collect();
+        for ((thunk, id), raw) in pending.into_iter().zip(&ids).zip(store.read_many(&ids)) {
+            thunk.value = decode(store, id, raw);
         }
-        id_ref.value().cloned()
     }
 
-    pub fn value(&self) -> Option<T> {
-        let id = self.id();
-        if id.is_none() {
-            return None;
+    /// Replaces the value. A stored value is never changed, so the thunk
+    /// gets a new id unless it has not been saved since the last change.
+    pub fn set_value(&mut self, store: &S, value: T) {
+        if !self.dirty {
+            self.id = Some(store.next_id());
         }
-        let node = self.node.clone();
-        self.value.borrow_mut().get_or_insert_with(|| {
-            let res = node.borrow_mut().sync_rpc(
-                Self::SVC,
-                MessageExtra::KvRead(KvReadExtra { key: id.into() }),
-            );
-            match res {
-                MessageExtra::KvReadOk(p) => {
-                    LazyValue::Loaded(serde_json::from_value(p.value).unwrap())
-                }
-                MessageExtra::Error(_) => LazyValue::LoadFailed,
-                _ => {
-                    eprintln!("wrong message type, {res:?}");
-                    LazyValue::LoadFailed
-                }
-            }
-        }).cloned()
-    }
-
-    pub fn set_value(&mut self, value: T) {
-        if !*self.dirty.borrow() {
-            self.id = RefCell::new(LazyValue::UnLoaded);
-            let _ = self.id();
+        self.value = LazyValue::Loaded(value);
+        self.dirty = true;
+    }
+
+    /// Changes the loaded value in place, `merge_fn` returns whether it
+    /// changed anything. False if there is no loaded value.
+    pub fn merge(&mut self, store: &S, merge_fn: impl FnOnce(&mut T) -> bool) -> bool {
+        let Some(v) = self.value.value_mut() else {
+            return false;
+        };
+        if merge_fn(v) && !self.dirty {
+            self.dirty = true;
+            self.id = Some(store.next_id());
         }
-        *self.value.borrow_mut() = LazyValue::Loaded(value);
-        *self.dirty.borrow_mut() = true;
+        true
     }
 
-    pub fn dirty(&self) -> bool {
-        *self.dirty.borrow()
-    }
-
-    pub fn merge(&mut self, merge_fn: impl FnOnce(&mut T) -> bool) -> bool {
-        let mut value = self.value.borrow_mut();
-        if let Some(v) = value.value_mut() {
-            if merge_fn(v) {
-                *self.dirty.borrow_mut() = true;
-                // update id
-                self.id = RefCell::new(LazyValue::UnLoaded);
-                self.id();
-            }
-            true
-        } else {
-            false
+    /// Writes the value if it changed since it was loaded or saved. False
+    /// if it could not be written.
+    pub fn save(&mut self, store: &S) -> bool {
+        if !self.dirty {
+            return true;
+        }
+        let (Some(id), Some(value)) = (&self.id, self.value.value()) else {
+            return false;
+        };
+        if store.write(id, store.codec().encode(value)) {
+            self.dirty = false;
         }
+        !self.dirty
     }
+}
 
-    pub fn save(&self) -> Result<(), MessageExtra> {
-        if self.dirty() {
-            let id = self.id();
-            let value = self.to_json();
-            let res = self.node.borrow_mut().sync_rpc(
-                Self::SVC,
-                MessageExtra::KvWrite(KvWriteExtra {
-                    key: id.into(),
-                    value,
-                }),
-            );
-            if matches!(res, MessageExtra::KvWriteOk) {
-                *self.dirty.borrow_mut() = false;
-                Ok(())
-            } else {
-                Err(res)
-            }
-        } else {
-            Ok(())
+fn decode<T: DeserializeOwned, S: Store>(store: &S, id: &str, raw: Option<S::Raw>) -> LazyValue<T> {
+    let Some(raw) = raw else {
+        return LazyValue::LoadFailed;
+    };
+    match store.codec().decode(raw) {
+        Some(value) => LazyValue::Loaded(value),
+        None => {
+            eprintln!("thunk {id} does not hold the expected type");
+            LazyValue::LoadFailed
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
 
-    pub fn to_json(&self) -> serde_json::Value {
-        serde_json::to_value(self.value()).unwrap()
+    #[test]
+    fn codecs_round_trip() {
+        let value = vec![(1, "a".to_string()), (2, "b".to_string())];
+        let raw = Json.encode(&value);
+        assert_eq!(raw, json!([[1, "a"], [2, "b"]]));
+        assert_eq!(Json.decode::<Vec<(u64, String)>>(raw), Some(value.clone()));
+        let raw = JsonString.encode(&value);
+        assert_eq!(raw, json!(r#"[[1,"a"],[2,"b"]]"#));
+        assert_eq!(JsonString.decode::<Vec<(u64, String)>>(raw), Some(value));
     }
 
-    pub fn from_json(json: serde_json::Value) -> T {
-        serde_json::from_value(json).unwrap()
+    #[test]
+    fn decoding_the_wrong_type_fails() {
+        assert_eq!(Json.decode::<u64>(json!("x")), None);
+        assert_eq!(JsonString.decode::<u64>(json!([1])), None);
+        assert_eq!(JsonString.decode::<u64>(json!("[1]")), None);
     }
 }
@@ -0,0 +1,429 @@
+//! Total-order broadcast.
+//!
+//! Every node delivers broadcast values in the same order. The order is a
+//! log of slots `tob-0`, `tob-1`, ... and each slot is decided by lin-kv:
+//! a value is written with `cas from=v to=v create_if_not_exists=true`,
+//! which succeeds only if the slot is empty or already holds `v`. So lin-kv
+//! is the consensus and two nodes can never disagree on a slot.
+//!
+//! To keep contention low, only the sequencer claims slots. Other nodes
+//! forward their values to it. The sequencer is `node_ids[0]` at epoch 0;
+//! when a forwarded value stays undelivered for too long, a node takes over
+//! by CAS-ing the `tob-leader` key from `[epoch, leader]` to
+//! `[epoch + 1, itself]` and announces the new epoch to everyone. A deposed
+//! sequencer that still claims slots is harmless, its claims either win a
+//! slot and are delivered normally, or lose and are forwarded again.
+//!
+//! A sequencer disseminates a slot only after winning it, so a slot that
+//! was won but not yet received leaves a gap and nodes hold back later
+//! slots. Usually the owner's retries fill it in. When the gap is still
+//! there after `GAP_TIMEOUT`, e.g. because the owner crashed between its
+//! claim and the dissemination, the node reads the slot from lin-kv itself.
+//! A slot that was never written is filled with the first held back value,
+//! which is then delivered twice and only counts the first time.
+use crate::messages::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+const LEADER_KEY: &str = "tob-leader";
+
+/// How long a forwarded value may stay undelivered before electing a new
+/// sequencer.
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// How long to wait for lin-kv to answer a slot claim before resending it.
+const CLAIM_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long later slots may be held back before reading the missing one.
+const GAP_TIMEOUT: Duration = Duration::from_millis(500);
+
+fn slot_key(seq: u64) -> String {
+    format!("tob-{seq}")
+}
+
+/// What to do after lin-kv answered a slot claim.
+pub enum ClaimOutcome {
+    /// The slot is ours, disseminate it.
+    Committed(u64, BroadcastValue),
+    /// The slot was taken, claim the value again with `claim`.
+    Retry(BroadcastValue),
+    /// lin-kv failed for another reason, claim the same slot again.
+    Resend(u64, BroadcastValue),
+    /// We are no longer the sequencer, forward the value to it.
+    Forward(String, BroadcastValue),
+    Unknown,
+}
+
+/// What lin-kv said about the slot missing from the holdback.
+pub enum GapOutcome {
+    /// The slot holds this value.
+    Decided(u64, BroadcastValue),
+    /// Nobody wrote the slot, fill it with `fill`.
+    Empty(u64),
+    /// Try again at the next timeout.
+    Failed,
+    Unknown,
+}
+
+/// The first undelivered slot, while later ones are held back.
+#[derive(Debug)]
+struct Gap {
+    seq: u64,
+    // when to request the slot (again)
+    deadline: Instant,
+    // msg_id of the read or fill in flight, with the value it fills in
+    request: Option<(u64, Option<BroadcastValue>)>,
+}
+
+#[derive(Debug, Default)]
+pub struct TotalOrder {
+    id: String,
+    node_ids: Vec<String>,
+    epoch: u64,
+    leader: String,
+    // values broadcast to this node that are not delivered yet
+    pending: HashMap<BroadcastValue, Instant>,
+    // msg_id of the leader cas in flight
+    election: Option<u64>,
+    // the next slot this node would claim as sequencer
+    next_seq: u64,
+    // msg_id of a slot cas -> (slot, value, sent at)
+    claims: HashMap<u64, (u64, BroadcastValue, Instant)>,
+    in_flight: HashSet<BroadcastValue>,
+    // slots received out of order
+    holdback: BTreeMap<u64, BroadcastValue>,
+    next_deliver: u64,
+    gap: Option<Gap>,
+}
+
+impl TotalOrder {
+    pub fn init(&mut self, id: &str, node_ids: &[String]) {
+        if !self.id.is_empty() {
+            return;
+        }
+        self.id = id.to_string();
+        self.node_ids = node_ids.to_vec();
+        self.node_ids.sort();
+        self.leader = self.node_ids.first().cloned().unwrap_or_default();
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leader == self.id
+    }
+
+    pub fn leader(&self) -> &str {
+        &self.leader
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// All nodes but this one.
+    pub fn peers(&self) -> Vec<String> {
+        self.node_ids
+            .iter()
+            .filter(|n| **n != self.id)
+            .cloned()
+            .collect()
+    }
+
+    /// Remembers a value broadcast to this node until it is delivered.
+    pub fn submit(&mut self, value: &BroadcastValue) {
+        self.pending
+            .entry(value.clone())
+            .or_insert_with(Instant::now);
+    }
+
+    /// Whether the sequencer should claim a slot for `value`.
+    pub fn should_claim(&self, value: &BroadcastValue) -> bool {
+        !self.in_flight.contains(value)
+    }
+
+    /// Claims the next free slot for `value` with the request `msg_id`.
+    pub fn claim(&mut self, msg_id: u64, value: &BroadcastValue) -> KvCasData {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.claim_slot(msg_id, seq, value)
+    }
+
+    pub fn claim_slot(&mut self, msg_id: u64, seq: u64, value: &BroadcastValue) -> KvCasData {
+        self.claims
+            .insert(msg_id, (seq, value.clone(), Instant::now()));
+        self.in_flight.insert(value.clone());
+        KvCasData {
+            key: slot_key(seq).into(),
+            from: value.0.value().clone(),
+            to: value.0.value().clone(),
+            create_if_not_exists: true,
+        }
+    }
+
+    /// `error` is the lin-kv error code, if any.
+    pub fn claim_result(&mut self, msg_id: u64, error: Option<u64>) -> ClaimOutcome {
+        let Some((seq, value, _)) = self.claims.remove(&msg_id) else {
+            return ClaimOutcome::Unknown;
+        };
+        match error {
+            None => {
+                self.in_flight.remove(&value);
+                return ClaimOutcome::Committed(seq, value);
+            }
+            // the slot holds another value
+            Some(PRECONDITION_FAILED) => {}
+            Some(_) => return ClaimOutcome::Resend(seq, value),
+        }
+        eprintln!("slot {seq} is taken");
+        self.next_seq = self.next_seq.max(seq + 1);
+        if self.is_leader() {
+            ClaimOutcome::Retry(value)
+        } else {
+            self.in_flight.remove(&value);
+            ClaimOutcome::Forward(self.leader.clone(), value)
+        }
+    }
+
+    /// Handles the answer to our leader cas. On success returns the values
+    /// this node has to sequence now.
+    pub fn election_result(&mut self, msg_id: u64, ok: bool) -> Option<Vec<BroadcastValue>> {
+        if self.election != Some(msg_id) {
+            return None;
+        }
+        self.election = None;
+        let now = Instant::now();
+        if !ok {
+            // someone else won, wait for its announcement
+            self.pending.values_mut().for_each(|t| *t = now);
+            return Some(Vec::new());
+        }
+        self.epoch += 1;
+        self.leader = self.id.clone();
+        eprintln!("became sequencer at epoch {}", self.epoch);
+        Some(self.pending.keys().cloned().collect())
+    }
+
+    /// Handles an announcement of a new sequencer. Returns the values to
+    /// forward to it, or None if the announcement is stale.
+    pub fn new_leader(&mut self, epoch: u64, leader: &str) -> Option<Vec<BroadcastValue>> {
+        if epoch <= self.epoch {
+            return None;
+        }
+        self.epoch = epoch;
+        self.leader = leader.to_string();
+        let now = Instant::now();
+        self.pending.values_mut().for_each(|t| *t = now);
+        Some(self.pending.keys().cloned().collect())
+    }
+
+    /// Accepts a decided slot and returns the values that can now be
+    /// delivered in order.
+    pub fn receive(&mut self, seq: u64, value: BroadcastValue) -> Vec<BroadcastValue> {
+        self.next_seq = self.next_seq.max(seq + 1);
+        if seq >= self.next_deliver {
+            self.holdback.insert(seq, value);
+        }
+        let mut ready = Vec::new();
+        while let Some(value) = self.holdback.remove(&self.next_deliver) {
+            self.pending.remove(&value);
+            ready.push(value);
+            self.next_deliver += 1;
+        }
+        ready
+    }
+
+    /// The leader cas to take over as sequencer with the request `msg_id`,
+    /// if one is due.
+    pub fn elect(&mut self, msg_id: u64, now: Instant) -> Option<KvCasData> {
+        if self.id.is_empty() || self.is_leader() || self.election.is_some() {
+            return None;
+        }
+        if !self
+            .pending
+            .values()
+            .any(|t| now.duration_since(*t) > ELECTION_TIMEOUT)
+        {
+            return None;
+        }
+        self.election = Some(msg_id);
+        Some(KvCasData {
+            key: LEADER_KEY.into(),
+            from: serde_json::json!([self.epoch, self.leader]),
+            to: serde_json::json!([self.epoch + 1, self.id]),
+            create_if_not_exists: true,
+        })
+    }
+
+    /// Pops the slot claims lin-kv did not answer in time, to be sent again.
+    /// The same slot is claimed again, since the first claim may have won it.
+    pub fn expired_claims(&mut self, now: Instant) -> Vec<(u64, BroadcastValue)> {
+        let mut expired = Vec::new();
+        self.claims.retain(|_, (seq, value, sent)| {
+            if now.duration_since(*sent) <= CLAIM_TIMEOUT {
+                return true;
+            }
+            expired.push((*seq, value.clone()));
+            false
+        });
+        expired
+    }
+
+    /// The key to read for the slot missing from the holdback with the request
+    /// `msg_id`, once it has been missing for `GAP_TIMEOUT`.
+    pub fn read_gap(&mut self, msg_id: u64, now: Instant) -> Option<serde_json::Value> {
+        if self.holdback.is_empty() {
+            self.gap = None;
+            return None;
+        }
+        let seq = self.next_deliver;
+        let gap = match &mut self.gap {
+            Some(gap) if gap.seq == seq => gap,
+            _ => self.gap.insert(Gap {
+                seq,
+                deadline: now + GAP_TIMEOUT,
+                request: None,
+            }),
+        };
+        if gap.deadline > now {
+            return None;
+        }
+        eprintln!("slot {seq} is missing, read it");
+        // a lost reply is replaced by the next request
+        gap.deadline = now + GAP_TIMEOUT;
+        gap.request = Some((msg_id, None));
+        Some(slot_key(seq).into())
+    }
+
+    /// Fills the empty missing slot with the first held back value, with the
+    /// request `msg_id`.
+    pub fn fill_gap(&mut self, msg_id: u64) -> Option<KvCasData> {
+        let value = self.holdback.values().next()?.clone();
+        let gap = self.gap.as_mut()?;
+        gap.request = Some((msg_id, Some(value.clone())));
+        Some(KvCasData {
+            key: slot_key(gap.seq).into(),
+            from: value.0.value().clone(),
+            to: value.0.into_value(),
+            create_if_not_exists: true,
+        })
+    }
+
+    /// Handles the answer to a read or fill of the missing slot.
+    pub fn gap_result(&mut self, msg_id: u64, reply: &MessageExtra) -> GapOutcome {
+        let Some(gap) = self.gap.as_mut() else {
+            return GapOutcome::Unknown;
+        };
+        let Some((_, fill)) = gap.request.take_if(|(id, _)| *id == msg_id) else {
+            return GapOutcome::Unknown;
+        };
+        let seq = gap.seq;
+        let decided = match (reply.kv_read_value(), reply, fill) {
+            (Some(value), _, None) => BroadcastValue::new(value.clone()),
+            (_, MessageExtra::KvCasOk, Some(value)) => value,
+            (_, MessageExtra::Error(err), None) if err.code == KEY_DOES_NOT_EXIST => {
+                return GapOutcome::Empty(seq);
+            }
+            // e.g. the fill lost against the owner's claim, read it next time
+            _ => return GapOutcome::Failed,
+        };
+        // the slots after one nobody sent out are likely missing too, so
+        // the next one is read right away if it does not show up
+        self.gap = Some(Gap {
+            seq: seq + 1,
+            deadline: Instant::now(),
+            request: None,
+        });
+        GapOutcome::Decided(seq, decided)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn value(v: u64) -> BroadcastValue {
+        BroadcastValue::new(json!(v))
+    }
+
+    fn sequencer() -> TotalOrder {
+        let mut tob = TotalOrder::default();
+        tob.init("n0", &["n0".to_string(), "n1".to_string()]);
+        tob
+    }
+
+    #[test]
+    fn unanswered_claims_are_sent_again_for_the_same_slot() {
+        let mut tob = sequencer();
+        tob.claim(1, &value(7));
+        let later = Instant::now() + CLAIM_TIMEOUT * 2;
+        assert_eq!(tob.expired_claims(later).len(), 1);
+        assert!(tob.expired_claims(later).is_empty());
+        assert!(matches!(tob.claim_result(1, None), ClaimOutcome::Unknown));
+
+        let cas = tob.claim_slot(2, 0, &value(7));
+        assert_eq!(cas.key, json!("tob-0"));
+        assert!(matches!(
+            tob.claim_result(2, None),
+            ClaimOutcome::Committed(0, _)
+        ));
+    }
+
+    #[test]
+    fn a_missing_slot_is_read_after_the_timeout() {
+        let mut tob = sequencer();
+        assert!(tob.receive(1, value(11)).is_empty());
+        let now = Instant::now();
+        assert_eq!(tob.read_gap(1, now), None);
+        assert_eq!(tob.read_gap(2, now + GAP_TIMEOUT * 2), Some(json!("tob-0")));
+
+        // a lin-kv read_ok, whichever variant it parses into
+        let reply: MessageExtra =
+            serde_json::from_value(json!({"type": "read_ok", "value": 10})).unwrap();
+        let GapOutcome::Decided(seq, decided) = tob.gap_result(2, &reply) else {
+            panic!("slot 0 is decided");
+        };
+        assert_eq!(tob.receive(seq, decided), vec![value(10), value(11)]);
+        assert_eq!(tob.read_gap(3, Instant::now()), None);
+    }
+
+    #[test]
+    fn an_empty_missing_slot_is_filled_with_a_held_back_value() {
+        let mut tob = sequencer();
+        tob.receive(1, value(11));
+        let now = Instant::now();
+        assert_eq!(tob.read_gap(0, now), None);
+        let later = now + GAP_TIMEOUT * 2;
+        assert!(tob.read_gap(1, later).is_some());
+        let missing = MessageExtra::Error(ErrorExtra {
+            code: KEY_DOES_NOT_EXIST,
+            text: "not found".to_string(),
+        });
+        assert!(matches!(tob.gap_result(1, &missing), GapOutcome::Empty(0)));
+
+        let cas = tob.fill_gap(2).unwrap();
+        assert_eq!((cas.key, cas.to), (json!("tob-0"), json!(11)));
+        let GapOutcome::Decided(seq, decided) = tob.gap_result(2, &MessageExtra::KvCasOk) else {
+            panic!("slot 0 is filled");
+        };
+        // delivered twice, `deliver_in_order` keeps the first one
+        assert_eq!(tob.receive(seq, decided), vec![value(11), value(11)]);
+    }
+
+    #[test]
+    fn a_lost_fill_is_read_again() {
+        let mut tob = sequencer();
+        tob.receive(1, value(11));
+        let now = Instant::now();
+        assert_eq!(tob.read_gap(0, now), None);
+        let later = now + GAP_TIMEOUT * 2;
+        tob.read_gap(1, later);
+        tob.fill_gap(2);
+        let taken = MessageExtra::Error(ErrorExtra {
+            code: PRECONDITION_FAILED,
+            text: "mismatch".to_string(),
+        });
+        assert!(matches!(tob.gap_result(2, &taken), GapOutcome::Failed));
+        assert_eq!(tob.read_gap(3, later), None);
+        assert!(tob.read_gap(4, later + GAP_TIMEOUT * 2).is_some());
+    }
+}
@@ -1,10 +1,14 @@
 //! read and write to lin-kv
+use crate::chunk_list::Chunk;
+use crate::config::ThunkCodec;
 use crate::messages::*;
 use crate::node::Node;
-use crate::thunk::{LazyValue, Thunk};
+use crate::lin_kv::LinKvStore;
+use crate::thunk::{Codec, Json, JsonString, LazyValue, Thunk};
+use crate::txn_engine::apply;
 use serde_json::json;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 
 const DB_KEY: &str = "ROOT";
@@ -13,87 +17,109 @@ pub struct Transactor {
     node: Rc<RefCell<Node>>,
 }
 
+type ChunkThunk<C> = Thunk<Chunk, LinKvStore<C>>;
+
 #[derive(Debug, Clone)]
-pub struct Database {
-    // Thunk will use its own id to get the value, i.e. HaspMap<String, Thunk<Vec<usize>>>,
-    // from lin-kv store which should response with {"k1": "id1", "k2": "id2"}.
+pub struct Database<C: Codec<serde_json::Value> = Json> {
+    // Thunk will use its own id to get the value, i.e. KeyMap<Thunk<Value>>,
+    // from lin-kv store which should response with [["k1", "id1"], ["k2", "id2"]].
     // data flow:
     // root -> id(database pointer)
-    // id -> value({"k1": "v1", "k2": "v2"}, should be deserialized to HashMap<String, Thunk<Vec<usize>>>)
+    // id -> value([["k1", "v1"], ["k2", "v2"]], should be deserialized to KeyMap<Thunk<Value>>)
     // v1 is an ID of inner thunk
-    // v1 -> value([1,2,3])
-    inner: Thunk<HashMap<usize, Thunk<Vec<usize>>>>,
-    node: Rc<RefCell<Node>>,
+    // v1 -> chunk({"value": [1,2,3]}), a list or a written value, or a list
+    // segment, see chunk_list.rs
+    inner: Thunk<KeyMap<ChunkThunk<C>>, LinKvStore<C>>,
+    store: LinKvStore<C>,
+    max_segments: usize,
 }
 
-impl Database {
-    pub fn transact(&mut self, txns: &[Query]) -> Vec<Query> {
-        let mut results = Vec::new();
-        let mut new_map: HashMap<usize, Thunk<Vec<usize>>> = HashMap::new();
+impl<C: Codec<serde_json::Value>> Database<C> {
+    pub fn transact(&mut self, txns: &[MicroOp]) -> Result<Vec<MicroOp>, ErrorExtra> {
+        let mut new_map: KeyMap<ChunkThunk<C>> = KeyMap::new();
 
         // new db contains only the keys we need for the txns
         // instead of the whole database keys
-        let om = self.inner.value();
+        let om = self.inner.value(&self.store);
         eprintln!("inner value: {:#?}", om);
         if let Some(om) = om {
             for txn in txns {
-                let v = om.get(&txn.1);
-                if let Some(v) = v {
-                    let mut v = v.clone();
-                    v.node = self.node.clone();
-                    new_map.insert(txn.1, v);
+                if let Some(v) = om.get(txn.key()) {
+                    new_map.insert(txn.key().clone(), v.clone());
                 }
             }
             eprintln!("new map initialized: {:#?}", new_map);
         }
 
-        for txn in txns {
-            let Query(op, key, value) = txn;
-            eprintln!("op: {op}, key: {key}, value: {value:?}");
-            match op.as_str() {
-                "r" => {
-                    let old_values = {
-                        let old_thunk = new_map.get(key);
-                        match old_thunk {
-                            Some(t) => t.value(),
-                            None => None,
-                        }
-                    };
-                    results.push(Query(op.clone(), *key, QueryValue::Read(old_values)));
-                }
-                "append" => {
-                    results.push(txn.clone());
-
-                    let value = match value {
-                        QueryValue::Append(v) => *v,
-                        QueryValue::Read(_) => panic!("wrong value for 'append' operation"),
-                    };
-                    let thunk = new_map.entry(*key).or_insert_with(|| Thunk {
-                        node: self.node.clone(),
-                        id: RefCell::new(LazyValue::UnLoaded),
-                        value: RefCell::new(LazyValue::Loaded(vec![])),
-                        dirty: RefCell::new(false),
-                    });
-                    // update thunk
-                    let mut old_values = thunk.value().unwrap_or(vec![]);
-                    old_values.push(value);
-                    thunk.set_value(old_values);
-                    eprintln!("thunk2: {:#?}", thunk);
-                }
-                _ => unimplemented!(),
-            }
+        // load the inner thunks, then the list segments they link to, and
+        // run the txn on their values
+        Thunk::load_many(new_map.values_mut(), &self.store);
+        let mut segments: Vec<ChunkThunk<C>> = new_map
+            .values()
+            .filter_map(|thunk| thunk.value.value())
+            .flat_map(|head| head.prev().iter().cloned())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .map(Thunk::with_id)
+            .collect();
+        Thunk::load_many(segments.iter_mut(), &self.store);
+        let segments: HashMap<String, Chunk> = segments
+            .into_iter()
+            .filter_map(|thunk| match thunk.value {
+                LazyValue::Loaded(chunk) => Some((thunk.id?, chunk)),
+                _ => None,
+            })
+            .collect();
+        // a key of the root whose chunks did not load must not read as
+        // missing, e.g. garbage collected under a stale root
+        let mut state: HashMap<TxnKey, serde_json::Value> = HashMap::new();
+        for (k, thunk) in new_map.iter() {
+            let value = thunk.value.value().and_then(|head| {
+                let chain: Option<Vec<Chunk>> =
+                    head.prev().iter().map(|id| segments.get(id).cloned()).collect();
+                Some(head.assemble(&chain?))
+            });
+            let Some(value) = value else {
+                return Err(ErrorExtra {
+                    code: TXN_CONFLICT,
+                    text: format!("chunks of key {k:?} are gone"),
+                });
+            };
+            state.insert(k.clone(), value);
+        }
+        let old_state = state.clone();
+        let results = apply(&mut state, txns)?;
+
+        let write_keys: HashSet<&TxnKey> = txns
+            .iter()
+            .filter(|op| op.is_write())
+            .map(MicroOp::key)
+            .collect();
+        for key in write_keys {
+            let thunk = new_map.entry(key.clone()).or_default();
+            // update thunk, a grown list only gets a new segment
+            let head = thunk.id.clone().zip(thunk.value.value().cloned());
+            let old = old_state.get(key).unwrap_or(&serde_json::Value::Null);
+            let chunk = Chunk::next(
+                head.as_ref().map(|(id, chunk)| (id, chunk)),
+                old,
+                &state[key],
+                self.max_segments,
+            );
+            thunk.set_value(&self.store, chunk);
+            eprintln!("thunk2: {:#?}", thunk);
         }
 
         self.merge(new_map);
 
-        results
+        Ok(results)
     }
 
-    pub fn merge(&mut self, new_map: HashMap<usize, Thunk<Vec<usize>>>) {
+    pub fn merge(&mut self, new_map: KeyMap<ChunkThunk<C>>) {
         eprintln!("merging new map: {:#?}", new_map);
         eprintln!("self inner: {:#?}", self.inner);
         let new_map2 = new_map.clone();
-        let merged = self.inner.merge(|old| {
+        let merged = self.inner.merge(&self.store, |old| {
             let mut changed = false;
             for (k, v) in new_map2 {
                 if v.dirty() {
@@ -105,48 +131,42 @@ impl Database {
             changed
         });
         if !merged {
-            self.inner.set_value(new_map);
+            self.inner.set_value(&self.store, new_map);
         }
         eprintln!("updated db: {:#?}", self.inner);
     }
 
-    pub fn from_json_value(node: Rc<RefCell<Node>>, json: serde_json::Value) -> Self {
+    pub fn from_json_value(store: LinKvStore<C>, json: serde_json::Value, max_segments: usize) -> Self {
         let root_id = serde_json::from_value(json);
         eprintln!("root id: {root_id:?}");
         let inner = match root_id {
-            Ok(id) => Thunk {
-                node: node.clone(),
-                id: RefCell::new(LazyValue::Loaded(id)),
-                // this will be from json { "k1": "id1", "k2": "id2" }
-                value: RefCell::new(LazyValue::UnLoaded),
-                dirty: RefCell::new(false),
-            },
-            Err(_) => Thunk {
-                node: node.clone(),
-                id: RefCell::new(LazyValue::LoadFailed),
-                value: RefCell::new(LazyValue::LoadFailed),
-                dirty: RefCell::new(false),
-            },
+            Ok(id) => Thunk::with_id(id),
+            // no database yet
+            Err(_) => Thunk::default(),
         };
 
-        Database { inner, node }
+        Database {
+            inner,
+            store,
+            max_segments,
+        }
     }
 
     pub fn to_json_value(&self) -> serde_json::Value {
-        let inner = self.inner.id.clone();
-        serde_json::to_value(&inner).unwrap()
+        serde_json::to_value(&self.inner).unwrap()
     }
 
     pub fn save(&mut self) {
-        let m = self.inner.value();
-        if let Some(m) = m {
-            m.iter().for_each(|(_, v)| {
-                eprintln!("saving inner thunk: {:?}", v);
-                let _ = v.save();
-            });
-            eprintln!("saving outer thunk: {:?}", self.inner);
-            let _ = self.inner.save();
-        }
+        let store = &self.store;
+        let Some(m) = self.inner.value.value_mut() else {
+            return;
+        };
+        m.iter_mut().for_each(|(_, v)| {
+            eprintln!("saving inner thunk: {:?}", v);
+            v.save(store);
+        });
+        eprintln!("saving outer thunk: {:?}", self.inner);
+        self.inner.save(store);
     }
 }
 
@@ -167,7 +187,7 @@ impl Transactor {
         match res {
             MessageExtra::KvReadOk(v) => v.value,
             MessageExtra::Error(err) => {
-                if err.code == 20 {
+                if err.code == KEY_DOES_NOT_EXIST {
                     serde_json::Value::Null
                 } else {
                     panic!("wrong response for lin-kv read")
@@ -199,16 +219,30 @@ impl Transactor {
     }
 
     /// perform a list of read and write operations
-    pub fn transact(&mut self, txns: &[Query]) -> Result<Vec<Query>, ErrorExtra> {
+    pub fn transact(&mut self, txns: &[MicroOp]) -> Result<Vec<MicroOp>, ErrorExtra> {
+        let codec = self.node.borrow().config.thunk_codec;
+        match codec {
+            ThunkCodec::Json => self.transact_with(Json, txns),
+            ThunkCodec::JsonString => self.transact_with(JsonString, txns),
+        }
+    }
+
+    fn transact_with<C: Codec<serde_json::Value>>(
+        &mut self,
+        codec: C,
+        txns: &[MicroOp],
+    ) -> Result<Vec<MicroOp>, ErrorExtra> {
         let db_key = json!(DB_KEY);
         // Load the current value from lin-kv
         let id1 = self.kv_read(&db_key);
         eprintln!("root id: {id1:?}");
-        let mut current_db = Database::from_json_value(self.node.clone(), id1);
+        let store = LinKvStore::new(&self.node, codec);
+        let max_segments = self.node.borrow().config.list_segments;
+        let mut current_db = Database::from_json_value(store, id1, max_segments);
         eprintln!("current db status: {:#?}", current_db.inner);
         let old_id = current_db.to_json_value();
         // Apply txn
-        let txns = current_db.transact(txns);
+        let txns = current_db.transact(txns)?;
         eprintln!("next db status: {:#?}", current_db.inner);
         let new_id = current_db.to_json_value();
 
@@ -221,7 +255,10 @@ impl Transactor {
         match self.kv_cas(&db_key, &old_id, &new_id) {
             MessageExtra::KvCasOk => Ok(txns),
             // if cas fails, tell the client
-            MessageExtra::Error(err) => Err(err),
+            MessageExtra::Error(err) => Err(ErrorExtra {
+                code: TXN_CONFLICT,
+                text: format!("root altered: {}", err.text),
+            }),
             _ => panic!("wrong response for lin-kv cas"),
         }
     }
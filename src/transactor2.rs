@@ -1,186 +1,457 @@
+use crate::btree::PersistentTree;
+use crate::chunk_list::Chunk;
+use crate::config::IndexKind;
+use crate::gc::{is_tombstone, TOMBSTONE};
+use crate::lin_kv;
 use crate::messages::*;
 use crate::node::Node;
-use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use crate::partitioning::{new_partitioner, plan_merges, PartInfo, Partition, Partitioner, Parts};
+use crate::txn_engine::apply;
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::rc::Rc;
 
-const SVC: &str = "lin-kv";
-
-// range partition for keys
-// root key: ["n0-1", "n0-2", "n1-1", "n1-2"]
-// "n0-1": [1 "n0-3", 2 "n1-3" 7 "n2-3"]
-// "n0-2": [12 "n0-8" 13 "n1-8" 14 "n2-8"]
-// "n1-1": [21 "n0-3", 22 "n1-3" 27 "n2-3"]
+// partitioned keys, see partitioning.rs for what a partition key is
+// root key: {"version": 7, "parts": {"0": {"id": "part-0-n0-1", ...}, "1": ...}}
+// "part-0-n0-1": {1: "n0-3", 2: "n1-3", 7: "n2-3"}
+// "part-1-n0-2": {21: "n0-8", 22: "n1-8", 27: "n2-8"}
+// "n0-3": {"value": [1, 2, 3]}, the value of a key, a list or a written
+// value, or a list segment, see chunk_list.rs
 const DB_PARTITION_KEY: &str = "ROOT";
+// b-tree index, see btree.rs
+// tree root key: {"version": 7, "root": "tree-n0-9"}
+const DB_TREE_KEY: &str = "TREE_ROOT";
+
+/// Maps keys to the ids of their chunks. A txn loads the index, stages the
+/// new mappings and publishes them with a single CAS.
+trait KeyIndex {
+    /// Prefetches whatever is needed to look up `keys`.
+    fn load(&mut self, keys: &[TxnKey]);
+    fn get(&mut self, key: &TxnKey) -> Option<String>;
+    /// Stages `saved: [(key, chunk_id)]`, returning the objects that must be
+    /// written before the commit.
+    fn stage(&mut self, saved: &HashMap<TxnKey, String>) -> Vec<(String, serde_json::Value)>;
+    /// The loaded root as stored, kept as the predecessor of the next one.
+    fn snapshot(&self) -> serde_json::Value;
+    /// Publishes the staged root, `prev` is the id of the snapshot. The
+    /// error is the lin-kv error code of the root CAS.
+    fn commit(&mut self, prev: String) -> Result<(), u64>;
+    /// Checks that the loaded root is still current without changing it,
+    /// the commit of a read-only batch.
+    fn fence(&mut self) -> bool;
+    /// Version of the loaded root.
+    fn version(&self) -> u64;
+    /// True if an object of the loaded root was garbage collected.
+    fn stale(&self) -> bool;
+    /// Stored objects the staged index no longer references.
+    fn superseded(&mut self) -> Vec<String>;
+}
+
+fn load_index(node: &Rc<RefCell<Node>>) -> Box<dyn KeyIndex> {
+    let (value, from_memory) = read_root(node, root_key(node));
+    index_at(node, value, from_memory)
+}
+
+/// The index of the root `value`, the current one or a snapshot.
+fn index_at(node: &Rc<RefCell<Node>>, value: Option<serde_json::Value>, from_memory: bool) -> Box<dyn KeyIndex> {
+    let kind = node.borrow().config.index;
+    match kind {
+        IndexKind::Partitions => Box::new(Root::from_value(node, value, from_memory)),
+        IndexKind::BTree => Box::new(TreeIndex::from_value(node, value, from_memory)),
+    }
+}
+
+fn root_key(node: &Rc<RefCell<Node>>) -> &'static str {
+    match node.borrow().config.index {
+        IndexKind::Partitions => DB_PARTITION_KEY,
+        IndexKind::BTree => DB_TREE_KEY,
+    }
+}
+
+/// The fields every kind of root has, to walk the history.
+#[derive(Deserialize, Debug, Default)]
+struct RootLink {
+    version: u64,
+    prev: Option<String>,
+}
+
+/// The root value at `key` and whether it came from memory. The txn
+/// leader uses the root it committed last instead, see `txn_leader.rs`.
+fn read_root(node: &Rc<RefCell<Node>>, key: &str) -> (Option<serde_json::Value>, bool) {
+    let cached = {
+        let node = node.borrow();
+        node.txn_leader.cached_root(&node.id, key)
+    };
+    if cached.is_some() {
+        eprintln!("root {key} from the leader's memory");
+        return (cached, true);
+    }
+    (lin_kv::read_many(node, &[key.to_string()]).pop().unwrap(), false)
+}
+
+/// Read fence for `root`, the value loaded from `key`. A root read from
+/// lin-kv was current at that read, which is all a read-only txn needs.
+/// One from memory may be outdated, it is checked by a CAS onto itself.
+fn fence_root(node: &Rc<RefCell<Node>>, key: &str, root: &impl Serialize, from_memory: bool) -> bool {
+    if !from_memory {
+        return true;
+    }
+    let root = serde_json::to_value(root).unwrap();
+    cas_root(node, key, root.clone(), root).is_ok()
+}
+
+/// CAS `key` from `from` to `to`, `PRECONDITION_FAILED` if someone else got
+/// there first.
+fn cas_root(
+    node: &Rc<RefCell<Node>>,
+    key: &str,
+    from: serde_json::Value,
+    to: serde_json::Value,
+) -> Result<(), u64> {
+    let res = lin_kv::cas(node, key, from, to.clone());
+    node.borrow_mut()
+        .txn_leader
+        .remember_root(key, res.is_ok().then_some(to));
+    res
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+struct RootValue {
+    // incremented by every commit
+    version: u64,
+    parts: Parts,
+    // snapshot of the previous root
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prev: Option<String>,
+}
 
 struct Root {
     node: Rc<RefCell<Node>>,
-    // [(0, "n0-1"), (1, "n0-2"), (2, "n1-1"), (3, "n1-2")]
-    // (partition_key, partition_id)
-    part_keys: HashMap<usize, String>,
+    partitioner: Box<dyn Partitioner>,
+    value: RootValue,
     // (partition_id, (key, chunk_id))
-    parts: RefCell<HashMap<String, HashMap<usize, String>>>,
+    parts: RefCell<HashMap<String, Partition>>,
+    staged: Option<RootValue>,
+    stale: Cell<bool>,
+    // the root came from the leader's memory, not from lin-kv
+    from_memory: bool,
 }
 
 impl Root {
-    fn load(node: &Rc<RefCell<Node>>) -> Self {
-        let res = node.borrow_mut().sync_rpc(
-            SVC,
-            MessageExtra::KvRead(KvReadExtra {
-                key: DB_PARTITION_KEY.into(),
-            }),
-        );
-
-        let part_keys = match res {
-            MessageExtra::KvReadOk(v) => serde_json::from_value(v.value).unwrap(),
-            MessageExtra::Error(err) => {
-                if err.code == 20 {
-                    HashMap::new()
-                } else {
-                    panic!("wrong response for lin-kv read")
-                }
-            }
-            _ => panic!("wrong response for lin-kv read"),
-        };
-        eprintln!("root partition keys: {part_keys:#?}");
+    fn from_value(node: &Rc<RefCell<Node>>, value: Option<serde_json::Value>, from_memory: bool) -> Self {
+        let value: RootValue = value
+            .map(|v| serde_json::from_value(v).unwrap())
+            .unwrap_or_default();
+        eprintln!("root: {value:#?}");
 
         Root {
             node: node.clone(),
-            part_keys,
+            partitioner: new_partitioner(&node.borrow().config),
+            value,
             parts: RefCell::new(HashMap::new()),
+            staged: None,
+            stale: Cell::new(false),
+            from_memory,
         }
     }
 
-    fn load_partition(&self, key: &str) -> HashMap<usize, String> {
-        let res = self
-            .node
-            .borrow_mut()
-            .sync_rpc(SVC, MessageExtra::KvRead(KvReadExtra { key: key.into() }));
-
-        match res {
-            MessageExtra::KvReadOk(v) => serde_json::from_value(v.value).unwrap(),
-            MessageExtra::Error(err) => {
-                if err.code == 20 {
-                    HashMap::new()
-                } else {
-                    panic!("wrong response for lin-kv read")
+    /// Loads every partition holding one of `keys`.
+    fn load_partitions<'a>(&self, keys: impl Iterator<Item = &'a TxnKey>) {
+        let ids: HashSet<String> = keys
+            .filter_map(|k| self.value.parts.get(&self.part_key(k)))
+            .map(|info| info.id.clone())
+            .collect();
+        self.load_partition_ids(ids);
+    }
+
+    /// Loads partitions from the node's cache or else from lin-kv in a
+    /// single round trip.
+    fn load_partition_ids(&self, ids: HashSet<String>) {
+        let mut parts = self.parts.borrow_mut();
+        let mut missed = Vec::new();
+        {
+            let mut node = self.node.borrow_mut();
+            for id in ids {
+                if parts.contains_key(&id) {
+                    continue;
+                }
+                match node.partition_cache.get(&id) {
+                    Some(partition) => {
+                        parts.insert(id, partition.clone());
+                    }
+                    None => missed.push(id),
                 }
             }
-            _ => panic!("wrong response for lin-kv read"),
+        }
+        let values = lin_kv::read_many(&self.node, &missed);
+        let mut node = self.node.borrow_mut();
+        for (id, value) in missed.into_iter().zip(values) {
+            if value.as_ref().is_some_and(is_tombstone) {
+                self.stale.set(true);
+                parts.insert(id, Partition::new());
+                continue;
+            }
+            let partition: Partition = value
+                .map(|v| serde_json::from_value(v).unwrap())
+                .unwrap_or_default();
+            node.partition_cache.insert(id.clone(), partition.clone());
+            parts.insert(id, partition);
         }
     }
 
-    fn save_partition(&self, key: &str, value: &HashMap<usize, String>) {
-        eprintln!("save partiton: {key}, {value:#?}");
-        let res = self.node.borrow_mut().sync_rpc(
-            SVC,
-            MessageExtra::KvWrite(KvWriteExtra {
-                key: key.into(),
-                value: serde_json::to_value(value.clone()).unwrap(),
-            }),
-        );
-        if !matches!(res, MessageExtra::KvWriteOk) {
-            unreachable!("the write error is not expected");
-        }
+    /// The content of the partition at `partition_key`, empty if there is
+    /// none yet.
+    fn partition(&self, partition_key: usize) -> Partition {
+        let Some(info) = self.value.parts.get(&partition_key) else {
+            return Partition::new();
+        };
+        self.load_partition_ids(HashSet::from([info.id.clone()]));
+        self.parts.borrow()[&info.id].clone()
     }
 
     fn next_part_id(&self, partition_key: &usize) -> String {
-        static mut NEXT_ID: usize = 0;
-        unsafe {
-            NEXT_ID += 1;
-        }
-        let id = unsafe { NEXT_ID };
-        format!("part-{}-{}-{}", partition_key, self.node.borrow().id, id)
+        let node = self.node.borrow();
+        node.ids.next_id(&format!("part-{}-{}", partition_key, node.id))
     }
 
-    fn part_key(&self, key: &usize) -> usize {
-        // this function set the size of a partition.
-        key / 20
+    fn part_key(&self, key: &TxnKey) -> usize {
+        // the partitioner sets the size of a partition.
+        self.partitioner.part_key(&self.value.parts, key)
     }
 
-    /// saved: [(key, chunk_id)]
-    fn save(&self, saved: &HashMap<usize, String>) -> bool {
+    /// Builds the new partitions for `saved: [(key, chunk_id)]`.
+    /// Returns the new root and the partitions that have to be written
+    /// before it is committed.
+    fn stage_parts(
+        &self,
+        saved: &HashMap<TxnKey, String>,
+    ) -> (RootValue, HashMap<String, Partition>) {
         eprintln!("save {saved:#?}");
-        let mut new_part_keys = self.part_keys.clone();
-        eprintln!("new part keys: {new_part_keys:#?}");
+        let version = self.value.version + 1;
+        let mut new_root = RootValue {
+            version,
+            parts: self.value.parts.clone(),
+            prev: None,
+        };
 
         // which partition has changed?
-        let mut new_parts: HashMap<String, HashMap<usize, String>> = HashMap::new();
+        let mut touched: BTreeMap<usize, Partition> = BTreeMap::new();
         saved.iter().for_each(|(k, v)| {
             let partition_key = self.part_key(k);
-            match new_part_keys.get(&partition_key) {
-                Some(pid) => {
-                    if new_parts.contains_key(pid) {
-                        let part = new_parts.get_mut(pid).unwrap();
-                        part.insert(*k, v.to_string());
-                    } else {
-                        let new_pid = self.next_part_id(&partition_key);
-                        let mut part = self.parts.borrow().get(pid).cloned().unwrap_or_default();
-
-                        // update the partition id
-                        new_part_keys.insert(partition_key, new_pid.clone());
-
-                        // update the partition
-                        part.insert(*k, v.to_string());
-                        new_parts.insert(new_pid, part);
-                    }
-                }
-                None => {
-                    // we generate a new partition id
-                    let new_pid = self.next_part_id(&partition_key);
-                    // and update the partition id
-                    let old_pid = new_part_keys.insert(partition_key, new_pid.clone());
-                    let mut new_values = old_pid
-                        .map(|pid| self.parts.borrow().get(&pid).cloned().unwrap_or_default())
-                        .unwrap_or_default();
-                    new_values.insert(*k, v.to_string());
-                    new_parts.insert(new_pid, new_values);
+            touched
+                .entry(partition_key)
+                .or_insert_with(|| self.partition(partition_key))
+                .insert(k.clone(), v.to_string());
+        });
+
+        // a rewritten partition absorbs a cold neighbor
+        let touched_keys: Vec<usize> = touched.keys().copied().collect();
+        let merges = plan_merges(self.partitioner.as_ref(), &self.value.parts, &touched_keys, version);
+        for (pk, other) in merges {
+            eprintln!("merge partition {other} into {pk}");
+            let mut part = touched.remove(&pk).unwrap();
+            part.extend(self.partition(other));
+            new_root.parts.remove(&pk);
+            new_root.parts.remove(&other);
+            touched.insert(pk.min(other), part);
+        }
+
+        // and a partition that grew too large is split in two
+        let mut new_parts: HashMap<String, Partition> = HashMap::new();
+        for (pk, part) in touched {
+            let pieces = match self.partitioner.split_at(pk, &part) {
+                Some(mid) => {
+                    eprintln!("split partition {pk} at {mid}");
+                    let (high, low): (Partition, Partition) =
+                        part.into_iter().partition(|(k, _)| k.position() >= mid);
+                    vec![(pk, low), (mid, high)]
                 }
+                None => vec![(pk, part)],
+            };
+            for (pk, part) in pieces {
+                let pid = self.next_part_id(&pk);
+                new_root.parts.insert(
+                    pk,
+                    PartInfo {
+                        id: pid.clone(),
+                        size: part.len(),
+                        last_write: version,
+                    },
+                );
+                new_parts.insert(pid, part);
             }
-        });
+        }
 
-        new_parts.iter().for_each(|(pid, values)| {
-            self.save_partition(pid, values);
-        });
+        (new_root, new_parts)
+    }
 
-        let from = self.part_keys.clone();
-
-        let res = self.node.borrow_mut().sync_rpc(
-            SVC,
-            MessageExtra::KvCas(KvCasData {
-                key: DB_PARTITION_KEY.into(),
-                from: serde_json::to_value(from).unwrap(),
-                to: serde_json::to_value(new_part_keys).unwrap(),
-                create_if_not_exists: true,
-            }),
-        );
-        match res {
-            MessageExtra::KvCasOk => true,
-            // if cas fails, tell the client
-            MessageExtra::Error(_) => false,
-            _ => panic!("wrong response for lin-kv cas"),
+    fn lookup(&self, key: &TxnKey) -> Option<String> {
+        let info = self.value.parts.get(&self.part_key(key))?;
+        if !self.parts.borrow().contains_key(&info.id) {
+            self.load_partition_ids(HashSet::from([info.id.clone()]));
         }
+        self.parts
+            .borrow()
+            .get(&info.id)
+            .and_then(|part| part.get(key).cloned())
     }
+}
 
-    fn get(&self, key: &usize) -> Option<String> {
-        let idx = self.part_key(key);
-        self.part_keys.get(&idx).and_then(|id| {
-            let mut parts_ref = self.parts.borrow_mut();
-            match parts_ref.get(id) {
-                Some(part) => part.get(&key).cloned(),
-                None => {
-                    let partition = self.load_partition(&id);
-                    eprintln!("partition: {partition:#?}");
-                    let res = partition.get(key).cloned();
-                    parts_ref.insert(id.to_string(), partition);
-                    res
-                }
-            }
-        })
+impl KeyIndex for Root {
+    fn load(&mut self, keys: &[TxnKey]) {
+        self.load_partitions(keys.iter());
+    }
+
+    fn get(&mut self, key: &TxnKey) -> Option<String> {
+        self.lookup(key)
+    }
+
+    fn stage(&mut self, saved: &HashMap<TxnKey, String>) -> Vec<(String, serde_json::Value)> {
+        let (new_root, new_parts) = self.stage_parts(saved);
+        self.staged = Some(new_root);
+        let mut node = self.node.borrow_mut();
+        new_parts
+            .into_iter()
+            .map(|(pid, values)| {
+                eprintln!("save partiton: {pid}, {values:#?}");
+                let value = serde_json::to_value(&values).unwrap();
+                node.partition_cache.insert(pid.clone(), values);
+                (pid, value)
+            })
+            .collect()
+    }
+
+    fn snapshot(&self) -> serde_json::Value {
+        serde_json::to_value(&self.value).unwrap()
+    }
+
+    /// CAS the root from the one we loaded to the staged one.
+    fn commit(&mut self, prev: String) -> Result<(), u64> {
+        let mut new_root = self.staged.take().expect("nothing staged");
+        new_root.prev = Some(prev);
+        cas_root(
+            &self.node,
+            DB_PARTITION_KEY,
+            serde_json::to_value(&self.value).unwrap(),
+            serde_json::to_value(new_root).unwrap(),
+        )
+    }
+
+    fn fence(&mut self) -> bool {
+        fence_root(&self.node, DB_PARTITION_KEY, &self.value, self.from_memory)
+    }
+
+    fn version(&self) -> u64 {
+        self.value.version
     }
 
-    fn contains_key(&self, key: &usize) -> bool {
-        self.get(key).is_some()
+    fn stale(&self) -> bool {
+        self.stale.get()
+    }
+
+    fn superseded(&mut self) -> Vec<String> {
+        let Some(staged) = &self.staged else {
+            return Vec::new();
+        };
+        let kept: HashSet<&String> = staged.parts.values().map(|info| &info.id).collect();
+        self.value
+            .parts
+            .values()
+            .map(|info| &info.id)
+            .filter(|id| !kept.contains(id))
+            .cloned()
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+struct TreeRoot {
+    // incremented by every commit
+    version: u64,
+    root: Option<String>,
+    // snapshot of the previous root
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prev: Option<String>,
+}
+
+/// Key index kept in a persistent b-tree, a commit rewrites only the tree
+/// nodes on the paths to the written keys.
+struct TreeIndex {
+    node: Rc<RefCell<Node>>,
+    value: TreeRoot,
+    tree: PersistentTree<TxnKey, String>,
+    from_memory: bool,
+}
+
+impl TreeIndex {
+    fn from_value(node: &Rc<RefCell<Node>>, value: Option<serde_json::Value>, from_memory: bool) -> Self {
+        let value: TreeRoot = value
+            .map(|v| serde_json::from_value(v).unwrap())
+            .unwrap_or_default();
+        eprintln!("tree root: {value:?}");
+        let order = node.borrow().config.btree_order;
+        TreeIndex {
+            node: node.clone(),
+            tree: PersistentTree::new(node, value.root.clone(), order),
+            value,
+            from_memory,
+        }
+    }
+}
+
+impl KeyIndex for TreeIndex {
+    fn load(&mut self, keys: &[TxnKey]) {
+        self.tree.load_paths(keys);
+    }
+
+    fn get(&mut self, key: &TxnKey) -> Option<String> {
+        self.tree.get(key)
+    }
+
+    fn stage(&mut self, saved: &HashMap<TxnKey, String>) -> Vec<(String, serde_json::Value)> {
+        eprintln!("save {saved:#?}");
+        let mut keys: Vec<TxnKey> = saved.keys().cloned().collect();
+        keys.sort();
+        self.tree.load_paths(&keys);
+        for k in keys {
+            self.tree.insert(k.clone(), saved[&k].clone());
+        }
+        self.tree.take_writes()
+    }
+
+    fn snapshot(&self) -> serde_json::Value {
+        serde_json::to_value(&self.value).unwrap()
+    }
+
+    fn commit(&mut self, prev: String) -> Result<(), u64> {
+        let new_root = TreeRoot {
+            version: self.value.version + 1,
+            root: self.tree.root().cloned(),
+            prev: Some(prev),
+        };
+        cas_root(
+            &self.node,
+            DB_TREE_KEY,
+            serde_json::to_value(&self.value).unwrap(),
+            serde_json::to_value(new_root).unwrap(),
+        )
+    }
+
+    fn fence(&mut self) -> bool {
+        fence_root(&self.node, DB_TREE_KEY, &self.value, self.from_memory)
+    }
+
+    fn version(&self) -> u64 {
+        self.value.version
+    }
+
+    fn stale(&self) -> bool {
+        self.tree.stale()
+    }
+
+    fn superseded(&mut self) -> Vec<String> {
+        self.tree.take_replaced()
     }
 }
 
@@ -193,125 +464,374 @@ impl Transactor {
         Self { node: node.clone() }
     }
 
-    pub fn load_chunk(&self, chunk_id: &str) -> Vec<usize> {
-        let res = self.node.borrow_mut().sync_rpc(
-            SVC,
-            MessageExtra::KvRead(KvReadExtra {
-                key: chunk_id.into(),
-            }),
-        );
-
-        match res {
-            MessageExtra::KvReadOk(v) => serde_json::from_value(v.value).unwrap(),
-            MessageExtra::Error(err) => {
-                if err.code == 20 {
-                    Vec::new()
-                } else {
-                    panic!("wrong response for lin-kv read")
+    /// Loads all chunks, from the node's cache or else from lin-kv in a
+    /// single round trip. `None` if one of them was garbage collected.
+    pub fn load_chunks(&self, chunk_ids: &[String]) -> Option<Vec<Chunk>> {
+        let mut chunks: Vec<Option<Chunk>> = {
+            let mut node = self.node.borrow_mut();
+            chunk_ids
+                .iter()
+                .map(|id| node.chunk_cache.get(id).cloned())
+                .collect()
+        };
+        let missed: Vec<String> = chunk_ids
+            .iter()
+            .zip(&chunks)
+            .filter(|(_, chunk)| chunk.is_none())
+            .map(|(id, _)| id.clone())
+            .collect();
+        eprintln!("chunk cache hits: {}", chunk_ids.len() - missed.len());
+
+        let mut values = lin_kv::read_many(&self.node, &missed).into_iter();
+        let mut node = self.node.borrow_mut();
+        for (id, chunk) in chunk_ids.iter().zip(chunks.iter_mut()) {
+            if chunk.is_none() {
+                let value = values.next().unwrap();
+                if value.as_ref().is_some_and(is_tombstone) {
+                    return None;
                 }
+                let value = value
+                    .map(|v| serde_json::from_value(v).unwrap())
+                    .unwrap_or(Chunk::Value(serde_json::Value::Null));
+                node.chunk_cache.insert(id.clone(), value.clone());
+                *chunk = Some(value);
             }
-            _ => panic!("wrong response for lin-kv read"),
         }
+        Some(chunks.into_iter().map(Option::unwrap).collect())
     }
 
-    pub fn save_chunk(&self, chunk_id: &str, values: &[usize]) {
-        let res = self.node.borrow_mut().sync_rpc(
-            SVC,
-            MessageExtra::KvWrite(KvWriteExtra {
-                key: chunk_id.into(),
-                value: serde_json::to_value(values).unwrap(),
-            }),
-        );
-        if !matches!(res, MessageExtra::KvWriteOk) {
-            unreachable!("the write error is not expected");
-        }
+    /// Loads the values stored in the chunks `heads`: the heads first, then
+    /// all list segments they link to at once. `None` if a chunk was garbage
+    /// collected.
+    pub fn load_values(&self, heads: &[String]) -> Option<Vec<(Chunk, serde_json::Value)>> {
+        let heads = self.load_chunks(heads)?;
+        let prev_ids: Vec<String> = heads
+            .iter()
+            .flat_map(|head| head.prev().iter().cloned())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        let prev: HashMap<String, Chunk> = prev_ids
+            .iter()
+            .cloned()
+            .zip(self.load_chunks(&prev_ids)?)
+            .collect();
+        let values = heads
+            .into_iter()
+            .map(|head| {
+                let chain: Vec<Chunk> = head.prev().iter().map(|id| prev[id].clone()).collect();
+                let value = head.assemble(&chain);
+                (head, value)
+            })
+            .collect();
+        Some(values)
     }
 
-    pub fn next_id(&self) -> usize {
-        static mut NEXT_ID: usize = 0;
-        unsafe {
-            NEXT_ID += 1;
+    /// Tombstones for the objects that are now safe to collect, evicted
+    /// from the caches as well.
+    fn collect_garbage(&self, current: u64) -> Vec<(String, serde_json::Value)> {
+        let mut node = self.node.borrow_mut();
+        let ids = node.gc.collect(current);
+        if !ids.is_empty() {
+            eprintln!("gc: {} objects, {} pending", ids.len(), node.gc.pending());
         }
-        unsafe { NEXT_ID }
+        ids.into_iter()
+            .map(|id| {
+                node.chunk_cache.remove(&id);
+                node.partition_cache.remove(&id);
+                node.tree_cache.remove(&id);
+                (id, serde_json::Value::from(TOMBSTONE))
+            })
+            .collect()
     }
 
     pub fn new_thunk_id(&self) -> String {
-        format!("{}-{}", self.node.borrow().id, self.next_id())
+        let node = self.node.borrow();
+        node.ids.next_id(&node.id)
     }
 
-    pub fn transact(&mut self, txns: &[Query]) -> Result<Vec<Query>, ErrorExtra> {
-        let keys = { txns.iter().map(|txn| txn.1).collect::<HashSet<_>>() };
+    pub fn transact(&mut self, txns: &[MicroOp]) -> Result<Vec<MicroOp>, ErrorExtra> {
+        self.transact_batch(&[txns.to_vec()]).pop().unwrap()
+    }
+
+    /// Reads `keys` as of commit `version`, following the snapshots back
+    /// from the current root. The chain is a linked list, so this is
+    /// O(versions): one round trip per commit since `version`, then the
+    /// index and the values as for a read.
+    pub fn read_at(&mut self, version: u64, keys: &[TxnKey]) -> Result<KeyMap<serde_json::Value>, ErrorExtra> {
+        let key = root_key(&self.node);
+        let mut value = lin_kv::read_many(&self.node, &[key.to_string()]).pop().unwrap();
+        loop {
+            let link: RootLink = value
+                .clone()
+                .map(|v| serde_json::from_value(v).unwrap())
+                .unwrap_or_default();
+            if link.version == version {
+                break;
+            }
+            if link.version < version {
+                return Err(no_version(format!(
+                    "version {version} is not committed yet, the latest is {}",
+                    link.version
+                )));
+            }
+            let gone = || no_version(format!("version {version} is no longer retained"));
+            let prev = link.prev.ok_or_else(gone)?;
+            value = lin_kv::read_many(&self.node, &[prev]).pop().unwrap();
+            if value.as_ref().is_none_or(is_tombstone) {
+                return Err(gone());
+            }
+        }
+        eprintln!("root of version {version}: {value:?}");
+
+        let mut index = index_at(&self.node, value, false);
+        index.load(keys);
+        let (found, ids): (Vec<TxnKey>, Vec<String>) = keys
+            .iter()
+            .filter_map(|k| index.get(k).map(|id| (k.clone(), id)))
+            .unzip();
+        let Some(chunks) = self.load_values(&ids).filter(|_| !index.stale()) else {
+            return Err(no_version(format!("version {version} is no longer retained")));
+        };
+        let mut values: KeyMap<serde_json::Value> = keys
+            .iter()
+            .map(|k| (k.clone(), serde_json::Value::Null))
+            .collect();
+        values.extend(found.into_iter().zip(chunks.into_iter().map(|(_, value)| value)));
+        Ok(values)
+    }
+
+    /// Group commit: runs `txns` one after another against the same root
+    /// and commits them all with one CAS. A txn that fails on its own, e.g.
+    /// with an append to a register, does not affect the others.
+    pub fn transact_batch(&mut self, txns: &[Vec<MicroOp>]) -> Vec<Result<Vec<MicroOp>, ErrorExtra>> {
+        let keys = { txns.iter().flatten().map(|op| op.key().clone()).collect::<HashSet<_>>() };
         eprintln!("keys: {keys:?}");
 
-        let root = Root::load(&self.node);
+        let mut index = load_index(&self.node);
 
-        // loadPartialState
-        let state: HashMap<usize, Vec<usize>> = keys
+        // loadPartialState: the index first, then all chunks at once
+        index.load(&keys.iter().cloned().collect::<Vec<_>>());
+        let (chunk_keys, chunk_ids): (Vec<TxnKey>, Vec<String>) = keys
             .iter()
-            .filter(|x| root.contains_key(&x))
-            .map(|x| {
-                let id = root.get(x).unwrap();
-                (*x, self.load_chunk(&id))
-            })
+            .filter_map(|k| index.get(k).map(|id| (k.clone(), id)))
+            .unzip();
+        let chunks = self.load_values(&chunk_ids);
+        let Some(chunks) = chunks.filter(|_| !index.stale()) else {
+            eprintln!("root {} was garbage collected", index.version());
+            return vec![Err(conflict("root garbage collected")); txns.len()];
+        };
+        let (heads, values): (Vec<Chunk>, Vec<serde_json::Value>) = chunks.into_iter().unzip();
+        let old_chunks: HashMap<TxnKey, (String, Chunk)> = chunk_keys
+            .iter()
+            .cloned()
+            .zip(chunk_ids.into_iter().zip(heads))
             .collect();
+        let state: HashMap<TxnKey, serde_json::Value> = chunk_keys.into_iter().zip(values).collect();
         eprintln!("state: {state:#?}");
 
-        let (state2, txn2) = {
-            let mut state2 = state.clone();
-            let txn2 = txns
-                .iter()
-                .map(|x| {
-                    let Query(op, k, qv) = x;
-                    match op.as_str() {
-                        "r" => {
-                            let v = state2.get(k).cloned();
-                            Query(op.clone(), *k, QueryValue::Read(v))
-                        }
-                        "append" => {
-                            let QueryValue::Append(value) = qv else {
-                                panic!("wrong value for 'append' operation");
-                            };
-                            state2
-                                .entry(*k)
-                                .and_modify(|v| v.push(*value))
-                                .or_insert(vec![*value]);
-                            x.clone()
-                        }
-                        _ => panic!("wrong operation"),
-                    }
-                })
-                .collect();
-            (state2, txn2)
-        };
+        // each txn sees the writes of the txns before it in the batch
+        let mut state2 = state.clone();
+        let mut write_keys = HashSet::new();
+        let results: Vec<Result<Vec<MicroOp>, ErrorExtra>> = txns
+            .iter()
+            .map(|txn| {
+                let mut scratch: HashMap<TxnKey, serde_json::Value> = txn
+                    .iter()
+                    .filter_map(|op| Some((op.key().clone(), state2.get(op.key())?.clone())))
+                    .collect();
+                let res = apply(&mut scratch, txn);
+                if res.is_ok() {
+                    state2.extend(scratch);
+                    write_keys.extend(txn.iter().filter(|x| x.is_write()).map(|x| x.key().clone()));
+                }
+                res
+            })
+            .collect();
         eprintln!("state2: {state2:#?}");
 
-        let write_keys = {
-            txns.iter()
-                .filter(|x| x.0 == "append")
-                .map(|x| x.1)
-                .collect::<HashSet<_>>()
-        };
+        // read-only: nothing to write, the root stays as it is
+        if write_keys.is_empty() {
+            if index.fence() {
+                return results;
+            }
+            return results
+                .into_iter()
+                .map(|res| res.and(Err(conflict("root altered"))))
+                .collect();
+        }
 
-        // savePartialState
-        let saved: HashMap<usize, String> = write_keys
+        // savePartialState: new chunks and index objects are written in one
+        // batch, the root CAS goes out once they are all acknowledged
+        let mut writes = Vec::new();
+        let mut superseded = Vec::new();
+        let max_segments = self.node.borrow().config.list_segments;
+        let saved: HashMap<TxnKey, String> = write_keys
             .iter()
             .map(|k| {
                 let thunk_id = self.new_thunk_id();
-                let thunk_values = state2.get(k).cloned().unwrap_or_default();
-                self.save_chunk(&thunk_id, &thunk_values);
-                (*k, thunk_id)
+                let old = old_chunks.get(k).map(|(id, head)| (id, head));
+                let old_value = state.get(k).unwrap_or(&serde_json::Value::Null);
+                let chunk = Chunk::next(old, old_value, &state2[k], max_segments);
+                if let Some(old) = old {
+                    superseded.extend(chunk.replaced(old));
+                }
+                writes.push((thunk_id.clone(), serde_json::to_value(&chunk).unwrap()));
+                self.node
+                    .borrow_mut()
+                    .chunk_cache
+                    .insert(thunk_id.clone(), chunk);
+                (k.clone(), thunk_id)
             })
             .collect();
 
-        let ok = root.save(&saved);
+        writes.extend(index.stage(&saved));
+        // the loaded root is kept for time travel, it goes the way of the
+        // objects only it references
+        let snapshot = {
+            let node = self.node.borrow();
+            node.ids.next_id(&format!("root-{}", node.id))
+        };
+        writes.push((snapshot.clone(), index.snapshot()));
+        let written: Vec<String> = writes.iter().map(|(id, _)| id.clone()).collect();
+        superseded.extend(index.superseded());
+        superseded.push(snapshot.clone());
+        // tombstones ride along with this txn's writes
+        writes.extend(self.collect_garbage(index.version()));
+        lin_kv::write_many(&self.node, writes);
 
-        if ok {
-            Ok(txn2)
-        } else {
-            Err(ErrorExtra {
-                code: 30,
-                text: "root altered".to_string(),
-            })
+        let committed = index.commit(snapshot);
+
+        self.node
+            .borrow_mut()
+            .gc
+            .after_commit(committed, index.version() + 1, superseded, written);
+
+        let err = match committed {
+            Ok(()) => return results,
+            Err(PRECONDITION_FAILED) => conflict("root altered"),
+            Err(code) => ErrorExtra {
+                code,
+                text: "root cas failed".to_string(),
+            },
+        };
+        results
+            .into_iter()
+            .map(|res| res.and(Err(err.clone())))
+            .collect()
+    }
+}
+
+/// The error for a version outside the retained history.
+fn no_version(text: String) -> ErrorExtra {
+    ErrorExtra {
+        code: KEY_DOES_NOT_EXIST,
+        text,
+    }
+}
+
+fn conflict(text: &str) -> ErrorExtra {
+    ErrorExtra {
+        code: TXN_CONFLICT,
+        text: text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use serde_json::{json, Value};
+    use std::sync::mpsc;
+
+    /// A node whose lin-kv answers its reads in order with `values`, `None`
+    /// for a missing key. A read beyond them panics.
+    fn node_reading(values: Vec<Option<Value>>) -> Rc<RefCell<Node>> {
+        let (lines, stdin) = mpsc::channel();
+        for (msg_id, value) in values.into_iter().enumerate() {
+            let body = match value {
+                Some(value) => json!({"type": "read_ok", "in_reply_to": msg_id, "value": value}),
+                None => json!({"type": "error", "in_reply_to": msg_id, "code": 20, "text": "not found"}),
+            };
+            let reply = json!({"src": "lin-kv", "dest": "n1", "body": body});
+            lines.send(reply.to_string()).unwrap();
         }
+        let mut config = Config::from_env();
+        config.index = IndexKind::Partitions;
+        config.partitioning = crate::config::PartitioningKind::Range;
+        let mut node = Node::with_stdin(config, stdin);
+        node.id = "n1".to_string();
+        Rc::new(RefCell::new(node))
+    }
+
+    fn txn(ops: Value) -> Vec<MicroOp> {
+        serde_json::from_value(ops).unwrap()
+    }
+
+    #[test]
+    fn tombstone_under_a_stale_root_is_a_conflict() {
+        // key 1 lives in partition 0, which was collected since
+        let root = json!({"version": 3, "parts": {"0": {"id": "part-0", "size": 1, "last_write": 3}}});
+        let node = node_reading(vec![Some(root), Some(json!(TOMBSTONE))]);
+        let res = Transactor::new(&node).transact_batch(&[txn(json!([["r", 1, null]]))]);
+        assert_eq!(res[0].as_ref().unwrap_err().code, TXN_CONFLICT);
+    }
+
+    /// The history of `read_at`: root version 3, snapshots of versions 2
+    /// and 1 under `snap-2` and `snap-1`.
+    fn history() -> Vec<Option<Value>> {
+        vec![
+            Some(json!({"version": 3, "parts": {}, "prev": "snap-2"})),
+            Some(json!({"version": 2, "parts": {}, "prev": "snap-1"})),
+        ]
+    }
+
+    fn keys(ks: &[u64]) -> Vec<TxnKey> {
+        ks.iter().map(|k| TxnKey::new(json!(k))).collect()
+    }
+
+    #[test]
+    fn read_at_follows_the_history_to_an_older_version() {
+        let part: Partition = [(TxnKey::new(json!(1)), "chunk-1".to_string())].into_iter().collect();
+        let mut reads = history();
+        reads.extend([
+            Some(json!({"version": 1, "parts": {"0": {"id": "part-0", "size": 1, "last_write": 1}}})),
+            Some(serde_json::to_value(part).unwrap()),
+            Some(serde_json::to_value(Chunk::Value(json!([1]))).unwrap()),
+        ]);
+        let node = node_reading(reads);
+        let values = Transactor::new(&node).read_at(1, &keys(&[1, 2])).unwrap();
+        assert_eq!(values.0[&TxnKey::new(json!(1))], json!([1]));
+        assert_eq!(values.0[&TxnKey::new(json!(2))], Value::Null);
+        // one read per version on the way, then the partition and the chunk
+        assert!(node.borrow().read_line(std::time::Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn read_at_past_gc_does_not_exist() {
+        let mut reads = history();
+        reads.push(Some(json!(TOMBSTONE)));
+        let node = node_reading(reads);
+        let err = Transactor::new(&node).read_at(1, &keys(&[1])).unwrap_err();
+        assert_eq!(err.code, KEY_DOES_NOT_EXIST);
+        assert_eq!(err.text, "version 1 is no longer retained");
+    }
+
+    #[test]
+    fn read_at_without_history_does_not_exist() {
+        // a root written before roots kept their history links to nothing
+        let node = node_reading(vec![Some(json!({"version": 3, "parts": {}}))]);
+        let err = Transactor::new(&node).read_at(2, &keys(&[1])).unwrap_err();
+        assert_eq!(err.code, KEY_DOES_NOT_EXIST);
+        // a snapshot never written, or lost with its writer
+        let mut reads = history();
+        reads.push(None);
+        let node = node_reading(reads);
+        let err = Transactor::new(&node).read_at(1, &keys(&[1])).unwrap_err();
+        assert_eq!(err.code, KEY_DOES_NOT_EXIST);
+        // and a version not committed yet
+        let node = node_reading(vec![Some(json!({"version": 3, "parts": {}}))]);
+        let err = Transactor::new(&node).read_at(4, &keys(&[1])).unwrap_err();
+        assert_eq!(err.text, "version 4 is not committed yet, the latest is 3");
     }
 }
@@ -0,0 +1,435 @@
+//! Transaction engines for the txn-list-append workload.
+//!
+//! Every key holds one JSON value: `append` grows a list and `w` replaces
+//! the value, so the rw-register workload runs on the same engines. All of
+//! them run the ops with [`apply`].
+//!
+//! `TxnHandler` delegates every `txn` request to a `TxnEngine`, which is
+//! picked at startup with `MAELSTROM_TXN_ENGINE`, so different designs can
+//! be compared on the same binary and workload:
+//!
+//! * `memory`: the whole database in node memory, challenge #7a.
+//! * `thunk`: the thunk based `Database` of `transactor.rs`.
+//! * `partitioned`: the range partitioned `Root` of `transactor2.rs`.
+//! * `percolator`: per-key locks and versions, see `percolator.rs`.
+//! * `occ`: per-key versions validated at commit, see `occ.rs`.
+//! * `calvin`: a replica per node fed by one txn log, see `calvin.rs`.
+//!
+//! All but `memory` keep the database in lin-kv and need the `lin_kv`
+//! feature. Except for `calvin`, which never aborts, they are wrapped in a
+//! `RetryEngine`, so a txn that loses a CAS is re-run instead of failing.
+use crate::config::{Config, TxnEngineKind};
+use crate::messages::*;
+use crate::node::Node;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+pub trait TxnEngine {
+    /// Applies the micro-operations of `txn` and returns them completed
+    /// with the values read, or an error for the client.
+    fn transact(
+        &mut self,
+        node: &Rc<RefCell<Node>>,
+        txn: &[MicroOp],
+    ) -> Result<Vec<MicroOp>, ErrorExtra>;
+
+    /// Runs several txns, each with its own result, see `TxnHandler`.
+    /// Engines that can commit them together override this.
+    fn transact_batch(
+        &mut self,
+        node: &Rc<RefCell<Node>>,
+        txns: &[Vec<MicroOp>],
+    ) -> Vec<Result<Vec<MicroOp>, ErrorExtra>> {
+        txns.iter().map(|txn| self.transact(node, txn)).collect()
+    }
+
+    /// Reads `keys` as they were after commit `version`, for engines that
+    /// keep a history.
+    fn read_at(
+        &mut self,
+        _node: &Rc<RefCell<Node>>,
+        _version: u64,
+        _keys: &[TxnKey],
+    ) -> Result<KeyMap<serde_json::Value>, ErrorExtra> {
+        Err(ErrorExtra {
+            code: NOT_SUPPORTED,
+            text: "this txn engine keeps no history".to_string(),
+        })
+    }
+
+    /// How long to wait before re-running a txn that conflicted on its
+    /// `attempt`th run, counted from 0, or None to report the conflict.
+    fn retry_after(&self, _attempt: u32) -> Option<Duration> {
+        None
+    }
+}
+
+/// Creates the engine selected by the config.
+pub fn new_engine(config: &Config) -> Box<dyn TxnEngine> {
+    match config.txn_engine {
+        // a single node never conflicts with itself
+        TxnEngineKind::Memory => Box::new(MemoryEngine),
+        #[cfg(feature = "lin_kv")]
+        TxnEngineKind::Thunk => Box::new(RetryEngine::new(Box::new(ThunkEngine), config)),
+        #[cfg(feature = "lin_kv")]
+        TxnEngineKind::Partitioned => {
+            Box::new(RetryEngine::new(Box::new(PartitionedEngine), config))
+        }
+        #[cfg(feature = "lin_kv")]
+        TxnEngineKind::Percolator => Box::new(RetryEngine::new(Box::new(PercolatorEngine), config)),
+        #[cfg(feature = "lin_kv")]
+        TxnEngineKind::Occ => Box::new(RetryEngine::new(Box::new(OccEngine), config)),
+        #[cfg(feature = "lin_kv")]
+        TxnEngineKind::Calvin => Box::new(CalvinEngine::default()),
+        #[cfg(not(feature = "lin_kv"))]
+        kind => panic!("txn engine {kind:?} needs the lin_kv feature"),
+    }
+}
+
+/// Runs `txn` against `state`, the current values of its keys, and returns
+/// the ops completed with the values read. On error `state` is left half
+/// updated, so callers apply to a copy.
+pub fn apply(
+    state: &mut HashMap<TxnKey, serde_json::Value>,
+    txn: &[MicroOp],
+) -> Result<Vec<MicroOp>, ErrorExtra> {
+    txn.iter()
+        .map(|op| {
+            match op {
+                MicroOp::Read { key, .. } => {
+                    return Ok(MicroOp::Read {
+                        key: key.clone(),
+                        value: state.get(key).cloned(),
+                    });
+                }
+                MicroOp::Append { key, value } => {
+                    match state.entry(key.clone()).or_insert(serde_json::Value::Null) {
+                        serde_json::Value::Array(list) => list.push(value.clone()),
+                        slot @ serde_json::Value::Null => *slot = serde_json::json!([value]),
+                        other => {
+                            return Err(ErrorExtra {
+                                code: PRECONDITION_FAILED,
+                                text: format!("can not append to key {key}, it holds {other}"),
+                            });
+                        }
+                    }
+                }
+                MicroOp::Write { key, value } => {
+                    state.insert(key.clone(), value.clone());
+                }
+            }
+            Ok(op.clone())
+        })
+        .collect()
+}
+
+/// Re-runs a txn from scratch when it aborts with `TXN_CONFLICT`.
+///
+/// Nothing of an aborted attempt is visible: its chunks are unreachable and
+/// the next attempt reloads the root, so the reads are regenerated from the
+/// state it commits against. Only a txn that conflicts `retries + 1` times
+/// in a row is reported to the client. The node does not wait for a retry:
+/// the request is parked in `Retries` and `on_tick` queues it again.
+pub struct RetryEngine {
+    pub inner: Box<dyn TxnEngine>,
+    pub retries: u32,
+    /// Doubled after each attempt, plus up to 100% random jitter so that
+    /// colliding nodes do not retry in lockstep.
+    pub backoff: Duration,
+}
+
+impl RetryEngine {
+    pub fn new(inner: Box<dyn TxnEngine>, config: &Config) -> Self {
+        Self {
+            inner,
+            retries: config.txn_retries,
+            backoff: config.txn_backoff,
+        }
+    }
+}
+
+impl RetryEngine {
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.backoff * 2u32.saturating_pow(attempt);
+        let jitter = (uuid::Uuid::new_v4().as_u128() % 1000) as u32;
+        delay + delay * jitter / 1000
+    }
+}
+
+impl TxnEngine for RetryEngine {
+    fn transact(
+        &mut self,
+        node: &Rc<RefCell<Node>>,
+        txn: &[MicroOp],
+    ) -> Result<Vec<MicroOp>, ErrorExtra> {
+        self.inner.transact(node, txn)
+    }
+
+    fn transact_batch(
+        &mut self,
+        node: &Rc<RefCell<Node>>,
+        txns: &[Vec<MicroOp>],
+    ) -> Vec<Result<Vec<MicroOp>, ErrorExtra>> {
+        self.inner.transact_batch(node, txns)
+    }
+
+    fn retry_after(&self, attempt: u32) -> Option<Duration> {
+        (attempt < self.retries).then(|| self.delay(attempt))
+    }
+
+    /// Past versions never change, there is nothing to retry.
+    fn read_at(
+        &mut self,
+        node: &Rc<RefCell<Node>>,
+        version: u64,
+        keys: &[TxnKey],
+    ) -> Result<KeyMap<serde_json::Value>, ErrorExtra> {
+        self.inner.read_at(node, version, keys)
+    }
+}
+
+/// Txn requests that conflicted and wait for their retry, see
+/// `TxnEngine::retry_after`.
+#[derive(Debug, Default)]
+pub struct Retries {
+    // (src, msg_id) of the requests retried so far, with their attempts
+    attempts: HashMap<(String, u64), u32>,
+    due: Vec<(Instant, Message)>,
+}
+
+impl Retries {
+    fn id(msg: &Message) -> (String, u64) {
+        (msg.src.clone(), msg.body.msg_id.unwrap_or_default())
+    }
+
+    /// How many times `msg` ran and conflicted.
+    pub fn attempt(&self, msg: &Message) -> u32 {
+        self.attempts
+            .get(&Self::id(msg))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Runs `msg` again once `at` has passed.
+    pub fn schedule(&mut self, msg: Message, at: Instant) {
+        *self.attempts.entry(Self::id(&msg)).or_default() += 1;
+        self.due.push((at, msg));
+    }
+
+    /// Forgets the attempts of `msg` once it is answered.
+    pub fn done(&mut self, msg: &Message) {
+        self.attempts.remove(&Self::id(msg));
+    }
+
+    /// Removes the requests due at `now`, in the order they were parked.
+    pub fn due(&mut self, now: Instant) -> Vec<Message> {
+        let (due, later) = std::mem::take(&mut self.due)
+            .into_iter()
+            .partition(|(at, _)| *at <= now);
+        self.due = later;
+        due.into_iter().map(|(_, msg)| msg).collect()
+    }
+}
+
+/// Keeps the whole database in `Node::kv_store`. Only correct on one node.
+pub struct MemoryEngine;
+
+impl TxnEngine for MemoryEngine {
+    fn transact(
+        &mut self,
+        node: &Rc<RefCell<Node>>,
+        txn: &[MicroOp],
+    ) -> Result<Vec<MicroOp>, ErrorExtra> {
+        let mut node = node.borrow_mut();
+        let (results, state) = run_local(&node.kv_store, txn)?;
+        node.kv_write(state);
+        Ok(results)
+    }
+}
+
+/// Runs `txn` against the database `store` held in memory, returns the
+/// completed ops and the new values of its keys. `store` is left alone, so
+/// a failed txn changes nothing.
+pub fn run_local(
+    store: &HashMap<TxnKey, serde_json::Value>,
+    txn: &[MicroOp],
+) -> Result<(Vec<MicroOp>, HashMap<TxnKey, serde_json::Value>), ErrorExtra> {
+    // all or nothing: apply to a copy of the keys involved
+    let mut state: HashMap<TxnKey, serde_json::Value> = txn
+        .iter()
+        .filter_map(|op| {
+            let value = store.get(op.key())?;
+            Some((op.key().clone(), value.clone()))
+        })
+        .collect();
+    let results = apply(&mut state, txn)?;
+    Ok((results, state))
+}
+
+/// See `transactor.rs`.
+#[cfg(feature = "lin_kv")]
+pub struct ThunkEngine;
+
+#[cfg(feature = "lin_kv")]
+impl TxnEngine for ThunkEngine {
+    fn transact(
+        &mut self,
+        node: &Rc<RefCell<Node>>,
+        txn: &[MicroOp],
+    ) -> Result<Vec<MicroOp>, ErrorExtra> {
+        crate::transactor::Transactor::new(node).transact(txn)
+    }
+}
+
+/// See `transactor2.rs`.
+#[cfg(feature = "lin_kv")]
+pub struct PartitionedEngine;
+
+#[cfg(feature = "lin_kv")]
+impl TxnEngine for PartitionedEngine {
+    fn transact(
+        &mut self,
+        node: &Rc<RefCell<Node>>,
+        txn: &[MicroOp],
+    ) -> Result<Vec<MicroOp>, ErrorExtra> {
+        crate::transactor2::Transactor::new(node).transact(txn)
+    }
+
+    fn transact_batch(
+        &mut self,
+        node: &Rc<RefCell<Node>>,
+        txns: &[Vec<MicroOp>],
+    ) -> Vec<Result<Vec<MicroOp>, ErrorExtra>> {
+        crate::transactor2::Transactor::new(node).transact_batch(txns)
+    }
+
+    fn read_at(
+        &mut self,
+        node: &Rc<RefCell<Node>>,
+        version: u64,
+        keys: &[TxnKey],
+    ) -> Result<KeyMap<serde_json::Value>, ErrorExtra> {
+        crate::transactor2::Transactor::new(node).read_at(version, keys)
+    }
+}
+
+/// See `percolator.rs`.
+#[cfg(feature = "lin_kv")]
+pub struct PercolatorEngine;
+
+#[cfg(feature = "lin_kv")]
+impl TxnEngine for PercolatorEngine {
+    fn transact(
+        &mut self,
+        node: &Rc<RefCell<Node>>,
+        txn: &[MicroOp],
+    ) -> Result<Vec<MicroOp>, ErrorExtra> {
+        crate::percolator::Percolator::new(node).transact(txn)
+    }
+}
+
+/// See `occ.rs`.
+#[cfg(feature = "lin_kv")]
+pub struct OccEngine;
+
+#[cfg(feature = "lin_kv")]
+impl TxnEngine for OccEngine {
+    fn transact(
+        &mut self,
+        node: &Rc<RefCell<Node>>,
+        txn: &[MicroOp],
+    ) -> Result<Vec<MicroOp>, ErrorExtra> {
+        crate::occ::Occ::new(node).transact(txn)
+    }
+}
+
+/// See `calvin.rs`.
+#[cfg(feature = "lin_kv")]
+#[derive(Default)]
+pub struct CalvinEngine {
+    replica: crate::calvin::Replica,
+}
+
+#[cfg(feature = "lin_kv")]
+impl TxnEngine for CalvinEngine {
+    fn transact(
+        &mut self,
+        node: &Rc<RefCell<Node>>,
+        txn: &[MicroOp],
+    ) -> Result<Vec<MicroOp>, ErrorExtra> {
+        self.replica.sequence(node, &[txn.to_vec()]).pop().unwrap()
+    }
+
+    /// The whole batch takes a single slot of the log.
+    fn transact_batch(
+        &mut self,
+        node: &Rc<RefCell<Node>>,
+        txns: &[Vec<MicroOp>],
+    ) -> Vec<Result<Vec<MicroOp>, ErrorExtra>> {
+        self.replica.sequence(node, txns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn txn(ops: Value) -> Vec<MicroOp> {
+        serde_json::from_value(ops).unwrap()
+    }
+
+    fn key(k: u64) -> TxnKey {
+        TxnKey::new(json!(k))
+    }
+
+    #[test]
+    fn append_to_a_missing_key_starts_a_list() {
+        let mut state = HashMap::new();
+        let res = apply(&mut state, &txn(json!([["append", 1, 5], ["r", 1, null]]))).unwrap();
+        assert_eq!(res, txn(json!([["append", 1, 5], ["r", 1, [5]]])));
+        assert_eq!(state[&key(1)], json!([5]));
+    }
+
+    #[test]
+    fn read_sees_earlier_writes_of_its_txn() {
+        let store = HashMap::from([(key(1), json!([1])), (key(2), json!(7))]);
+        let ops = txn(json!([
+            ["r", 1, null],
+            ["append", 1, 2],
+            ["r", 1, null],
+            ["w", 2, 8],
+            ["r", 2, null],
+            ["r", 3, null]
+        ]));
+        let (res, state) = run_local(&store, &ops).unwrap();
+        assert_eq!(
+            res,
+            txn(json!([
+                ["r", 1, [1]],
+                ["append", 1, 2],
+                ["r", 1, [1, 2]],
+                ["w", 2, 8],
+                ["r", 2, 8],
+                ["r", 3, null]
+            ]))
+        );
+        assert_eq!(
+            state,
+            HashMap::from([(key(1), json!([1, 2])), (key(2), json!(8))])
+        );
+        // the store itself is left alone
+        assert_eq!(store[&key(1)], json!([1]));
+    }
+
+    #[test]
+    fn append_to_a_written_value_fails_the_whole_txn() {
+        let store = HashMap::from([(key(2), json!(7))]);
+        let ops = txn(json!([["append", 1, 1], ["append", 2, 1]]));
+        let err = run_local(&store, &ops).unwrap_err();
+        assert_eq!(err.code, PRECONDITION_FAILED);
+        assert_eq!(store, HashMap::from([(key(2), json!(7))]));
+    }
+}
@@ -0,0 +1,391 @@
+//! Leader forwarding for txns.
+//!
+//! When every node commits against the same root, the root CAS fails more
+//! often the more nodes there are. In leader mode one node holds a lease
+//! in lin-kv and the other nodes forward their `txn` requests to it as
+//! `txn_forward`, then relay its `txn_forward_ok` to the client.
+//!
+//! The lease is `{"leader": "n1", "term": 7}` under `txn-leader`. Clocks
+//! differ between nodes, so it holds no time: a node trusts a lease for
+//! `MAELSTROM_TXN_LEASE_MS` of its own clock after it first saw it, and
+//! takes it over by CAS if it has not changed by then. The leader extends
+//! it while it is busy by bumping the term. Being the only writer, the
+//! leader keeps the root it committed last and does not read it again.
+//!
+//! None of this is needed for correctness, the root CAS still decides every
+//! commit: a stale root or a second leader only makes it fail and the txn is
+//! retried. A node runs a forwarded txn itself even if it lost the lease
+//! meanwhile, so a txn is forwarded at most once.
+//!
+//! Forwards are not resent. One that got no answer, e.g. because the leader
+//! crashed, is run by the forwarding node after all. So that the two never
+//! both run it, a forward carries a ttl of one lease duration, counted by
+//! the leader from when it read the forward, after which it drops it. The
+//! forwarding node waits twice as long, counted from when it sent it.
+use crate::config::Config;
+use crate::lin_kv::LinKv;
+use crate::messages::{Message, MessageExtra};
+use crate::node::Node;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const LEASE_KEY: &str = "txn-leader";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub leader: String,
+    /// bumped by every takeover and extension
+    pub term: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct TxnLeader {
+    enabled: bool,
+    duration: Duration,
+    // the known lease, with when it expires on our clock
+    lease: Option<(Lease, Instant)>,
+    // (lin-kv key, root value) of the last root this node committed
+    root: Option<(String, serde_json::Value)>,
+    /// client requests forwarded to the leader, by the msg_id of the
+    /// forward, with when to take them back
+    pub forwarded: HashMap<u64, (Message, Instant)>,
+    // (client, msg_id) of the requests taken back from the leader
+    taken_back: HashSet<(String, u64)>,
+    // (forwarder, msg_id) of the forwards read, with when they go stale
+    received: HashMap<(String, u64), Instant>,
+}
+
+impl TxnLeader {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            enabled: config.txn_leader,
+            duration: config.txn_lease,
+            ..Default::default()
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// True if node `id` holds an unexpired lease.
+    pub fn is_leader(&self, id: &str) -> bool {
+        self.lease
+            .as_ref()
+            .is_some_and(|(lease, expires)| lease.leader == id && *expires > Instant::now())
+    }
+
+    /// The root at `key` as node `id` committed it, if it is the leader.
+    pub fn cached_root(&self, id: &str, key: &str) -> Option<serde_json::Value> {
+        if !self.is_leader(id) {
+            return None;
+        }
+        self.root
+            .as_ref()
+            .filter(|(k, _)| k == key)
+            .map(|(_, value)| value.clone())
+    }
+
+    /// Remembers the client request `req` forwarded as `msg_id`. Returns
+    /// the ttl of the forward in ms.
+    pub fn forward(&mut self, msg_id: u64, req: &Message, now: Instant) -> u64 {
+        self.forwarded
+            .insert(msg_id, (req.clone(), now + 2 * self.duration));
+        self.duration.as_millis() as u64
+    }
+
+    /// Takes back the forwarded requests that the leader did not answer in
+    /// time, to run them here.
+    pub fn expired(&mut self, now: Instant) -> Vec<Message> {
+        let mut expired = Vec::new();
+        self.forwarded.retain(|_, (req, deadline)| {
+            if *deadline > now {
+                return true;
+            }
+            expired.push(req.clone());
+            false
+        });
+        for req in &expired {
+            self.taken_back
+                .insert((req.src.clone(), req.body.msg_id.unwrap_or_default()));
+        }
+        expired
+    }
+
+    /// True, once, for a request taken back by `expired`.
+    pub fn taken_back(&mut self, req: &Message) -> bool {
+        self.taken_back
+            .remove(&(req.src.clone(), req.body.msg_id.unwrap_or_default()))
+    }
+
+    /// Starts the ttl of a forward read at `now`.
+    pub fn received(&mut self, msg: &Message, now: Instant) {
+        if let MessageExtra::TxnForward(payload) = &msg.body.extra {
+            if let Some(ttl) = payload.ttl {
+                let id = (msg.src.clone(), msg.body.msg_id.unwrap_or_default());
+                self.received.insert(id, now + Duration::from_millis(ttl));
+            }
+        }
+    }
+
+    /// True if the forward `msg` outlived its ttl, so the forwarding node
+    /// may run it itself. A forward is checked every time it is run, a
+    /// retry too, and forgotten once stale or `done`; one never stamped
+    /// counts as fresh.
+    pub fn stale(&mut self, msg: &Message, now: Instant) -> bool {
+        let id = (msg.src.clone(), msg.body.msg_id.unwrap_or_default());
+        let stale = self
+            .received
+            .get(&id)
+            .is_some_and(|deadline| *deadline <= now);
+        if stale {
+            self.received.remove(&id);
+        }
+        stale
+    }
+
+    /// Forgets the ttl of the forward `msg` once it is answered.
+    pub fn done(&mut self, msg: &Message) {
+        self.received
+            .remove(&(msg.src.clone(), msg.body.msg_id.unwrap_or_default()));
+    }
+
+    /// Records the root just committed, or forgets it after a failed CAS.
+    pub fn remember_root(&mut self, key: &str, value: Option<serde_json::Value>) {
+        self.root = value.map(|value| (key.to_string(), value));
+    }
+}
+
+/// The current leader, taking over or extending the lease when needed.
+/// Only costs lin-kv round trips when the known lease is about to expire.
+pub fn current_leader(node: &Rc<RefCell<Node>>) -> String {
+    let (id, known, duration) = {
+        let node = node.borrow();
+        let leader = &node.txn_leader;
+        (node.id.clone(), leader.lease.clone(), leader.duration)
+    };
+    let lease = renew(node, &id, known, duration, Instant::now());
+    let leader = lease.0.leader.clone();
+    node.borrow_mut().txn_leader.lease = Some(lease);
+    leader
+}
+
+/// The lease after `known`, with when it expires. `now` is taken before
+/// any round trip, so our own lease never outlives what the others see.
+fn renew<K: LinKv>(
+    kv: &K,
+    id: &str,
+    known: Option<(Lease, Instant)>,
+    duration: Duration,
+    now: Instant,
+) -> (Lease, Instant) {
+    if let Some((lease, expires)) = &known {
+        // followers trust a lease until it expires, the leader extends it
+        // once half of it is used up
+        let fresh = if lease.leader == id {
+            *expires > now + duration / 2
+        } else {
+            *expires > now
+        };
+        if fresh {
+            return known.unwrap();
+        }
+    }
+
+    let current = read_lease(kv);
+    if let Some(lease) = &current {
+        // someone else's lease that changed since we last saw it is live
+        // for another whole duration
+        if lease.leader != id && known.as_ref().map(|(known, _)| known) != Some(lease) {
+            return (lease.clone(), now + duration);
+        }
+    }
+    // missing, not extended for a whole duration, or ours
+    let ours = Lease {
+        leader: id.to_string(),
+        term: current.as_ref().map_or(0, |lease| lease.term + 1),
+    };
+    let from = serde_json::to_value(&current).unwrap();
+    let to = serde_json::to_value(&ours).unwrap();
+    if kv
+        .cas_many(vec![(LEASE_KEY.to_string(), from, to)])
+        .pop()
+        .unwrap()
+        .is_ok()
+    {
+        eprintln!("txn leader lease, term {}", ours.term);
+        (ours, now + duration)
+    } else {
+        let lease = read_lease(kv).expect("the lease was just written");
+        (lease, now + duration)
+    }
+}
+
+fn read_lease<K: LinKv>(kv: &K) -> Option<Lease> {
+    kv.read_many(&[LEASE_KEY.to_string()])
+        .pop()
+        .unwrap()
+        .map(|v| serde_json::from_value(v).unwrap())
+}
+
+/// Wall clock time in ms, for lock expiry.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lin_kv::mem::MemKv;
+    use crate::messages::{MessageBody, TxnRequestExtra};
+
+    const DURATION: Duration = Duration::from_millis(1000);
+
+    fn lease(leader: &str, term: u64) -> Lease {
+        Lease {
+            leader: leader.to_string(),
+            term,
+        }
+    }
+
+    fn kv_with(lease: &Lease) -> MemKv {
+        let kv = MemKv::default();
+        let value = serde_json::to_value(lease).unwrap();
+        kv.data.borrow_mut().insert(LEASE_KEY.to_string(), value);
+        kv
+    }
+
+    fn txn(src: &str, msg_id: u64, ttl: Option<u64>) -> Message {
+        Message {
+            src: src.to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                extra: MessageExtra::TxnForward(TxnRequestExtra { txn: vec![], ttl }),
+            },
+        }
+    }
+
+    #[test]
+    fn first_node_takes_a_missing_lease() {
+        let now = Instant::now();
+        let kv = MemKv::default();
+        assert_eq!(
+            renew(&kv, "n1", None, DURATION, now),
+            (lease("n1", 0), now + DURATION)
+        );
+        assert_eq!(read_lease(&kv), Some(lease("n1", 0)));
+    }
+
+    #[test]
+    fn follower_takes_over_a_lease_not_extended_for_a_duration() {
+        let now = Instant::now();
+        let kv = kv_with(&lease("n2", 3));
+        let known = renew(&kv, "n1", None, DURATION, now);
+        assert_eq!(known, (lease("n2", 3), now + DURATION));
+        // trusted until it expires on our clock
+        let later = now + DURATION / 2;
+        assert_eq!(
+            renew(&kv, "n1", Some(known.clone()), DURATION, later),
+            known
+        );
+        let expired = now + DURATION;
+        assert_eq!(
+            renew(&kv, "n1", Some(known), DURATION, expired),
+            (lease("n1", 4), expired + DURATION)
+        );
+        assert_eq!(read_lease(&kv), Some(lease("n1", 4)));
+    }
+
+    #[test]
+    fn follower_trusts_an_extended_lease_for_another_duration() {
+        let now = Instant::now();
+        let kv = kv_with(&lease("n2", 4));
+        let known = (lease("n2", 3), now);
+        assert_eq!(
+            renew(&kv, "n1", Some(known), DURATION, now),
+            (lease("n2", 4), now + DURATION)
+        );
+        assert_eq!(read_lease(&kv), Some(lease("n2", 4)));
+    }
+
+    #[test]
+    fn leader_extends_its_lease_once_half_is_used() {
+        let now = Instant::now();
+        let kv = kv_with(&lease("n1", 0));
+        let known = (lease("n1", 0), now + DURATION);
+        let early = now + DURATION / 4;
+        assert_eq!(
+            renew(&kv, "n1", Some(known.clone()), DURATION, early),
+            known
+        );
+        let late = now + DURATION * 3 / 4;
+        assert_eq!(
+            renew(&kv, "n1", Some(known), DURATION, late),
+            (lease("n1", 1), late + DURATION)
+        );
+        assert_eq!(read_lease(&kv), Some(lease("n1", 1)));
+    }
+
+    #[test]
+    fn losing_the_lease_cas_follows_the_winner() {
+        let now = Instant::now();
+        let kv = MemKv::default();
+        let winner = serde_json::to_value(lease("n3", 0)).unwrap();
+        *kv.before_cas.borrow_mut() = Some(Box::new(move |data| {
+            data.insert(LEASE_KEY.to_string(), winner);
+        }));
+        assert_eq!(
+            renew(&kv, "n1", None, DURATION, now),
+            (lease("n3", 0), now + DURATION)
+        );
+    }
+
+    #[test]
+    fn expired_forward_is_taken_back_once() {
+        let now = Instant::now();
+        let mut leader = TxnLeader {
+            enabled: true,
+            duration: DURATION,
+            ..Default::default()
+        };
+        let req = txn("c1", 7, None);
+        assert_eq!(leader.forward(100, &req, now), 1000);
+        // the leader may still be running it
+        assert!(leader.expired(now + DURATION).is_empty());
+        let expired = leader.expired(now + 2 * DURATION);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].body.msg_id, Some(7));
+        assert!(leader.expired(now + 3 * DURATION).is_empty());
+        assert!(leader.forwarded.is_empty());
+        assert!(leader.taken_back(&req));
+        assert!(!leader.taken_back(&req));
+    }
+
+    #[test]
+    fn forward_goes_stale_after_its_ttl() {
+        let now = Instant::now();
+        let mut leader = TxnLeader::default();
+        let fresh = txn("n2", 1, Some(100));
+        let late = txn("n3", 1, Some(100));
+        leader.received(&fresh, now);
+        leader.received(&late, now);
+        assert!(!leader.stale(&fresh, now + Duration::from_millis(99)));
+        assert!(leader.stale(&late, now + Duration::from_millis(100)));
+        // a retry of a fresh forward is checked against the same ttl
+        assert!(leader.stale(&fresh, now + Duration::from_millis(100)));
+        // forgotten once stale, a forward never stamped counts as fresh
+        assert!(!leader.stale(&late, now + Duration::from_millis(100)));
+        assert!(!leader.stale(&txn("n4", 1, Some(100)), now + DURATION));
+        leader.received(&fresh, now);
+        leader.done(&fresh);
+        assert!(!leader.stale(&fresh, now + DURATION));
+    }
+}
@@ -0,0 +1,136 @@
+//! Vector clocks over node ids.
+//!
+//! A node missing from the map has counter 0, so clocks from nodes that have
+//! not heard of each other yet can still be compared.
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct VectorClock(BTreeMap<String, u64>);
+
+impl VectorClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, node: &str) -> u64 {
+        self.0.get(node).copied().unwrap_or(0)
+    }
+
+    /// Counts one more event on `node` and returns its new counter.
+    pub fn increment(&mut self, node: &str) -> u64 {
+        let counter = self.0.entry(node.to_string()).or_insert(0);
+        *counter += 1;
+        *counter
+    }
+
+    /// Pointwise maximum of both clocks.
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node, counter) in &other.0 {
+            let mine = self.0.entry(node.clone()).or_insert(0);
+            *mine = (*mine).max(*counter);
+        }
+    }
+
+    /// `self -> other`: every counter is <= and the clocks differ.
+    pub fn happened_before(&self, other: &VectorClock) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Less)
+    }
+
+    /// The causal delivery condition. `self` counts the messages delivered
+    /// from each node; a message stamped `msg` by `sender` is deliverable iff
+    /// it is the next one from `sender` and everything it depends on from
+    /// other nodes has been delivered already.
+    pub fn can_deliver(&self, msg: &VectorClock, sender: &str) -> bool {
+        msg.get(sender) == self.get(sender) + 1
+            && msg
+                .0
+                .iter()
+                .all(|(node, counter)| node == sender || *counter <= self.get(node))
+    }
+}
+
+impl PartialOrd for VectorClock {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let nodes = self.0.keys().chain(other.0.keys());
+        let (mut less, mut greater) = (false, false);
+        for node in nodes {
+            match self.get(node).cmp(&other.get(node)) {
+                Ordering::Less => less = true,
+                Ordering::Greater => greater = true,
+                Ordering::Equal => {}
+            }
+        }
+        match (less, greater) {
+            (false, false) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Less),
+            (false, true) => Some(Ordering::Greater),
+            (true, true) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(counters: &[(&str, u64)]) -> VectorClock {
+        VectorClock(counters.iter().map(|&(n, c)| (n.to_string(), c)).collect())
+    }
+
+    #[test]
+    fn a_new_clock_is_zero_everywhere() {
+        let clock = VectorClock::new();
+        assert_eq!(clock.get("n0"), 0);
+        assert_eq!(clock, VectorClock::default());
+        assert_eq!(
+            clock.partial_cmp(&self::clock(&[("n0", 0)])),
+            Some(Ordering::Equal)
+        );
+    }
+
+    #[test]
+    fn increment_counts_per_node() {
+        let mut clock = VectorClock::new();
+        assert_eq!(clock.increment("n0"), 1);
+        assert_eq!(clock.increment("n0"), 2);
+        assert_eq!(clock.increment("n1"), 1);
+        assert_eq!(clock, self::clock(&[("n0", 2), ("n1", 1)]));
+    }
+
+    #[test]
+    fn merge_takes_the_pointwise_maximum() {
+        let mut a = clock(&[("n0", 3), ("n1", 1)]);
+        a.merge(&clock(&[("n1", 4), ("n2", 2)]));
+        assert_eq!(a, clock(&[("n0", 3), ("n1", 4), ("n2", 2)]));
+    }
+
+    #[test]
+    fn happened_before_is_strict() {
+        let a = clock(&[("n0", 1)]);
+        let b = clock(&[("n0", 1), ("n1", 1)]);
+        assert!(a.happened_before(&b));
+        assert!(!b.happened_before(&a));
+        assert!(!a.happened_before(&a));
+    }
+
+    #[test]
+    fn clocks_of_independent_events_are_unordered() {
+        let a = clock(&[("n0", 2), ("n1", 1)]);
+        let b = clock(&[("n0", 1), ("n1", 2)]);
+        assert_eq!(a.partial_cmp(&b), None);
+        assert!(!a.happened_before(&b) && !b.happened_before(&a));
+    }
+
+    #[test]
+    fn can_deliver_the_next_message_with_its_past_delivered() {
+        let delivered = clock(&[("n0", 1), ("n1", 1)]);
+        assert!(delivered.can_deliver(&clock(&[("n0", 2), ("n1", 1)]), "n0"));
+        assert!(!delivered.can_deliver(&clock(&[("n0", 3)]), "n0"));
+        assert!(!delivered.can_deliver(&clock(&[("n0", 1)]), "n0"));
+        assert!(!delivered.can_deliver(&clock(&[("n0", 2), ("n1", 2)]), "n0"));
+        assert!(delivered.can_deliver(&clock(&[("n2", 1)]), "n2"));
+    }
+}
@@ -0,0 +1,283 @@
+//! Write-ahead log for the node-local state.
+//!
+//! `messages_seen`, `kv_store` and `unacked` only live in memory, so a node
+//! restarted by the kill nemesis would come back empty. With
+//! `MAELSTROM_WAL_DIR` set, every change to them is appended to
+//! `<dir>/<node>.wal` as one JSON line before anyone hears of it, e.g.
+//! before a broadcast is acknowledged. Every `MAELSTROM_WAL_SNAPSHOT`
+//! records the whole state goes to `<dir>/<node>.snapshot` and the log
+//! starts over.
+//!
+//! The snapshot and the log are replayed at startup, before `init` and any
+//! other message is handled. Maelstrom only names the node in `init`, so
+//! `Node::recover` reads that first message to find the files.
+//! Records are idempotent, replaying the log over a newer snapshot after a
+//! crash in between is harmless. A torn last line is skipped.
+//!
+//! The nemesis kills the process, not the machine, so what was written is
+//! in the page cache and nothing is fsynced.
+//!
+//! The delivery state of total-order and causal broadcast is not logged,
+//! so the config refuses a log in those modes.
+use crate::broadcast_set::BroadcastSet;
+use crate::messages::{BroadcastValue, KeyMap};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Record {
+    Seen {
+        value: BroadcastValue,
+    },
+    /// values learned at once from anti-entropy
+    SeenAll {
+        values: BroadcastSet,
+    },
+    /// the values a txn wrote, all or nothing
+    KvWrite {
+        entries: KeyMap<serde_json::Value>,
+    },
+    /// a message put into `unacked`
+    Sent {
+        msg_id: u64,
+        message: String,
+    },
+    Acked {
+        msg_id: u64,
+    },
+}
+
+/// The durable part of a node.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct State {
+    pub messages_seen: BroadcastSet,
+    pub kv_store: KeyMap<serde_json::Value>,
+    pub unacked: HashMap<u64, String>,
+}
+
+impl State {
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Seen { value } => {
+                self.messages_seen.insert(&value);
+            }
+            Record::SeenAll { values } => self.messages_seen.union_with(&values),
+            Record::KvWrite { entries } => self.kv_store.extend(entries),
+            Record::Sent { msg_id, message } => {
+                self.unacked.insert(msg_id, message);
+            }
+            Record::Acked { msg_id } => {
+                self.unacked.remove(&msg_id);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Wal {
+    log: File,
+    snapshot: PathBuf,
+    // records since the last snapshot
+    records: Cell<usize>,
+    snapshot_every: usize,
+}
+
+impl Wal {
+    /// Opens the log of `node_id` in `dir` and returns the state it holds.
+    pub fn open(dir: &Path, node_id: &str, snapshot_every: usize) -> io::Result<(Wal, State)> {
+        fs::create_dir_all(dir)?;
+        let snapshot = dir.join(format!("{node_id}.snapshot"));
+        let mut state: State = match fs::read(&snapshot) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => State::default(),
+            Err(e) => return Err(e),
+        };
+
+        let path = dir.join(format!("{node_id}.wal"));
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(path)?;
+        let mut records = 0;
+        for line in BufReader::new(&log).lines() {
+            match serde_json::from_str(&line?) {
+                Ok(record) => state.apply(record),
+                Err(e) => eprintln!("wal: skip torn record: {e}"),
+            }
+            records += 1;
+        }
+
+        let wal = Wal {
+            log,
+            snapshot,
+            records: Cell::new(records),
+            snapshot_every,
+        };
+        Ok((wal, state))
+    }
+
+    /// Appends `record`, true once a snapshot is due.
+    pub fn append(&self, record: &Record) -> bool {
+        let mut line = serde_json::to_vec(record).unwrap();
+        line.push(b'\n');
+        // one write per record, so a crash tears at most the last line
+        (&self.log)
+            .write_all(&line)
+            .unwrap_or_else(|e| panic!("wal write failed: {e}"));
+        self.records.set(self.records.get() + 1);
+        self.records.get() >= self.snapshot_every
+    }
+
+    /// Replaces the snapshot with `state` and empties the log.
+    pub fn snapshot(&self, state: &State) -> io::Result<()> {
+        let tmp = self.snapshot.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(state).unwrap())?;
+        fs::rename(&tmp, &self.snapshot)?;
+        self.log.set_len(0)?;
+        self.records.set(0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::TxnKey;
+    use serde_json::json;
+
+    /// An empty directory of its own for each test.
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wal-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn seen(v: u64) -> Record {
+        Record::Seen { value: v.into() }
+    }
+
+    #[test]
+    fn replays_what_was_appended() {
+        let dir = dir("replay");
+        let (wal, state) = Wal::open(&dir, "n0", 100).unwrap();
+        assert_eq!(state.messages_seen.len(), 0);
+        wal.append(&seen(1));
+        wal.append(&seen(2));
+        wal.append(&Record::KvWrite {
+            entries: [(TxnKey::from(7), json!([1]))].into_iter().collect(),
+        });
+        wal.append(&Record::Sent {
+            msg_id: 3,
+            message: "a".to_string(),
+        });
+        wal.append(&Record::Sent {
+            msg_id: 4,
+            message: "b".to_string(),
+        });
+        wal.append(&Record::Acked { msg_id: 3 });
+        drop(wal);
+
+        let (_, state) = Wal::open(&dir, "n0", 100).unwrap();
+        assert_eq!(
+            state.messages_seen.iter().collect::<Vec<_>>(),
+            vec![1.into(), 2.into()]
+        );
+        assert_eq!(state.kv_store.get(&TxnKey::from(7)), Some(&json!([1])));
+        assert_eq!(state.unacked, HashMap::from([(4, "b".to_string())]));
+        // other nodes have their own log
+        let (_, other) = Wal::open(&dir, "n1", 100).unwrap();
+        assert!(other.messages_seen.is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_torn_last_record_is_skipped() {
+        let dir = dir("torn");
+        let (wal, _) = Wal::open(&dir, "n0", 100).unwrap();
+        wal.append(&seen(1));
+        drop(wal);
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.join("n0.wal"))
+            .unwrap();
+        log.write_all(br#"{"op":"seen","val"#).unwrap();
+
+        let (_, state) = Wal::open(&dir, "n0", 100).unwrap();
+        assert_eq!(state.messages_seen.len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_snapshot_is_due_every_few_records() {
+        let dir = dir("due");
+        let (wal, _) = Wal::open(&dir, "n0", 3).unwrap();
+        assert!(!wal.append(&seen(1)));
+        assert!(!wal.append(&seen(2)));
+        assert!(wal.append(&seen(3)));
+        wal.snapshot(&State::default()).unwrap();
+        assert!(!wal.append(&seen(4)));
+        drop(wal);
+
+        // replayed records count towards the next snapshot
+        let (wal, _) = Wal::open(&dir, "n0", 3).unwrap();
+        assert!(!wal.append(&seen(5)));
+        assert!(wal.append(&seen(6)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_snapshot_replaces_the_log() {
+        let dir = dir("snapshot");
+        let (wal, mut state) = Wal::open(&dir, "n0", 100).unwrap();
+        for v in 1..=3 {
+            let record = seen(v);
+            wal.append(&record);
+            state.apply(record);
+        }
+        wal.snapshot(&state).unwrap();
+        assert_eq!(fs::metadata(dir.join("n0.wal")).unwrap().len(), 0);
+        assert!(!dir.join("n0.snapshot.tmp").exists());
+        wal.append(&seen(4));
+        drop(wal);
+
+        let (_, state) = Wal::open(&dir, "n0", 100).unwrap();
+        let values: Vec<u64> = state
+            .messages_seen
+            .iter()
+            .filter_map(|v| v.as_u64())
+            .collect();
+        assert_eq!(values, vec![1, 2, 3, 4]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replaying_the_log_over_a_newer_snapshot_is_harmless() {
+        let dir = dir("idempotent");
+        let (wal, mut state) = Wal::open(&dir, "n0", 100).unwrap();
+        let records = [
+            seen(1),
+            Record::Sent {
+                msg_id: 1,
+                message: "m".to_string(),
+            },
+        ];
+        for record in records {
+            wal.append(&record);
+            state.apply(record);
+        }
+        // a crash after writing the snapshot, before the log was emptied
+        fs::write(dir.join("n0.snapshot"), serde_json::to_vec(&state).unwrap()).unwrap();
+        drop(wal);
+
+        let (_, replayed) = Wal::open(&dir, "n0", 100).unwrap();
+        assert_eq!(replayed.messages_seen, state.messages_seen);
+        assert_eq!(replayed.unacked, state.unacked);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}